@@ -1,13 +1,16 @@
 //! hermes documentation
 
 use std::env;
-use std::net::Ipv4Addr;
 use std::sync::Arc;
 
 use getopts::Options;
 
-use hermes::dns::context::{ResolveStrategy, ServerContext};
-use hermes::dns::protocol::{DnsRecord, TransientTtl};
+use hermes::dns::cache::{CacheConfig, SynchronizedCache, TtlOverride};
+use hermes::dns::context::{IpPreference, ResolveStrategy, ServerContext};
+use hermes::dns::forward::{parse_forwarder, ForwardOrder};
+use hermes::dns::hints::{default_root_hints, load_root_hints};
+use hermes::dns::https::HttpsMethod;
+use hermes::dns::route::{normalize, parse_rule};
 use hermes::dns::server::{DnsServer, DnsTcpServer, DnsUdpServer};
 use hermes::web::server::WebServer;
 
@@ -27,13 +30,153 @@ fn main() {
         "authority",
         "disable support for recursive lookups, and serve only local zones",
     );
-    opts.optopt(
+    opts.optmulti(
         "f",
         "forward",
-        "forward replies to specified dns server",
+        "forward replies to specified dns server, as an IP address with an \
+         optional port, or as tls://ADDRESS[:PORT][#NAME] to use DNS over \
+         TLS, or https://ADDRESS[:PORT][/PATH][#NAME] to use DNS over HTTPS. \
+         Repeat to fall back on further servers.",
         "SERVER",
     );
+    opts.optopt(
+        "",
+        "forward-order",
+        "which forwarder to try first: ordered (default) or random",
+        "ORDER",
+    );
+    opts.optopt(
+        "",
+        "tls-ca",
+        "trust only the certificate authorities in this PEM file for \
+         forwarders reached over TLS or HTTPS",
+        "FILE",
+    );
+    opts.optmulti(
+        "",
+        "tls-pin",
+        "accept only forwarders reached over TLS or HTTPS with this public \
+         key, given as the base64 encoded SHA-256 digest of its \
+         SubjectPublicKeyInfo",
+        "PIN",
+    );
+    opts.optopt(
+        "",
+        "https-method",
+        "how to send queries to forwarders over HTTPS: post (default) or get",
+        "METHOD",
+    );
+    opts.optmulti(
+        "",
+        "route",
+        "resolve a domain and everything below it with other servers than the \
+         rest, given as a comma separated list of servers or recursive",
+        "DOMAIN=SERVERS",
+    );
     opts.optopt("p", "port", "listen on specified port", "PORT");
+    opts.optopt(
+        "",
+        "root-hints",
+        "read the root name servers to start recursive lookups from out of a \
+         named.root file, rather than using the bundled ones",
+        "FILE",
+    );
+    opts.optopt(
+        "",
+        "ip-version",
+        "which addresses to reach name servers on: 4 (default), 6 or dual",
+        "VERSION",
+    );
+    opts.optopt(
+        "",
+        "query-timeout",
+        "milliseconds to wait for each query to another name server",
+        "MS",
+    );
+    opts.optopt(
+        "",
+        "resolve-timeout",
+        "milliseconds to spend on resolving a name before giving up",
+        "MS",
+    );
+    opts.optflag(
+        "",
+        "no-qname-minimisation",
+        "send the full name being resolved to every name server",
+    );
+    opts.optopt(
+        "",
+        "max-referrals",
+        "most referrals to follow when resolving a name",
+        "COUNT",
+    );
+    opts.optopt(
+        "",
+        "max-nested-lookups",
+        "most lookups of name servers to nest within one another",
+        "COUNT",
+    );
+    opts.optopt(
+        "",
+        "max-upstream-queries",
+        "most queries to send to other name servers when resolving a name",
+        "COUNT",
+    );
+    opts.optopt(
+        "",
+        "cache-size",
+        "maximum number of domains to keep in the cache, 0 for no limit",
+        "COUNT",
+    );
+    opts.optopt(
+        "",
+        "stale-window",
+        "seconds to keep serving expired records when upstream servers are unreachable",
+        "SECONDS",
+    );
+    opts.optopt(
+        "",
+        "stale-answer-timeout",
+        "milliseconds to wait for upstream servers before answering with expired records",
+        "MS",
+    );
+    opts.optopt(
+        "",
+        "stale-recheck",
+        "seconds to answer with expired records right away after failing to refresh them",
+        "SECONDS",
+    );
+    opts.optopt(
+        "",
+        "min-ttl",
+        "shortest time to cache any record for",
+        "SECONDS",
+    );
+    opts.optopt(
+        "",
+        "max-ttl",
+        "longest time to cache any record for, 0 for no limit",
+        "SECONDS",
+    );
+    opts.optopt(
+        "",
+        "max-negative-ttl",
+        "longest time to cache a missing domain for, 0 for no limit",
+        "SECONDS",
+    );
+    opts.optmulti(
+        "",
+        "ttl-override",
+        "longest time to cache records at or below a domain for",
+        "DOMAIN=SECONDS",
+    );
+    opts.optopt(
+        "",
+        "cache-file",
+        "where to keep a snapshot of the cache between restarts, which is only kept \
+         when this is given",
+        "PATH",
+    );
 
     let opt_matches = match opts.parse(&args[1..]) {
         Ok(m) => m,
@@ -48,22 +191,57 @@ fn main() {
     let mut context = Arc::new(ServerContext::new());
 
     if let Some(ctx) = Arc::get_mut(&mut context) {
-        let mut index_rootservers = true;
         if opt_matches.opt_present("f") {
-            match opt_matches
-                .opt_str("f")
-                .and_then(|x| x.parse::<Ipv4Addr>().ok())
-            {
-                Some(ip) => {
-                    ctx.resolve_strategy = ResolveStrategy::Forward {
-                        host: ip.to_string(),
-                        port: 53,
-                    };
-                    index_rootservers = false;
-                    println!("Running as forwarder");
+            let mut servers = Vec::new();
+            for server in opt_matches.opt_strs("f") {
+                match parse_forwarder(&server) {
+                    Some(addr) => servers.push(addr),
+                    None => {
+                        println!(
+                            "Forward parameter must be a valid IP address, optionally with a \
+                             port, and prefixed with tls:// or https:// for DNS over TLS or \
+                             HTTPS"
+                        );
+                        return;
+                    }
+                }
+            }
+
+            let order = match opt_matches.opt_str("forward-order").as_deref() {
+                None | Some("ordered") => ForwardOrder::Ordered,
+                Some("random") => ForwardOrder::Random,
+                _ => {
+                    println!("Forward order parameter must be ordered or random");
+                    return;
+                }
+            };
+
+            ctx.resolve_strategy = ResolveStrategy::Forward { servers, order };
+            println!("Running as forwarder");
+        }
+
+        ctx.tls_ca_file = opt_matches.opt_str("tls-ca");
+        ctx.tls_pins = opt_matches.opt_strs("tls-pin");
+
+        ctx.https_method = match opt_matches.opt_str("https-method").as_deref() {
+            None | Some("post") => HttpsMethod::Post,
+            Some("get") => HttpsMethod::Get,
+            _ => {
+                println!("HTTPS method parameter must be post or get");
+                return;
+            }
+        };
+
+        for rule in opt_matches.opt_strs("route") {
+            match parse_rule(&rule) {
+                Some((domain, target)) => {
+                    if let Err(e) = ctx.routes.add(&domain, target) {
+                        println!("Failed to add route for {}: {:?}", domain, e);
+                        return;
+                    }
                 }
                 None => {
-                    println!("Forward parameter must be a valid Ipv4 address");
+                    println!("Route must be given as DOMAIN=SERVERS or DOMAIN=recursive");
                     return;
                 }
             }
@@ -71,6 +249,11 @@ fn main() {
 
         if opt_matches.opt_present("a") {
             ctx.allow_recursive = false;
+
+            if ctx.routes.has_recursive() {
+                println!("Routes to the recursive resolver require recursive lookups");
+                return;
+            }
         }
 
         if opt_matches.opt_present("p") {
@@ -85,6 +268,243 @@ fn main() {
             }
         }
 
+        if opt_matches.opt_present("ip-version") {
+            match opt_matches.opt_str("ip-version").as_deref() {
+                Some("4") => ctx.ip_preference = IpPreference::V4Only,
+                Some("6") => ctx.ip_preference = IpPreference::V6Only,
+                Some("dual") => ctx.ip_preference = IpPreference::DualStack,
+                _ => {
+                    println!("IP version parameter must be 4, 6 or dual");
+                    return;
+                }
+            }
+        }
+
+        if opt_matches.opt_present("no-qname-minimisation") {
+            ctx.qname_minimisation = false;
+        }
+
+        if opt_matches.opt_present("query-timeout") {
+            match opt_matches
+                .opt_str("query-timeout")
+                .and_then(|x| x.parse::<u64>().ok())
+            {
+                Some(timeout) => {
+                    ctx.query_timeout = timeout;
+                }
+                None => {
+                    println!("Query timeout parameter must be a valid number");
+                    return;
+                }
+            }
+        }
+
+        if opt_matches.opt_present("resolve-timeout") {
+            match opt_matches
+                .opt_str("resolve-timeout")
+                .and_then(|x| x.parse::<u64>().ok())
+            {
+                Some(timeout) => {
+                    ctx.resolve_timeout = timeout;
+                }
+                None => {
+                    println!("Resolve timeout parameter must be a valid number");
+                    return;
+                }
+            }
+        }
+
+        if opt_matches.opt_present("max-referrals") {
+            match opt_matches
+                .opt_str("max-referrals")
+                .and_then(|x| x.parse::<usize>().ok())
+            {
+                Some(count) => {
+                    ctx.max_referrals = count;
+                }
+                None => {
+                    println!("Max referrals parameter must be a valid number");
+                    return;
+                }
+            }
+        }
+
+        if opt_matches.opt_present("max-nested-lookups") {
+            match opt_matches
+                .opt_str("max-nested-lookups")
+                .and_then(|x| x.parse::<usize>().ok())
+            {
+                Some(count) => {
+                    ctx.max_nested_lookups = count;
+                }
+                None => {
+                    println!("Max nested lookups parameter must be a valid number");
+                    return;
+                }
+            }
+        }
+
+        if opt_matches.opt_present("max-upstream-queries") {
+            match opt_matches
+                .opt_str("max-upstream-queries")
+                .and_then(|x| x.parse::<usize>().ok())
+            {
+                Some(count) => {
+                    ctx.max_upstream_queries = count;
+                }
+                None => {
+                    println!("Max upstream queries parameter must be a valid number");
+                    return;
+                }
+            }
+        }
+
+        let mut cache_config = CacheConfig::default();
+
+        if opt_matches.opt_present("cache-size") {
+            match opt_matches
+                .opt_str("cache-size")
+                .and_then(|x| x.parse::<usize>().ok())
+            {
+                Some(size) => {
+                    cache_config.max_entries = size;
+                }
+                None => {
+                    println!("Cache size parameter must be a valid number");
+                    return;
+                }
+            }
+        }
+
+        if opt_matches.opt_present("stale-window") {
+            match opt_matches
+                .opt_str("stale-window")
+                .and_then(|x| x.parse::<u32>().ok())
+            {
+                Some(window) => {
+                    cache_config.stale_window = window;
+                }
+                None => {
+                    println!("Stale window parameter must be a valid number");
+                    return;
+                }
+            }
+        }
+
+        if opt_matches.opt_present("stale-answer-timeout") {
+            match opt_matches
+                .opt_str("stale-answer-timeout")
+                .and_then(|x| x.parse::<u64>().ok())
+            {
+                Some(timeout) => {
+                    ctx.stale_answer_timeout = timeout;
+                }
+                None => {
+                    println!("Stale answer timeout parameter must be a valid number");
+                    return;
+                }
+            }
+        }
+
+        if opt_matches.opt_present("stale-recheck") {
+            match opt_matches
+                .opt_str("stale-recheck")
+                .and_then(|x| x.parse::<u32>().ok())
+            {
+                Some(delay) => {
+                    cache_config.stale_recheck = delay;
+                }
+                None => {
+                    println!("Stale recheck parameter must be a valid number");
+                    return;
+                }
+            }
+        }
+
+        if opt_matches.opt_present("min-ttl") {
+            match opt_matches
+                .opt_str("min-ttl")
+                .and_then(|x| x.parse::<u32>().ok())
+            {
+                Some(ttl) => {
+                    cache_config.min_ttl = ttl;
+                }
+                None => {
+                    println!("Min TTL parameter must be a valid number");
+                    return;
+                }
+            }
+        }
+
+        if opt_matches.opt_present("max-ttl") {
+            match opt_matches
+                .opt_str("max-ttl")
+                .and_then(|x| x.parse::<u32>().ok())
+            {
+                Some(ttl) => {
+                    cache_config.max_ttl = ttl;
+                }
+                None => {
+                    println!("Max TTL parameter must be a valid number");
+                    return;
+                }
+            }
+        }
+
+        if opt_matches.opt_present("max-negative-ttl") {
+            match opt_matches
+                .opt_str("max-negative-ttl")
+                .and_then(|x| x.parse::<u32>().ok())
+            {
+                Some(ttl) => {
+                    cache_config.max_negative_ttl = ttl;
+                }
+                None => {
+                    println!("Max negative TTL parameter must be a valid number");
+                    return;
+                }
+            }
+        }
+
+        for rule in opt_matches.opt_strs("ttl-override") {
+            match rule
+                .split_once('=')
+                .and_then(|(suffix, x)| x.parse::<u32>().ok().map(|ttl| (suffix, ttl)))
+            {
+                Some((suffix, max_ttl)) => {
+                    cache_config.ttl_overrides.push(TtlOverride {
+                        suffix: normalize(suffix.trim().trim_start_matches("*.")),
+                        max_ttl,
+                    });
+                }
+                None => {
+                    println!("TTL override must be given as DOMAIN=SECONDS");
+                    return;
+                }
+            }
+        }
+
+        ctx.cache = SynchronizedCache::with_config(cache_config);
+
+        ctx.cache_file = opt_matches
+            .opt_str("cache-file")
+            .filter(|path| !path.is_empty());
+
+        // The hints are needed for routes to the recursive resolver even when
+        // forwarding everything else, and those can be added at any time
+        if ctx.allow_recursive {
+            ctx.root_hints = match opt_matches.opt_str("root-hints") {
+                Some(path) => match load_root_hints(&path) {
+                    Ok(hints) => hints,
+                    Err(e) => {
+                        println!("Failed to read root hints from {}: {:?}", path, e);
+                        return;
+                    }
+                },
+                None => default_root_hints(),
+            };
+        }
+
         match ctx.initialize() {
             Ok(_) => {}
             Err(e) => {
@@ -92,10 +512,10 @@ fn main() {
                 return;
             }
         }
+    }
 
-        if index_rootservers {
-            let _ = ctx.cache.store(&get_rootservers());
-        }
+    if let Err(e) = ServerContext::run_background_tasks(context.clone()) {
+        println!("Failed to start background tasks: {:?}", e);
     }
 
     println!("Listening on port {}", context.dns_port);
@@ -121,207 +541,3 @@ fn main() {
         webserver.run_webserver();
     }
 }
-
-fn get_rootservers() -> Vec<DnsRecord> {
-    let mut rootservers = Vec::new();
-
-    rootservers.push(DnsRecord::NS {
-        domain: "".to_string(),
-        host: "a.root-servers.net".to_string(),
-        ttl: TransientTtl(3600000),
-    });
-    rootservers.push(DnsRecord::A {
-        domain: "a.root-servers.net".to_string(),
-        addr: "198.41.0.4".parse().unwrap(),
-        ttl: TransientTtl(3600000),
-    });
-    rootservers.push(DnsRecord::AAAA {
-        domain: "a.root-servers.net".to_string(),
-        addr: "2001:503:ba3e::2:30".parse().unwrap(),
-        ttl: TransientTtl(3600000),
-    });
-
-    rootservers.push(DnsRecord::NS {
-        domain: "".to_string(),
-        host: "b.root-servers.net".to_string(),
-        ttl: TransientTtl(3600000),
-    });
-    rootservers.push(DnsRecord::A {
-        domain: "b.root-servers.net".to_string(),
-        addr: "192.228.79.201".parse().unwrap(),
-        ttl: TransientTtl(3600000),
-    });
-    rootservers.push(DnsRecord::AAAA {
-        domain: "b.root-servers.net".to_string(),
-        addr: "2001:500:84::b".parse().unwrap(),
-        ttl: TransientTtl(3600000),
-    });
-
-    rootservers.push(DnsRecord::NS {
-        domain: "".to_string(),
-        host: "c.root-servers.net".to_string(),
-        ttl: TransientTtl(3600000),
-    });
-    rootservers.push(DnsRecord::A {
-        domain: "c.root-servers.net".to_string(),
-        addr: "192.33.4.12".parse().unwrap(),
-        ttl: TransientTtl(3600000),
-    });
-    rootservers.push(DnsRecord::AAAA {
-        domain: "c.root-servers.net".to_string(),
-        addr: "2001:500:2::c".parse().unwrap(),
-        ttl: TransientTtl(3600000),
-    });
-
-    rootservers.push(DnsRecord::NS {
-        domain: "".to_string(),
-        host: "d.root-servers.net".to_string(),
-        ttl: TransientTtl(3600000),
-    });
-    rootservers.push(DnsRecord::A {
-        domain: "d.root-servers.net".to_string(),
-        addr: "199.7.91.13".parse().unwrap(),
-        ttl: TransientTtl(3600000),
-    });
-    rootservers.push(DnsRecord::AAAA {
-        domain: "d.root-servers.net".to_string(),
-        addr: "2001:500:2d::d".parse().unwrap(),
-        ttl: TransientTtl(3600000),
-    });
-
-    rootservers.push(DnsRecord::NS {
-        domain: "".to_string(),
-        host: "e.root-servers.net".to_string(),
-        ttl: TransientTtl(3600000),
-    });
-    rootservers.push(DnsRecord::A {
-        domain: "e.root-servers.net".to_string(),
-        addr: "192.203.230.10".parse().unwrap(),
-        ttl: TransientTtl(3600000),
-    });
-
-    rootservers.push(DnsRecord::NS {
-        domain: "".to_string(),
-        host: "f.root-servers.net".to_string(),
-        ttl: TransientTtl(3600000),
-    });
-    rootservers.push(DnsRecord::A {
-        domain: "f.root-servers.net".to_string(),
-        addr: "192.5.5.241".parse().unwrap(),
-        ttl: TransientTtl(3600000),
-    });
-    rootservers.push(DnsRecord::AAAA {
-        domain: "f.root-servers.net".to_string(),
-        addr: "2001:500:2f::f".parse().unwrap(),
-        ttl: TransientTtl(3600000),
-    });
-
-    rootservers.push(DnsRecord::NS {
-        domain: "".to_string(),
-        host: "g.root-servers.net".to_string(),
-        ttl: TransientTtl(3600000),
-    });
-    rootservers.push(DnsRecord::A {
-        domain: "g.root-servers.net".to_string(),
-        addr: "192.112.36.4".parse().unwrap(),
-        ttl: TransientTtl(3600000),
-    });
-
-    rootservers.push(DnsRecord::NS {
-        domain: "".to_string(),
-        host: "h.root-servers.net".to_string(),
-        ttl: TransientTtl(3600000),
-    });
-    rootservers.push(DnsRecord::A {
-        domain: "h.root-servers.net".to_string(),
-        addr: "198.97.190.53".parse().unwrap(),
-        ttl: TransientTtl(3600000),
-    });
-    rootservers.push(DnsRecord::AAAA {
-        domain: "h.root-servers.net".to_string(),
-        addr: "2001:500:1::53".parse().unwrap(),
-        ttl: TransientTtl(3600000),
-    });
-
-    rootservers.push(DnsRecord::NS {
-        domain: "".to_string(),
-        host: "i.root-servers.net".to_string(),
-        ttl: TransientTtl(3600000),
-    });
-    rootservers.push(DnsRecord::A {
-        domain: "i.root-servers.net".to_string(),
-        addr: "192.36.148.17".parse().unwrap(),
-        ttl: TransientTtl(3600000),
-    });
-    rootservers.push(DnsRecord::AAAA {
-        domain: "i.root-servers.net".to_string(),
-        addr: "2001:7fe::53".parse().unwrap(),
-        ttl: TransientTtl(3600000),
-    });
-
-    rootservers.push(DnsRecord::NS {
-        domain: "".to_string(),
-        host: "j.root-servers.net".to_string(),
-        ttl: TransientTtl(3600000),
-    });
-    rootservers.push(DnsRecord::A {
-        domain: "j.root-servers.net".to_string(),
-        addr: "192.58.128.30".parse().unwrap(),
-        ttl: TransientTtl(3600000),
-    });
-    rootservers.push(DnsRecord::AAAA {
-        domain: "j.root-servers.net".to_string(),
-        addr: "2001:503:c27::2:30".parse().unwrap(),
-        ttl: TransientTtl(3600000),
-    });
-
-    rootservers.push(DnsRecord::NS {
-        domain: "".to_string(),
-        host: "k.root-servers.net".to_string(),
-        ttl: TransientTtl(3600000),
-    });
-    rootservers.push(DnsRecord::A {
-        domain: "k.root-servers.net".to_string(),
-        addr: "193.0.14.129".parse().unwrap(),
-        ttl: TransientTtl(3600000),
-    });
-    rootservers.push(DnsRecord::AAAA {
-        domain: "k.root-servers.net".to_string(),
-        addr: "2001:7fd::1".parse().unwrap(),
-        ttl: TransientTtl(3600000),
-    });
-
-    rootservers.push(DnsRecord::NS {
-        domain: "".to_string(),
-        host: "l.root-servers.net".to_string(),
-        ttl: TransientTtl(3600000),
-    });
-    rootservers.push(DnsRecord::A {
-        domain: "l.root-servers.net".to_string(),
-        addr: "199.7.83.42".parse().unwrap(),
-        ttl: TransientTtl(3600000),
-    });
-    rootservers.push(DnsRecord::AAAA {
-        domain: "l.root-servers.net".to_string(),
-        addr: "2001:500:3::42".parse().unwrap(),
-        ttl: TransientTtl(3600000),
-    });
-
-    rootservers.push(DnsRecord::NS {
-        domain: "".to_string(),
-        host: "m.root-servers.net".to_string(),
-        ttl: TransientTtl(3600000),
-    });
-    rootservers.push(DnsRecord::A {
-        domain: "m.root-servers.net".to_string(),
-        addr: "202.12.27.33".parse().unwrap(),
-        ttl: TransientTtl(3600000),
-    });
-    rootservers.push(DnsRecord::AAAA {
-        domain: "m.root-servers.net".to_string(),
-        addr: "2001:dc3::35".parse().unwrap(),
-        ttl: TransientTtl(3600000),
-    });
-
-    rootservers
-}
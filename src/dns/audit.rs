@@ -0,0 +1,276 @@
+//! an append-only log of the changes made to local zones
+
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use chrono::*;
+use derive_more::{Display, Error, From};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::dns::authority::Zone;
+use crate::dns::protocol::DnsRecord;
+
+#[derive(Debug, Display, From, Error)]
+pub enum AuditError {
+    Io(std::io::Error),
+    Serialization(serde_json::Error),
+    UnknownVersion,
+}
+
+type Result<T> = std::result::Result<T, AuditError>;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AuditOperation {
+    CreateZone,
+    AddRecord,
+    DeleteRecord,
+    Import,
+    Restore,
+}
+
+/// A single change to a zone
+///
+/// `serial` is the serial of the zone after the change was applied, which is
+/// what identifies a version of the zone. A change that affects several records
+/// is logged as one entry per record, all sharing the same serial.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub timestamp: DateTime<Local>,
+    pub client: String,
+    pub operation: AuditOperation,
+    pub serial: u32,
+    pub before: Option<DnsRecord>,
+    pub after: Option<DnsRecord>,
+}
+
+impl AuditEntry {
+    pub fn new(
+        client: &str,
+        operation: AuditOperation,
+        serial: u32,
+        before: Option<DnsRecord>,
+        after: Option<DnsRecord>,
+    ) -> AuditEntry {
+        AuditEntry {
+            timestamp: Local::now(),
+            client: client.to_string(),
+            operation,
+            serial,
+            before,
+            after,
+        }
+    }
+}
+
+/// The audit logs of all zones, kept in memory and mirrored to one file per
+/// zone, with one JSON encoded entry per line
+pub struct AuditLog {
+    dir: PathBuf,
+    entries: BTreeMap<String, Vec<AuditEntry>>,
+}
+
+impl Default for AuditLog {
+    fn default() -> Self {
+        AuditLog::new("audit")
+    }
+}
+
+impl AuditLog {
+    pub fn new<P: AsRef<Path>>(dir: P) -> AuditLog {
+        AuditLog {
+            dir: dir.as_ref().to_path_buf(),
+            entries: BTreeMap::new(),
+        }
+    }
+
+    pub fn load(&mut self) -> Result<()> {
+        let audit_dir = match self.dir.read_dir() {
+            Ok(x) => x,
+            Err(_) => return Ok(()),
+        };
+
+        for wrapped_filename in audit_dir {
+            let path = match wrapped_filename {
+                Ok(x) => x.path(),
+                Err(_) => continue,
+            };
+
+            let zone = match path
+                .file_name()
+                .and_then(|x| x.to_str())
+                .and_then(|x| x.strip_suffix(".log"))
+            {
+                Some(x) => x.to_string(),
+                None => continue,
+            };
+
+            // A damaged entry is skipped rather than keeping every zone from
+            // loading, at the cost of a gap in the history of this one
+            let mut entries = Vec::new();
+            for (num, line) in BufReader::new(File::open(&path)?).lines().enumerate() {
+                match serde_json::from_str(&line?) {
+                    Ok(entry) => entries.push(entry),
+                    Err(e) => {
+                        println!("Skipping line {} of audit log {:?}: {:?}", num + 1, path, e)
+                    }
+                }
+            }
+
+            self.entries.insert(zone, entries);
+        }
+
+        Ok(())
+    }
+
+    /// Append entries to the log of a zone, writing them to disk before they
+    /// become visible in memory
+    pub fn append(&mut self, zone: &str, new_entries: Vec<AuditEntry>) -> Result<()> {
+        if new_entries.is_empty() {
+            return Ok(());
+        }
+
+        fs::create_dir_all(&self.dir)?;
+
+        let mut data = String::new();
+        for entry in &new_entries {
+            data.push_str(&serde_json::to_string(entry)?);
+            data.push('\n');
+        }
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.dir.join(format!("{}.log", zone)))?;
+        file.write_all(data.as_bytes())?;
+
+        self.entries
+            .entry(zone.to_string())
+            .or_default()
+            .extend(new_entries);
+
+        Ok(())
+    }
+
+    pub fn entries(&self, zone: &str) -> &[AuditEntry] {
+        self.entries.get(zone).map(|x| x.as_slice()).unwrap_or(&[])
+    }
+
+    /// Reconstruct the records of `zone` as they were at version `serial`, by
+    /// undoing every logged change made after it
+    pub fn revert(&self, zone: &Zone, serial: u32) -> Result<Zone> {
+        let entries = self.entries(&zone.domain);
+
+        let oldest = entries
+            .first()
+            .map(|x| x.serial.saturating_sub(1))
+            .unwrap_or(zone.serial);
+        if serial > zone.serial || serial < oldest {
+            return Err(AuditError::UnknownVersion);
+        }
+
+        let mut result = zone.clone();
+        for entry in entries.iter().rev().take_while(|x| x.serial > serial) {
+            if let Some(ref after) = entry.after {
+                result.delete_record(after);
+            }
+            if let Some(ref before) = entry.before {
+                result.add_record(before);
+            }
+        }
+
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use crate::dns::protocol::TransientTtl;
+
+    fn record(addr: &str) -> DnsRecord {
+        DnsRecord::A {
+            domain: "www.example.com".to_string(),
+            addr: addr.parse().unwrap(),
+            ttl: TransientTtl(3600),
+        }
+    }
+
+    #[test]
+    fn test_revert() {
+        let mut log = AuditLog::new("audit");
+
+        let mut zone = Zone::new("example.com".to_string(), String::new(), String::new());
+
+        // Build up a history without touching the disk
+        let history = vec![
+            AuditEntry::new("127.0.0.1", AuditOperation::CreateZone, 0, None, None),
+            AuditEntry::new(
+                "127.0.0.1",
+                AuditOperation::AddRecord,
+                1,
+                None,
+                Some(record("127.0.0.1")),
+            ),
+            AuditEntry::new(
+                "127.0.0.1",
+                AuditOperation::Import,
+                2,
+                Some(record("127.0.0.1")),
+                None,
+            ),
+            AuditEntry::new(
+                "127.0.0.1",
+                AuditOperation::Import,
+                2,
+                None,
+                Some(record("127.0.0.2")),
+            ),
+        ];
+        log.entries.insert("example.com".to_string(), history);
+
+        zone.serial = 2;
+        zone.add_record(&record("127.0.0.2"));
+
+        let v1 = log.revert(&zone, 1).unwrap();
+        assert_eq!(1, v1.records.len());
+        assert!(v1.records.contains(&record("127.0.0.1")));
+
+        let v0 = log.revert(&zone, 0).unwrap();
+        assert!(v0.records.is_empty());
+
+        let v2 = log.revert(&zone, 2).unwrap();
+        assert_eq!(zone.records, v2.records);
+
+        assert!(log.revert(&zone, 3).is_err());
+    }
+
+    #[test]
+    fn test_load_damaged_log() {
+        let dir = std::env::temp_dir().join(format!("hermes-audit-log-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let entry = AuditEntry::new(
+            "127.0.0.1",
+            AuditOperation::AddRecord,
+            1,
+            None,
+            Some(record("127.0.0.1")),
+        );
+        let data = format!(
+            "{}\n{{\"timestamp\": \"yesterday\"\n",
+            serde_json::to_string(&entry).unwrap()
+        );
+        fs::write(dir.join("example.com.log"), data).unwrap();
+
+        // The damaged line is left out, and the rest is kept
+        let mut log = AuditLog::new(&dir);
+        log.load().unwrap();
+        assert_eq!(1, log.entries("example.com").len());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
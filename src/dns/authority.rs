@@ -7,12 +7,15 @@ use std::path::Path;
 use std::sync::{LockResult, RwLock, RwLockReadGuard, RwLockWriteGuard};
 
 use derive_more::{Display, From, Error};
+use serde_derive::{Deserialize, Serialize};
 
+use crate::dns::audit::{AuditEntry, AuditLog};
 use crate::dns::buffer::{PacketBuffer, StreamPacketBuffer, VectorPacketBuffer};
 use crate::dns::protocol::{DnsPacket, DnsRecord, QueryType, ResultCode, TransientTtl};
 
 #[derive(Debug, Display, From, Error)]
 pub enum AuthorityError {
+    Audit(crate::dns::audit::AuditError),
     Buffer(crate::dns::buffer::BufferError),
     Protocol(crate::dns::protocol::ProtocolError),
     Io(std::io::Error),
@@ -21,7 +24,8 @@ pub enum AuthorityError {
 
 type Result<T> = std::result::Result<T, AuthorityError>;
 
-#[derive(Clone, Debug, Default)]
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Zone {
     pub domain: String,
     pub m_name: String,
@@ -56,17 +60,53 @@ impl Zone {
     pub fn delete_record(&mut self, rec: &DnsRecord) -> bool {
         self.records.remove(rec)
     }
+
+    /// Compute the changes needed to turn this zone into `other`
+    ///
+    /// Records are considered equal regardless of TTL when stored in a zone, so
+    /// the TTL is compared separately here. A record with a changed TTL shows
+    /// up as both removed and added.
+    pub fn diff(&self, other: &Zone) -> ZoneDiff {
+        let identical = |a: &DnsRecord, set: &BTreeSet<DnsRecord>| {
+            set.get(a)
+                .map(|b| a.get_ttl() == b.get_ttl())
+                .unwrap_or(false)
+        };
+
+        ZoneDiff {
+            added: other
+                .records
+                .iter()
+                .filter(|rec| !identical(rec, &self.records))
+                .cloned()
+                .collect(),
+            removed: self
+                .records
+                .iter()
+                .filter(|rec| !identical(rec, &other.records))
+                .cloned()
+                .collect(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ZoneDiff {
+    pub added: Vec<DnsRecord>,
+    pub removed: Vec<DnsRecord>,
 }
 
 #[derive(Default)]
 pub struct Zones {
     zones: BTreeMap<String, Zone>,
+    audit: AuditLog,
 }
 
 impl<'a> Zones {
     pub fn new() -> Zones {
         Zones {
             zones: BTreeMap::new(),
+            audit: AuditLog::default(),
         }
     }
 
@@ -108,6 +148,8 @@ impl<'a> Zones {
             self.zones.insert(zone.domain.clone(), zone);
         }
 
+        self.audit.load()?;
+
         Ok(())
     }
 
@@ -159,6 +201,20 @@ impl<'a> Zones {
     pub fn get_zone_mut(&'a mut self, domain: &str) -> Option<&'a mut Zone> {
         self.zones.get_mut(domain)
     }
+
+    pub fn audit(&self) -> &AuditLog {
+        &self.audit
+    }
+
+    /// Put a changed version of a zone in place, along with the entries
+    /// describing the changes. The entries are appended to the audit log
+    /// first, so that if that fails the zone is left as it was, and no
+    /// change is ever made without being logged.
+    pub fn commit(&mut self, zone: Zone, entries: Vec<AuditEntry>) -> Result<()> {
+        self.audit.append(&zone.domain, entries)?;
+        self.add_zone(zone);
+        self.save()
+    }
 }
 
 #[derive(Default)]
@@ -255,3 +311,52 @@ impl Authority {
         self.zones.write()
     }
 }
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use crate::dns::audit::AuditOperation;
+
+    #[test]
+    fn test_commit_without_audit_log() {
+        // The audit log can't be written, since its directory would have to
+        // be created below a file
+        let blocker = std::env::temp_dir().join(format!("hermes-audit-{}", std::process::id()));
+        File::create(&blocker).unwrap();
+
+        let mut zones = Zones {
+            zones: BTreeMap::new(),
+            audit: AuditLog::new(blocker.join("audit")),
+        };
+
+        let mut zone = Zone::new(
+            "example.com".to_string(),
+            "ns1.example.com".to_string(),
+            "admin.example.com".to_string(),
+        );
+        zone.serial = 1;
+        zones.add_zone(zone.clone());
+
+        let rec = DnsRecord::A {
+            domain: "www.example.com".to_string(),
+            addr: "127.0.0.1".parse().unwrap(),
+            ttl: TransientTtl(3600),
+        };
+        let mut changed = zone.clone();
+        changed.add_record(&rec);
+        changed.serial = 2;
+
+        let entry = AuditEntry::new("127.0.0.1", AuditOperation::AddRecord, 2, None, Some(rec));
+        assert!(zones.commit(changed, vec![entry]).is_err());
+
+        // Nothing was changed, since the change couldn't be logged
+        let current = zones.get_zone("example.com").unwrap();
+        assert_eq!(1, current.serial);
+        assert!(current.records.is_empty());
+        assert!(zones.audit().entries("example.com").is_empty());
+
+        std::fs::remove_file(&blocker).unwrap();
+    }
+}
@@ -2,18 +2,24 @@
 
 use std::clone::Clone;
 use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
 use std::hash::{Hash, Hasher};
-use std::sync::{Arc, RwLock};
+use std::io::ErrorKind;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 
+use ::serde::{Deserializer, Serializer};
 use chrono::*;
 use derive_more::{Display, Error, From};
 use serde_derive::{Deserialize, Serialize};
 
-use crate::dns::protocol::{DnsPacket, DnsRecord, QueryType, ResultCode};
+use crate::dns::protocol::{is_subdomain, DnsPacket, DnsRecord, QueryType, ResultCode};
 
 #[derive(Debug, Display, From, Error)]
 pub enum CacheError {
     Io(std::io::Error),
+    Serialization(serde_json::Error),
     PoisonedLock,
 }
 
@@ -25,10 +31,39 @@ pub enum CacheState {
     NotCached,
 }
 
+/// How much a record can be trusted, depending on where in a response it was
+/// found, ranked from least to most trustworthy (RFC 2181, section 5.4.1)
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Trust {
+    /// Addresses from the additional section, or hints from the configuration
+    Glue,
+    /// Records from the authority section, such as the NS records of a referral
+    Authority,
+    /// Records from the answer section
+    #[default]
+    Answer,
+}
+
 #[derive(Clone, Eq, Debug, Serialize, Deserialize)]
 pub struct RecordEntry {
     pub record: DnsRecord,
     pub timestamp: DateTime<Local>,
+    #[serde(default)]
+    pub trust: Trust,
+}
+
+impl RecordEntry {
+    /// The number of seconds left of the original TTL
+    pub fn remaining_ttl(&self, now: DateTime<Local>) -> u32 {
+        remaining_ttl(self.record.get_ttl(), self.timestamp, now)
+    }
+}
+
+/// Count down a TTL from the time it was stored, so that answers served from
+/// the cache never claim to be valid for longer than the original record was
+pub fn remaining_ttl(ttl: u32, timestamp: DateTime<Local>, now: DateTime<Local>) -> u32 {
+    let elapsed = (now - timestamp).num_seconds().max(0);
+    (ttl as i64 - elapsed).max(0) as u32
 }
 
 impl PartialEq<RecordEntry> for RecordEntry {
@@ -46,12 +81,34 @@ impl Hash for RecordEntry {
     }
 }
 
+/// Copy the SOA of a negative entry for use in an answer, counting its TTL
+/// down the same way as for the records themselves (RFC 2308)
+fn negative_soa(soa: &Option<DnsRecord>, ttl: u32) -> Option<DnsRecord> {
+    soa.as_ref().map(|soa| {
+        let mut soa = soa.clone();
+        soa.set_ttl(ttl);
+        soa
+    })
+}
+
+/// A name which doesn't exist at all. As described in RFC 8020, this also
+/// means that nothing exists below it.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct NxDomainEntry {
+    pub ttl: u32,
+    pub timestamp: DateTime<Local>,
+    pub soa: Option<DnsRecord>,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum RecordSet {
+    /// The name exists, but has no records of this type
     NoRecords {
         qtype: QueryType,
         ttl: u32,
         timestamp: DateTime<Local>,
+        #[serde(default)]
+        soa: Option<DnsRecord>,
     },
     Records {
         qtype: QueryType,
@@ -59,12 +116,58 @@ pub enum RecordSet {
     },
 }
 
-#[derive(Clone, Debug)]
+/// A statistic which can be updated while the cache is only locked for
+/// reading, so that lookups don't have to wait for each other
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn new(value: u64) -> Counter {
+        Counter(AtomicU64::new(value))
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub fn set(&self, value: u64) {
+        self.0.store(value, Ordering::Relaxed)
+    }
+
+    pub fn increment(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl Clone for Counter {
+    fn clone(&self) -> Self {
+        Counter::new(self.get())
+    }
+}
+
+impl ::serde::Serialize for Counter {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_u64(self.get())
+    }
+}
+
+impl<'de> ::serde::Deserialize<'de> for Counter {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        <u64 as ::serde::Deserialize>::deserialize(deserializer).map(Counter::new)
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct DomainEntry {
     pub domain: String,
     pub record_types: HashMap<QueryType, RecordSet>,
-    pub hits: u32,
+    #[serde(default)]
+    pub nxdomain: Option<NxDomainEntry>,
+    pub hits: Counter,
     pub updates: u32,
+    /// When the entry was last used, as a tick of the cache holding it
+    #[serde(skip)]
+    last_used: Counter,
 }
 
 impl DomainEntry {
@@ -72,35 +175,112 @@ impl DomainEntry {
         DomainEntry {
             domain: domain,
             record_types: HashMap::new(),
-            hits: 0,
+            nxdomain: None,
+            hits: Counter::default(),
             updates: 0,
+            last_used: Counter::default(),
         }
     }
 
-    pub fn store_nxdomain(&mut self, qtype: QueryType, ttl: u32) {
+    pub fn is_empty(&self) -> bool {
+        self.record_types.is_empty() && self.nxdomain.is_none()
+    }
+
+    /// Drop every record that expired before `cutoff`, returning the number of
+    /// records removed
+    pub fn remove_expired(&mut self, cutoff: DateTime<Local>) -> usize {
+        let mut removed = 0;
+
+        if let Some(NxDomainEntry { ttl, timestamp, .. }) = self.nxdomain {
+            if timestamp + Duration::seconds(ttl as i64) < cutoff {
+                self.nxdomain = None;
+            }
+        }
+        self.record_types.retain(|_, rs| match *rs {
+            RecordSet::NoRecords { ttl, timestamp, .. } => {
+                timestamp + Duration::seconds(ttl as i64) >= cutoff
+            }
+            RecordSet::Records {
+                ref mut records, ..
+            } => {
+                let before = records.len();
+                records.retain(|entry| {
+                    entry.timestamp + Duration::seconds(entry.record.get_ttl() as i64) >= cutoff
+                });
+                removed += before - records.len();
+
+                !records.is_empty()
+            }
+        });
+
+        removed
+    }
+
+    pub fn store_nodata(
+        &mut self,
+        qtype: QueryType,
+        ttl: u32,
+        soa: Option<&DnsRecord>,
+        config: &CacheConfig,
+    ) {
         self.updates += 1;
 
         let new_set = RecordSet::NoRecords {
-            qtype: qtype,
-            ttl: ttl,
+            qtype,
+            ttl: config.negative_ttl(&self.domain, ttl),
             timestamp: Local::now(),
+            soa: soa.cloned(),
         };
 
         self.record_types.insert(qtype, new_set);
     }
 
-    pub fn store_record(&mut self, rec: &DnsRecord) {
+    /// Mark the name as not existing, which replaces anything previously known
+    /// about it
+    pub fn store_nxdomain(&mut self, ttl: u32, soa: Option<&DnsRecord>, config: &CacheConfig) {
         self.updates += 1;
 
-        let entry = RecordEntry {
-            record: rec.clone(),
+        self.record_types.clear();
+        self.nxdomain = Some(NxDomainEntry {
+            ttl: config.negative_ttl(&self.domain, ttl),
             timestamp: Local::now(),
+            soa: soa.cloned(),
+        });
+    }
+
+    /// Store a record, unless the cache already holds records of the same type
+    /// from a more trustworthy source. Records from a more trustworthy source
+    /// replace all the ones that are less so.
+    pub fn store_record(&mut self, rec: &DnsRecord, config: &CacheConfig, trust: Trust) {
+        let mut record = rec.clone();
+        record.set_ttl(config.ttl(&self.domain, rec.get_ttl()));
+
+        let now = Local::now();
+        let entry = RecordEntry {
+            record,
+            timestamp: now,
+            trust,
         };
 
         if let Some(&mut RecordSet::Records {
             ref mut records, ..
         }) = self.record_types.get_mut(&rec.get_querytype())
         {
+            let best = records
+                .iter()
+                .filter(|x| x.remaining_ttl(now) > 0)
+                .map(|x| x.trust)
+                .max();
+
+            match best {
+                Some(best) if best > trust => return,
+                Some(best) if best < trust => records.clear(),
+                _ => {}
+            }
+
+            self.updates += 1;
+            self.nxdomain = None;
+
             if records.contains(&entry) {
                 records.remove(&entry);
             }
@@ -109,6 +289,9 @@ impl DomainEntry {
             return;
         }
 
+        self.updates += 1;
+        self.nxdomain = None;
+
         let mut records = HashSet::new();
         records.insert(entry);
 
@@ -159,6 +342,34 @@ impl DomainEntry {
         }
     }
 
+    /// Check whether any unexpired record of `qtype` has less than `percent`
+    /// of its original TTL left
+    pub fn is_expiring(&self, qtype: QueryType, percent: u32, now: DateTime<Local>) -> bool {
+        match self.record_types.get(&qtype) {
+            Some(RecordSet::Records { records, .. }) => records.iter().any(|entry| {
+                let ttl = entry.record.get_ttl() as u64;
+                let remaining = entry.remaining_ttl(now) as u64;
+
+                remaining > 0 && remaining * 100 <= ttl * percent as u64
+            }),
+            _ => false,
+        }
+    }
+
+    /// Add the SOA of a cached NODATA entry for `qtype`, if there is one
+    pub fn fill_nodata_soa(&self, qtype: QueryType, result_vec: &mut Vec<DnsRecord>) {
+        if let Some(RecordSet::NoRecords {
+            ttl,
+            timestamp,
+            ref soa,
+            ..
+        }) = self.record_types.get(&qtype)
+        {
+            let remaining = remaining_ttl(*ttl, *timestamp, Local::now());
+            result_vec.extend(negative_soa(soa, remaining));
+        }
+    }
+
     pub fn fill_queryresult(&self, qtype: QueryType, result_vec: &mut Vec<DnsRecord>) {
         let now = Local::now();
 
@@ -176,26 +387,275 @@ impl DomainEntry {
                 }
 
                 if entry.record.get_querytype() == qtype {
-                    result_vec.push(entry.record.clone());
+                    let mut record = entry.record.clone();
+                    record.set_ttl(entry.remaining_ttl(now));
+                    result_vec.push(record);
                 }
             }
         }
     }
 }
 
+/// The TTL of answers served from expired entries, as suggested by RFC 8767
+pub const STALE_TTL: u32 = 30;
+
+/// Caps the TTL of every domain at or below `suffix`
+#[derive(Clone, Debug)]
+pub struct TtlOverride {
+    pub suffix: String,
+    pub max_ttl: u32,
+}
+
+/// Settings controlling the size of the cache, and how long records are kept
+#[derive(Clone, Debug)]
+pub struct CacheConfig {
+    /// The maximum number of domains kept in the cache, or 0 for no limit.
+    /// The cache may briefly grow past this by a sixteenth, since evicting
+    /// requires sorting every entry by when it was last used.
+    pub max_entries: usize,
+    /// Seconds that entries are kept after expiring, to be used as a last
+    /// resort when they can't be refreshed, or 0 to drop them right away
+    pub stale_window: u32,
+    /// Seconds to wait after failing to refresh an entry before trying
+    /// again, during which its expired records are answered with right away
+    /// (RFC 8767)
+    pub stale_recheck: u32,
+    /// The number of hits a domain needs before its records are refreshed
+    /// ahead of expiry, or 0 to never prefetch
+    pub prefetch_hits: u32,
+    /// How far into the end of its TTL a record has to be, in percent of the
+    /// original TTL, to be prefetched
+    pub prefetch_percent: u32,
+    /// The shortest time any record is cached for
+    pub min_ttl: u32,
+    /// The longest time any record is cached for, or 0 for no limit
+    pub max_ttl: u32,
+    /// The longest time an NXDOMAIN is cached for, or 0 for no limit
+    pub max_negative_ttl: u32,
+    /// Domain specific limits, which take precedence over `min_ttl`. When
+    /// several match a domain, the one with the longest suffix is used.
+    pub ttl_overrides: Vec<TtlOverride>,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            max_entries: 100_000,
+            stale_window: 86400,
+            stale_recheck: 30,
+            prefetch_hits: 10,
+            prefetch_percent: 10,
+            min_ttl: 0,
+            max_ttl: 86400,
+            max_negative_ttl: 3600,
+            ttl_overrides: Vec::new(),
+        }
+    }
+}
+
+impl CacheConfig {
+    /// The TTL to cache a record for `domain` with, given the TTL the
+    /// upstream server answered with
+    pub fn ttl(&self, domain: &str, ttl: u32) -> u32 {
+        let ttl = cap(ttl.max(self.min_ttl), self.max_ttl);
+
+        self.apply_override(domain, ttl)
+    }
+
+    pub fn negative_ttl(&self, domain: &str, ttl: u32) -> u32 {
+        let ttl = cap(ttl.max(self.min_ttl), self.max_ttl);
+        let ttl = cap(ttl, self.max_negative_ttl);
+
+        self.apply_override(domain, ttl)
+    }
+
+    fn apply_override(&self, domain: &str, ttl: u32) -> u32 {
+        self.ttl_overrides
+            .iter()
+            .filter(|x| is_subdomain(domain, &x.suffix))
+            .max_by_key(|x| x.suffix.len())
+            .map(|x| ttl.min(x.max_ttl))
+            .unwrap_or(ttl)
+    }
+}
+
+fn cap(ttl: u32, limit: u32) -> u32 {
+    if limit == 0 {
+        ttl
+    } else {
+        ttl.min(limit)
+    }
+}
+
+/// Counts of what has been dropped from the cache, and why
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct CacheStatistics {
+    pub domains: usize,
+    /// Domains dropped to keep the cache within its configured size
+    pub evictions: u64,
+    /// Records dropped by sweeps, once expired for longer than the stale
+    /// window
+    pub expirations: u64,
+}
+
 #[derive(Default)]
 pub struct Cache {
     domain_entries: BTreeMap<String, Arc<DomainEntry>>,
+    config: CacheConfig,
+    statistics: CacheStatistics,
+
+    /// An ever increasing counter, stamped on entries as they're used so
+    /// that the oldest ones can be evicted first
+    tick: AtomicU64,
 }
 
 impl Cache {
     pub fn new() -> Cache {
+        Cache::with_config(CacheConfig::default())
+    }
+
+    pub fn with_config(config: CacheConfig) -> Cache {
         Cache {
             domain_entries: BTreeMap::new(),
+            config,
+            statistics: CacheStatistics::default(),
+            tick: AtomicU64::new(0),
+        }
+    }
+
+    pub fn statistics(&self) -> CacheStatistics {
+        CacheStatistics {
+            domains: self.domain_entries.len(),
+            ..self.statistics
+        }
+    }
+
+    fn touch(&self, entry: &DomainEntry) {
+        entry
+            .last_used
+            .set(self.tick.fetch_add(1, Ordering::Relaxed) + 1);
+    }
+
+    /// The root name servers are the starting point for all recursive lookups,
+    /// so they are never evicted no matter how rarely they're used.
+    fn is_pinned(&self, qname: &str) -> bool {
+        if qname.is_empty() {
+            return true;
+        }
+
+        let root = match self.domain_entries.get("") {
+            Some(x) => x,
+            None => return false,
+        };
+
+        match root.record_types.get(&QueryType::NS) {
+            Some(RecordSet::Records { records, .. }) => records.iter().any(
+                |entry| matches!(entry.record, DnsRecord::NS { ref host, .. } if host == qname),
+            ),
+            _ => false,
+        }
+    }
+
+    /// Evict the least recently used domains once the cache has outgrown its
+    /// configured size, bringing it back down to that size
+    fn evict(&mut self) -> usize {
+        let max_entries = self.config.max_entries;
+        if max_entries == 0 || self.domain_entries.len() <= max_entries + max_entries / 16 {
+            return 0;
+        }
+
+        let mut candidates = self
+            .domain_entries
+            .iter()
+            .filter(|(qname, _)| !self.is_pinned(qname))
+            .map(|(qname, entry)| (entry.last_used.get(), qname.clone()))
+            .collect::<Vec<_>>();
+        candidates.sort_unstable();
+        candidates.truncate(self.domain_entries.len() - max_entries);
+
+        for (_, qname) in &candidates {
+            self.domain_entries.remove(qname);
+        }
+
+        self.statistics.evictions += candidates.len() as u64;
+
+        candidates.len()
+    }
+
+    /// Remove records that have been expired for longer than the stale window,
+    /// and any domains left without records
+    pub fn sweep(&mut self) -> usize {
+        let cutoff = Local::now() - Duration::seconds(self.config.stale_window as i64);
+
+        let mut swept = 0;
+        let mut empty = Vec::new();
+        for (qname, entry) in self.domain_entries.iter_mut() {
+            let entry = Arc::make_mut(entry);
+            swept += entry.remove_expired(cutoff);
+
+            if entry.is_empty() {
+                empty.push(qname.clone());
+            }
         }
+
+        for qname in &empty {
+            self.domain_entries.remove(qname);
+        }
+
+        self.statistics.expirations += swept as u64;
+
+        swept
+    }
+
+    /// Remove `qname` from the cache, along with every name below it if
+    /// `subtree` is set. The root hints are always kept, since recursive
+    /// lookups can't do without them. Returns the number of domains removed.
+    pub fn flush(&mut self, qname: &str, subtree: bool) -> usize {
+        let flushed = self
+            .domain_entries
+            .keys()
+            .filter(|x| {
+                if subtree {
+                    is_subdomain(x, qname)
+                } else {
+                    *x == qname
+                }
+            })
+            .filter(|x| !self.is_pinned(x))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        for qname in &flushed {
+            self.domain_entries.remove(qname);
+        }
+
+        flushed.len()
     }
 
-    fn get_cache_state(&mut self, qname: &str, qtype: QueryType) -> CacheState {
+    /// Add entries read back from a snapshot, skipping any records that have
+    /// expired in the meantime. Returns the number of domains restored.
+    pub fn restore(&mut self, entries: Vec<DomainEntry>) -> usize {
+        let now = Local::now();
+
+        let mut restored = 0;
+        for mut entry in entries {
+            entry.remove_expired(now);
+            if entry.is_empty() {
+                continue;
+            }
+
+            self.touch(&entry);
+            self.domain_entries
+                .insert(entry.domain.clone(), Arc::new(entry));
+            restored += 1;
+        }
+
+        self.evict();
+
+        restored
+    }
+
+    fn get_cache_state(&self, qname: &str, qtype: QueryType) -> CacheState {
         match self.domain_entries.get(qname) {
             Some(x) => x.get_cache_state(qtype),
             None => CacheState::NotCached,
@@ -203,79 +663,257 @@ impl Cache {
     }
 
     fn fill_queryresult(
-        &mut self,
+        &self,
         qname: &str,
         qtype: QueryType,
         result_vec: &mut Vec<DnsRecord>,
         increment_stats: bool,
     ) {
-        if let Some(domain_entry) = self.domain_entries.get_mut(qname).and_then(Arc::get_mut) {
+        if let Some(domain_entry) = self.domain_entries.get(qname) {
             if increment_stats {
-                domain_entry.hits += 1
+                domain_entry.hits.increment();
+                self.touch(domain_entry);
             }
 
             domain_entry.fill_queryresult(qtype, result_vec);
         }
     }
 
-    pub fn lookup(&mut self, qname: &str, qtype: QueryType) -> Option<DnsPacket> {
-        match self.get_cache_state(qname, qtype) {
-            CacheState::PositiveCache => {
-                let mut qr = DnsPacket::new();
-                self.fill_queryresult(qname, qtype, &mut qr.answers, true);
-                self.fill_queryresult(qname, QueryType::NS, &mut qr.authorities, false);
+    /// Find an unexpired NXDOMAIN for `qname` or any of its parents
+    fn find_nxdomain(&self, qname: &str, cutoff: DateTime<Local>) -> Option<&NxDomainEntry> {
+        let mut name = qname;
+        loop {
+            if let Some(nxdomain) = self
+                .domain_entries
+                .get(name)
+                .and_then(|x| x.nxdomain.as_ref())
+            {
+                if nxdomain.timestamp + Duration::seconds(nxdomain.ttl as i64) >= cutoff {
+                    return Some(nxdomain);
+                }
+            }
 
-                Some(qr)
+            match name.find('.') {
+                Some(idx) => name = &name[idx + 1..],
+                None => return None,
             }
+        }
+    }
+
+    pub fn lookup(&self, qname: &str, qtype: QueryType) -> Option<DnsPacket> {
+        let state = self.get_cache_state(qname, qtype);
+        if let CacheState::PositiveCache = state {
+            let mut qr = DnsPacket::new();
+            self.fill_queryresult(qname, qtype, &mut qr.answers, true);
+            self.fill_queryresult(qname, QueryType::NS, &mut qr.authorities, false);
+
+            return Some(qr);
+        }
+
+        let now = Local::now();
+        if let Some(nxdomain) = self.find_nxdomain(qname, now) {
+            let remaining = remaining_ttl(nxdomain.ttl, nxdomain.timestamp, now);
+
+            let mut qr = DnsPacket::new();
+            qr.header.rescode = ResultCode::NXDOMAIN;
+            qr.authorities
+                .extend(negative_soa(&nxdomain.soa, remaining));
+
+            return Some(qr);
+        }
+
+        match state {
             CacheState::NegativeCache => {
                 let mut qr = DnsPacket::new();
-                qr.header.rescode = ResultCode::NXDOMAIN;
+                if let Some(entry) = self.domain_entries.get(qname) {
+                    entry.fill_nodata_soa(qtype, &mut qr.authorities);
+                }
 
                 Some(qr)
             }
-            CacheState::NotCached => None,
+            _ => None,
+        }
+    }
+
+    /// Check whether a popular entry is about to expire, and should be
+    /// resolved again before it does to spare clients the cache miss
+    pub fn needs_prefetch(&self, qname: &str, qtype: QueryType) -> bool {
+        if self.config.prefetch_hits == 0 {
+            return false;
+        }
+
+        match self.domain_entries.get(qname) {
+            Some(entry) => {
+                entry.hits.get() >= self.config.prefetch_hits as u64
+                    && entry.is_expiring(qtype, self.config.prefetch_percent, Local::now())
+            }
+            None => false,
         }
     }
 
+    /// Look up records that have expired but are still within the stale
+    /// window. These are only to be used when a fresh answer can't be had.
+    pub fn lookup_stale(&self, qname: &str, qtype: QueryType) -> Option<DnsPacket> {
+        if self.config.stale_window == 0 {
+            return None;
+        }
+
+        let cutoff = Local::now() - Duration::seconds(self.config.stale_window as i64);
+
+        let mut qr = DnsPacket::new();
+
+        if let Some(nxdomain) = self.find_nxdomain(qname, cutoff) {
+            qr.header.rescode = ResultCode::NXDOMAIN;
+            qr.authorities
+                .extend(negative_soa(&nxdomain.soa, STALE_TTL));
+
+            return Some(qr);
+        }
+
+        let domain_entry = self.domain_entries.get(qname)?;
+
+        match *domain_entry.record_types.get(&qtype)? {
+            RecordSet::NoRecords {
+                ttl,
+                timestamp,
+                ref soa,
+                ..
+            } => {
+                if timestamp + Duration::seconds(ttl as i64) < cutoff {
+                    return None;
+                }
+
+                qr.authorities.extend(negative_soa(soa, STALE_TTL));
+            }
+            RecordSet::Records { ref records, .. } => {
+                for entry in records {
+                    let ttl_offset = Duration::seconds(entry.record.get_ttl() as i64);
+                    if entry.timestamp + ttl_offset < cutoff {
+                        continue;
+                    }
+
+                    let mut record = entry.record.clone();
+                    record.set_ttl(STALE_TTL);
+                    qr.answers.push(record);
+                }
+
+                if qr.answers.is_empty() {
+                    return None;
+                }
+            }
+        }
+
+        Some(qr)
+    }
+
     pub fn store(&mut self, records: &[DnsRecord]) {
+        self.store_with_trust(records, Trust::Answer);
+    }
+
+    pub fn store_with_trust(&mut self, records: &[DnsRecord], trust: Trust) {
+        let config = std::mem::take(&mut self.config);
+        self.store_with(records, &config, trust);
+        self.config = config;
+
+        self.evict();
+    }
+
+    /// Store records that are part of the server configuration, such as the
+    /// root hints, exempt from the TTL limits
+    pub fn store_hints(&mut self, records: &[DnsRecord]) {
+        self.store_unlimited(records, Trust::Glue);
+    }
+
+    /// Store the root name servers learned by priming, which take the place
+    /// of the root hints, and are exempt from the TTL limits just the same
+    pub fn store_priming(&mut self, records: &[DnsRecord]) {
+        self.store_unlimited(records, Trust::Answer);
+    }
+
+    fn store_unlimited(&mut self, records: &[DnsRecord], trust: Trust) {
+        let config = CacheConfig {
+            min_ttl: 0,
+            max_ttl: 0,
+            max_negative_ttl: 0,
+            ttl_overrides: Vec::new(),
+            ..CacheConfig::default()
+        };
+        self.store_with(records, &config, trust);
+
+        self.evict();
+    }
+
+    fn store_with(&mut self, records: &[DnsRecord], config: &CacheConfig, trust: Trust) {
         for rec in records {
             let domain = match rec.get_domain() {
                 Some(x) => x,
                 None => continue,
             };
 
-            if let Some(ref mut rs) = self.domain_entries.get_mut(&domain).and_then(Arc::get_mut) {
-                rs.store_record(rec);
-                continue;
-            }
-
-            let mut rs = DomainEntry::new(domain.clone());
-            rs.store_record(rec);
-            self.domain_entries.insert(domain.clone(), Arc::new(rs));
+            self.entry_mut(&domain).store_record(rec, config, trust);
         }
     }
 
-    pub fn store_nxdomain(&mut self, qname: &str, qtype: QueryType, ttl: u32) {
-        if let Some(ref mut rs) = self.domain_entries.get_mut(qname).and_then(Arc::get_mut) {
-            rs.store_nxdomain(qtype, ttl);
-            return;
-        }
+    /// Get an entry for updating, creating it if needed. Anyone else holding
+    /// on to the entry, such as the web interface, keeps the previous copy.
+    fn entry_mut(&mut self, qname: &str) -> &mut DomainEntry {
+        let entry = self
+            .domain_entries
+            .entry(qname.to_string())
+            .or_insert_with(|| Arc::new(DomainEntry::new(qname.to_string())));
+
+        let entry = Arc::make_mut(entry);
+        entry
+            .last_used
+            .set(self.tick.fetch_add(1, Ordering::Relaxed) + 1);
+
+        entry
+    }
+
+    /// Remember that `qname` has no records of type `qtype`
+    pub fn store_nodata(
+        &mut self,
+        qname: &str,
+        qtype: QueryType,
+        ttl: u32,
+        soa: Option<&DnsRecord>,
+    ) {
+        let config = std::mem::take(&mut self.config);
+        self.entry_mut(qname).store_nodata(qtype, ttl, soa, &config);
+        self.config = config;
+
+        self.evict();
+    }
+
+    /// Remember that `qname`, and everything below it, doesn't exist
+    pub fn store_nxdomain(&mut self, qname: &str, ttl: u32, soa: Option<&DnsRecord>) {
+        let config = std::mem::take(&mut self.config);
+        self.entry_mut(qname).store_nxdomain(ttl, soa, &config);
+        self.config = config;
 
-        let mut rs = DomainEntry::new(qname.to_string());
-        rs.store_nxdomain(qtype, ttl);
-        self.domain_entries.insert(qname.to_string(), Arc::new(rs));
+        self.evict();
     }
 }
 
 #[derive(Default)]
 pub struct SynchronizedCache {
     pub cache: RwLock<Cache>,
+    /// Entries currently being refreshed in the background
+    refreshing: Mutex<HashSet<(String, QueryType)>>,
+    /// Entries that failed to refresh, and when to try again
+    rechecks: Mutex<HashMap<(String, QueryType), DateTime<Local>>>,
 }
 
 impl SynchronizedCache {
     pub fn new() -> SynchronizedCache {
+        SynchronizedCache::with_config(CacheConfig::default())
+    }
+
+    pub fn with_config(config: CacheConfig) -> SynchronizedCache {
         SynchronizedCache {
-            cache: RwLock::new(Cache::new()),
+            cache: RwLock::new(Cache::with_config(config)),
+            refreshing: Mutex::new(HashSet::new()),
+            rechecks: Mutex::new(HashMap::new()),
         }
     }
 
@@ -291,8 +929,21 @@ impl SynchronizedCache {
         Ok(list)
     }
 
+    pub fn statistics(&self) -> Result<CacheStatistics> {
+        let cache = self.cache.read().map_err(|_| CacheError::PoisonedLock)?;
+
+        Ok(cache.statistics())
+    }
+
+    /// Get everything cached for a single name, without counting it as a hit
+    pub fn get(&self, qname: &str) -> Result<Option<Arc<DomainEntry>>> {
+        let cache = self.cache.read().map_err(|_| CacheError::PoisonedLock)?;
+
+        Ok(cache.domain_entries.get(qname).cloned())
+    }
+
     pub fn lookup(&self, qname: &str, qtype: QueryType) -> Option<DnsPacket> {
-        let mut cache = match self.cache.write() {
+        let cache = match self.cache.read() {
             Ok(x) => x,
             Err(_) => return None,
         };
@@ -300,6 +951,82 @@ impl SynchronizedCache {
         cache.lookup(qname, qtype)
     }
 
+    pub fn lookup_stale(&self, qname: &str, qtype: QueryType) -> Option<DnsPacket> {
+        let cache = match self.cache.read() {
+            Ok(x) => x,
+            Err(_) => return None,
+        };
+
+        cache.lookup_stale(qname, qtype)
+    }
+
+    pub fn needs_prefetch(&self, qname: &str, qtype: QueryType) -> bool {
+        match self.cache.read() {
+            Ok(cache) => cache.needs_prefetch(qname, qtype),
+            Err(_) => false,
+        }
+    }
+
+    /// Claim the refresh of an entry, returning false if another thread
+    /// is already refreshing it, or if the last refresh failed too recently
+    pub fn start_refresh(&self, qname: &str, qtype: QueryType) -> Result<bool> {
+        if self.is_rechecking(qname, qtype) {
+            return Ok(false);
+        }
+
+        let mut refreshing = self
+            .refreshing
+            .lock()
+            .map_err(|_| CacheError::PoisonedLock)?;
+
+        Ok(refreshing.insert((qname.to_string(), qtype)))
+    }
+
+    /// Release the refresh of an entry. When it failed, the entry isn't
+    /// refreshed again until the recheck delay has passed.
+    pub fn finish_refresh(&self, qname: &str, qtype: QueryType, failed: bool) -> Result<()> {
+        let key = (qname.to_string(), qtype);
+
+        {
+            let mut rechecks = self.rechecks.lock().map_err(|_| CacheError::PoisonedLock)?;
+
+            let delay = match self.cache.read() {
+                Ok(cache) => cache.config.stale_recheck,
+                Err(_) => 0,
+            };
+
+            let now = Local::now();
+            rechecks.retain(|_, until| *until > now);
+            if failed && delay > 0 {
+                rechecks.insert(key.clone(), now + Duration::seconds(delay as i64));
+            } else {
+                rechecks.remove(&key);
+            }
+        }
+
+        let mut refreshing = self
+            .refreshing
+            .lock()
+            .map_err(|_| CacheError::PoisonedLock)?;
+
+        refreshing.remove(&key);
+
+        Ok(())
+    }
+
+    /// Whether the last refresh of an entry failed within the recheck delay
+    pub fn is_rechecking(&self, qname: &str, qtype: QueryType) -> bool {
+        let rechecks = match self.rechecks.lock() {
+            Ok(x) => x,
+            Err(_) => return false,
+        };
+
+        match rechecks.get(&(qname.to_string(), qtype)) {
+            Some(until) => *until > Local::now(),
+            None => false,
+        }
+    }
+
     pub fn store(&self, records: &[DnsRecord]) -> Result<()> {
         let mut cache = self.cache.write().map_err(|_| CacheError::PoisonedLock)?;
 
@@ -308,13 +1035,93 @@ impl SynchronizedCache {
         Ok(())
     }
 
-    pub fn store_nxdomain(&self, qname: &str, qtype: QueryType, ttl: u32) -> Result<()> {
+    pub fn store_with_trust(&self, records: &[DnsRecord], trust: Trust) -> Result<()> {
+        let mut cache = self.cache.write().map_err(|_| CacheError::PoisonedLock)?;
+
+        cache.store_with_trust(records, trust);
+
+        Ok(())
+    }
+
+    pub fn store_hints(&self, records: &[DnsRecord]) -> Result<()> {
+        let mut cache = self.cache.write().map_err(|_| CacheError::PoisonedLock)?;
+
+        cache.store_hints(records);
+
+        Ok(())
+    }
+
+    pub fn store_priming(&self, records: &[DnsRecord]) -> Result<()> {
+        let mut cache = self.cache.write().map_err(|_| CacheError::PoisonedLock)?;
+
+        cache.store_priming(records);
+
+        Ok(())
+    }
+
+    pub fn store_nodata(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        ttl: u32,
+        soa: Option<&DnsRecord>,
+    ) -> Result<()> {
+        let mut cache = self.cache.write().map_err(|_| CacheError::PoisonedLock)?;
+
+        cache.store_nodata(qname, qtype, ttl, soa);
+
+        Ok(())
+    }
+
+    pub fn store_nxdomain(&self, qname: &str, ttl: u32, soa: Option<&DnsRecord>) -> Result<()> {
         let mut cache = self.cache.write().map_err(|_| CacheError::PoisonedLock)?;
 
-        cache.store_nxdomain(qname, qtype, ttl);
+        cache.store_nxdomain(qname, ttl, soa);
 
         Ok(())
     }
+
+    pub fn flush(&self, qname: &str, subtree: bool) -> Result<usize> {
+        let mut cache = self.cache.write().map_err(|_| CacheError::PoisonedLock)?;
+
+        Ok(cache.flush(qname, subtree))
+    }
+
+    pub fn sweep(&self) -> Result<usize> {
+        let mut cache = self.cache.write().map_err(|_| CacheError::PoisonedLock)?;
+
+        Ok(cache.sweep())
+    }
+
+    /// Write a snapshot of the cache to `path`, returning the number of
+    /// domains written. The snapshot is written to a temporary file which then
+    /// replaces the previous one, so that a crash can't leave a partial file.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<usize> {
+        let list = self.list()?;
+        let entries = list.iter().map(|x| x.as_ref()).collect::<Vec<_>>();
+
+        let path = path.as_ref();
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, serde_json::to_string(&entries)?)?;
+        fs::rename(&tmp_path, path)?;
+
+        Ok(entries.len())
+    }
+
+    /// Read back a snapshot written by `save`, if there is one
+    pub fn load<P: AsRef<Path>>(&self, path: P) -> Result<usize> {
+        let data = match fs::read_to_string(path) {
+            Ok(x) => x,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+
+        let entries = serde_json::from_str(&data)?;
+
+        let mut cache = self.cache.write().map_err(|_| CacheError::PoisonedLock)?;
+
+        Ok(cache.restore(entries))
+    }
 }
 
 #[cfg(test)]
@@ -334,7 +1141,7 @@ mod tests {
         }
 
         // Register a negative cache entry
-        cache.store_nxdomain("www.google.com", QueryType::A, 3600);
+        cache.store_nxdomain("www.google.com", 3600, None);
 
         // Verify that we get a response, with the NXDOMAIN flag set
         if let Some(packet) = cache.lookup("www.google.com", QueryType::A) {
@@ -342,7 +1149,7 @@ mod tests {
         }
 
         // Register a negative cache entry with no TTL
-        cache.store_nxdomain("www.yahoo.com", QueryType::A, 0);
+        cache.store_nxdomain("www.yahoo.com", 0, None);
 
         // And check that no such result is actually returned, since it's expired
         if cache.lookup("www.yahoo.com", QueryType::A).is_some() {
@@ -416,6 +1223,7 @@ mod tests {
                 .get(&"www.google.com".to_string())
                 .unwrap()
                 .hits
+                .get()
         );
         assert_eq!(
             2,
@@ -432,6 +1240,7 @@ mod tests {
                 .get(&"www.yahoo.com".to_string())
                 .unwrap()
                 .hits
+                .get()
         );
         assert_eq!(
             3,
@@ -456,6 +1265,484 @@ mod tests {
                 .get(&"www.microsoft.com".to_string())
                 .unwrap()
                 .hits
+                .get()
+        );
+    }
+
+    fn a_record(domain: &str, ttl: u32) -> DnsRecord {
+        DnsRecord::A {
+            domain: domain.to_string(),
+            addr: "127.0.0.1".parse().unwrap(),
+            ttl: TransientTtl(ttl),
+        }
+    }
+
+    #[test]
+    fn test_lru_eviction() {
+        let mut cache = Cache::with_config(CacheConfig {
+            max_entries: 4,
+            ..CacheConfig::default()
+        });
+
+        cache.store(&[
+            DnsRecord::NS {
+                domain: "".to_string(),
+                host: "a.root-servers.net".to_string(),
+                ttl: TransientTtl(3600),
+            },
+            a_record("a.root-servers.net", 3600),
+        ]);
+
+        cache.store(&[a_record("www.google.com", 3600)]);
+        cache.store(&[a_record("www.yahoo.com", 3600)]);
+
+        // Using google makes yahoo the least recently used entry, and since
+        // the root servers are never evicted it's the one to go
+        assert!(cache.lookup("www.google.com", QueryType::A).is_some());
+        cache.store(&[a_record("www.bing.com", 3600)]);
+
+        assert_eq!(4, cache.domain_entries.len());
+        assert_eq!(1, cache.statistics().evictions);
+        assert!(cache.lookup("www.yahoo.com", QueryType::A).is_none());
+        assert!(cache.lookup("www.google.com", QueryType::A).is_some());
+        assert!(cache.lookup("www.bing.com", QueryType::A).is_some());
+        assert!(cache.lookup("a.root-servers.net", QueryType::A).is_some());
+    }
+
+    #[test]
+    fn test_sweep() {
+        let mut cache = Cache::with_config(CacheConfig {
+            stale_window: 0,
+            ..CacheConfig::default()
+        });
+
+        cache.store(&[
+            a_record("www.google.com", 3600),
+            a_record("www.yahoo.com", 0),
+            DnsRecord::CNAME {
+                domain: "www.google.com".to_string(),
+                host: "google.com".to_string(),
+                ttl: TransientTtl(0),
+            },
+        ]);
+        cache.store_nxdomain("www.bing.com", 0, None);
+
+        std::thread::sleep(std::time::Duration::from_millis(10));
+
+        assert_eq!(2, cache.sweep());
+        assert_eq!(2, cache.statistics().expirations);
+
+        assert_eq!(1, cache.domain_entries.len());
+        assert_eq!(1, cache.domain_entries["www.google.com"].record_types.len());
+    }
+
+    fn backdate(cache: &mut Cache, qname: &str, qtype: QueryType, seconds: i64) {
+        let entry = Arc::get_mut(cache.domain_entries.get_mut(qname).unwrap()).unwrap();
+        if let Some(RecordSet::Records { records, .. }) = entry.record_types.get_mut(&qtype) {
+            *records = records
+                .drain()
+                .map(|mut x| {
+                    x.timestamp = x.timestamp - Duration::seconds(seconds);
+                    x
+                })
+                .collect();
+        }
+    }
+
+    #[test]
+    fn test_remaining_ttl() {
+        let mut cache = Cache::new();
+
+        cache.store(&[a_record("www.google.com", 3600)]);
+
+        // Pretend the record was stored 100 seconds ago
+        backdate(&mut cache, "www.google.com", QueryType::A, 100);
+
+        let packet = cache.lookup("www.google.com", QueryType::A).unwrap();
+        let ttl = packet.answers[0].get_ttl();
+        assert!(ttl <= 3500 && ttl >= 3498, "unexpected ttl {}", ttl);
+
+        // The stored record keeps its original ttl
+        let entry = &cache.domain_entries["www.google.com"];
+        if let Some(RecordSet::Records { records, .. }) = entry.record_types.get(&QueryType::A) {
+            assert!(records.iter().all(|x| x.record.get_ttl() == 3600));
+        }
+
+        let now = Local::now();
+        assert_eq!(0, remaining_ttl(10, now - Duration::seconds(20), now));
+        assert_eq!(10, remaining_ttl(10, now + Duration::seconds(20), now));
+    }
+
+    #[test]
+    fn test_lookup_stale() {
+        let mut cache = Cache::new();
+
+        cache.store(&[a_record("www.google.com", 0)]);
+        cache.store_nxdomain("www.yahoo.com", 0, None);
+
+        std::thread::sleep(std::time::Duration::from_millis(10));
+
+        // Expired entries are neither served nor swept, but are available as
+        // stale answers
+        assert!(cache.lookup("www.google.com", QueryType::A).is_none());
+        assert_eq!(0, cache.sweep());
+
+        let packet = cache.lookup_stale("www.google.com", QueryType::A).unwrap();
+        assert_eq!(1, packet.answers.len());
+        assert_eq!(STALE_TTL, packet.answers[0].get_ttl());
+
+        let packet = cache.lookup_stale("www.yahoo.com", QueryType::A).unwrap();
+        assert_eq!(ResultCode::NXDOMAIN, packet.header.rescode);
+
+        assert!(cache
+            .lookup_stale("www.google.com", QueryType::AAAA)
+            .is_none());
+        assert!(cache.lookup_stale("www.bing.com", QueryType::A).is_none());
+
+        let mut cache = Cache::with_config(CacheConfig {
+            stale_window: 0,
+            ..CacheConfig::default()
+        });
+        cache.store(&[a_record("www.google.com", 0)]);
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        assert!(cache.lookup_stale("www.google.com", QueryType::A).is_none());
+    }
+
+    #[test]
+    fn test_needs_prefetch() {
+        let mut cache = Cache::with_config(CacheConfig {
+            prefetch_hits: 3,
+            prefetch_percent: 10,
+            ..CacheConfig::default()
+        });
+
+        cache.store(&[a_record("www.google.com", 100)]);
+
+        for _ in 0..3 {
+            assert!(cache.lookup("www.google.com", QueryType::A).is_some());
+        }
+
+        // Popular, but with most of its TTL left
+        assert!(!cache.needs_prefetch("www.google.com", QueryType::A));
+
+        backdate(&mut cache, "www.google.com", QueryType::A, 95);
+        assert!(cache.needs_prefetch("www.google.com", QueryType::A));
+        assert!(!cache.needs_prefetch("www.google.com", QueryType::AAAA));
+
+        // About to expire, but not popular enough
+        cache.store(&[a_record("www.yahoo.com", 100)]);
+        assert!(cache.lookup("www.yahoo.com", QueryType::A).is_some());
+        backdate(&mut cache, "www.yahoo.com", QueryType::A, 95);
+        assert!(!cache.needs_prefetch("www.yahoo.com", QueryType::A));
+    }
+
+    #[test]
+    fn test_refresh_recheck() {
+        let cache = SynchronizedCache::new();
+
+        // Only one refresh of an entry at a time
+        assert!(cache.start_refresh("www.google.com", QueryType::A).unwrap());
+        assert!(!cache.start_refresh("www.google.com", QueryType::A).unwrap());
+        assert!(cache
+            .start_refresh("www.google.com", QueryType::AAAA)
+            .unwrap());
+
+        cache
+            .finish_refresh("www.google.com", QueryType::AAAA, false)
+            .unwrap();
+        assert!(!cache.is_rechecking("www.google.com", QueryType::AAAA));
+        assert!(cache
+            .start_refresh("www.google.com", QueryType::AAAA)
+            .unwrap());
+
+        // After a failure, it isn't tried again until the recheck delay is up
+        cache
+            .finish_refresh("www.google.com", QueryType::A, true)
+            .unwrap();
+        assert!(cache.is_rechecking("www.google.com", QueryType::A));
+        assert!(!cache.start_refresh("www.google.com", QueryType::A).unwrap());
+
+        let cache = SynchronizedCache::with_config(CacheConfig {
+            stale_recheck: 0,
+            ..CacheConfig::default()
+        });
+        assert!(cache.start_refresh("www.google.com", QueryType::A).unwrap());
+        cache
+            .finish_refresh("www.google.com", QueryType::A, true)
+            .unwrap();
+        assert!(!cache.is_rechecking("www.google.com", QueryType::A));
+        assert!(cache.start_refresh("www.google.com", QueryType::A).unwrap());
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let path = std::env::temp_dir().join(format!("hermes-cache-{}.json", std::process::id()));
+
+        let cache = SynchronizedCache::new();
+        cache
+            .store(&[
+                a_record("www.google.com", 3600),
+                a_record("www.yahoo.com", 0),
+            ])
+            .unwrap();
+        cache.store_nxdomain("www.bing.com", 3600, None).unwrap();
+
+        assert_eq!(3, cache.save(&path).unwrap());
+
+        std::thread::sleep(std::time::Duration::from_millis(10));
+
+        // The expired record is dropped, even though it's within the stale
+        // window
+        let restored = SynchronizedCache::new();
+        assert_eq!(2, restored.load(&path).unwrap());
+        assert!(restored.lookup("www.google.com", QueryType::A).is_some());
+        assert!(restored
+            .lookup_stale("www.yahoo.com", QueryType::A)
+            .is_none());
+
+        match restored.lookup("www.bing.com", QueryType::A) {
+            Some(packet) => assert_eq!(ResultCode::NXDOMAIN, packet.header.rescode),
+            None => panic!(),
+        }
+
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(0, restored.load(&path).unwrap());
+    }
+
+    #[test]
+    fn test_ttl_limits() {
+        let config = CacheConfig {
+            min_ttl: 60,
+            max_ttl: 86400,
+            max_negative_ttl: 300,
+            ttl_overrides: vec![
+                TtlOverride {
+                    suffix: "corp".to_string(),
+                    max_ttl: 600,
+                },
+                TtlOverride {
+                    suffix: "internal.corp".to_string(),
+                    max_ttl: 30,
+                },
+            ],
+            ..CacheConfig::default()
+        };
+
+        assert_eq!(60, config.ttl("www.google.com", 0));
+        assert_eq!(3600, config.ttl("www.google.com", 3600));
+        assert_eq!(86400, config.ttl("www.google.com", 604800));
+        assert_eq!(300, config.negative_ttl("www.google.com", 3600));
+        assert_eq!(600, config.ttl("www.corp", 3600));
+        assert_eq!(30, config.ttl("www.internal.corp", 3600));
+        assert_eq!(30, config.ttl("internal.corp", 0));
+        assert_eq!(30, config.negative_ttl("www.internal.corp", 3600));
+        assert_eq!(3600, config.ttl("www.notinternal.corp.com", 3600));
+
+        let mut cache = Cache::with_config(config);
+        cache.store(&[a_record("www.internal.corp", 3600)]);
+        cache.store_nxdomain("www.google.com", 3600, None);
+
+        let entry = &cache.domain_entries["www.internal.corp"];
+        match entry.record_types.get(&QueryType::A) {
+            Some(RecordSet::Records { records, .. }) => {
+                assert!(records.iter().all(|x| x.record.get_ttl() == 30))
+            }
+            _ => panic!(),
+        }
+
+        let entry = &cache.domain_entries["www.google.com"];
+        match entry.nxdomain {
+            Some(NxDomainEntry { ttl, .. }) => assert_eq!(300, ttl),
+            None => panic!(),
+        }
+
+        cache.store_hints(&[a_record("a.root-servers.net", 3600000)]);
+        let entry = &cache.domain_entries["a.root-servers.net"];
+        match entry.record_types.get(&QueryType::A) {
+            Some(RecordSet::Records { records, .. }) => {
+                assert!(records.iter().all(|x| x.record.get_ttl() == 3600000))
+            }
+            _ => panic!(),
+        }
+    }
+
+    fn soa_record(zone: &str, ttl: u32, minimum: u32) -> DnsRecord {
+        DnsRecord::SOA {
+            domain: zone.to_string(),
+            m_name: format!("ns1.{}", zone),
+            r_name: format!("hostmaster.{}", zone),
+            serial: 1,
+            refresh: 3600,
+            retry: 600,
+            expire: 86400,
+            minimum,
+            ttl: TransientTtl(ttl),
+        }
+    }
+
+    #[test]
+    fn test_negative_cache() {
+        let mut cache = Cache::new();
+
+        let soa = soa_record("google.com", 3600, 300);
+
+        // NODATA only applies to the type it was stored for
+        cache.store(&[a_record("www.google.com", 3600)]);
+        cache.store_nodata("www.google.com", QueryType::AAAA, 300, Some(&soa));
+
+        let packet = cache.lookup("www.google.com", QueryType::AAAA).unwrap();
+        assert_eq!(ResultCode::NOERROR, packet.header.rescode);
+        assert!(packet.answers.is_empty());
+        assert_eq!(1, packet.authorities.len());
+        assert!(packet.authorities[0].get_ttl() <= 300);
+
+        assert_eq!(
+            1,
+            cache
+                .lookup("www.google.com", QueryType::A)
+                .unwrap()
+                .answers
+                .len()
         );
+        assert!(cache.lookup("www.google.com", QueryType::MX).is_none());
+
+        // NXDOMAIN applies to all types, and to every name below it
+        cache.store_nxdomain("foo.google.com", 300, Some(&soa));
+
+        for (qname, qtype) in &[
+            ("foo.google.com", QueryType::A),
+            ("foo.google.com", QueryType::MX),
+            ("www.foo.google.com", QueryType::A),
+        ] {
+            let packet = cache.lookup(qname, *qtype).unwrap();
+            assert_eq!(ResultCode::NXDOMAIN, packet.header.rescode);
+            assert_eq!(Some(&soa), packet.authorities.first());
+        }
+
+        assert!(cache.lookup("bar.google.com", QueryType::A).is_none());
+
+        // Until the name turns out to exist after all
+        cache.store(&[a_record("foo.google.com", 3600)]);
+        let packet = cache.lookup("foo.google.com", QueryType::A).unwrap();
+        assert_eq!(ResultCode::NOERROR, packet.header.rescode);
+        assert_eq!(1, packet.answers.len());
+        assert!(cache.lookup("www.foo.google.com", QueryType::A).is_none());
+    }
+
+    #[test]
+    fn test_flush() {
+        let mut cache = Cache::new();
+
+        cache.store(&[
+            DnsRecord::NS {
+                domain: "".to_string(),
+                host: "a.root-servers.net".to_string(),
+                ttl: TransientTtl(3600),
+            },
+            a_record("a.root-servers.net", 3600),
+            a_record("google.com", 3600),
+            a_record("www.google.com", 3600),
+            a_record("mail.google.com", 3600),
+            a_record("notgoogle.com", 3600),
+        ]);
+
+        assert_eq!(1, cache.flush("www.google.com", false));
+        assert_eq!(0, cache.flush("www.google.com", false));
+        assert!(cache.lookup("google.com", QueryType::A).is_some());
+
+        assert_eq!(2, cache.flush("google.com", true));
+        assert!(cache.lookup("mail.google.com", QueryType::A).is_none());
+        assert!(cache.lookup("notgoogle.com", QueryType::A).is_some());
+
+        // Flushing everything leaves the root hints in place
+        assert_eq!(1, cache.flush("", true));
+        assert_eq!(2, cache.domain_entries.len());
+    }
+
+    #[test]
+    fn test_concurrent_access() {
+        let cache = SynchronizedCache::new();
+        cache.store(&[a_record("www.google.com", 3600)]).unwrap();
+
+        // Lookups only need to read the cache, so they can run while another
+        // thread is doing the same
+        {
+            let _reader = cache.cache.read().unwrap();
+            assert!(cache.lookup("www.google.com", QueryType::A).is_some());
+        }
+
+        // Updates aren't lost because someone else is holding on to the entry
+        let entry = cache.get("www.google.com").unwrap().unwrap();
+        cache
+            .store(&[DnsRecord::A {
+                domain: "www.google.com".to_string(),
+                addr: "127.0.0.2".parse().unwrap(),
+                ttl: TransientTtl(3600),
+            }])
+            .unwrap();
+        assert!(cache.lookup("www.google.com", QueryType::A).is_some());
+
+        let updated = cache.get("www.google.com").unwrap().unwrap();
+        assert_eq!(2, updated.updates);
+        assert_eq!(2, updated.hits.get());
+        assert_eq!(1, entry.updates);
+
+        let cache = Arc::new(cache);
+        let threads = (0..4)
+            .map(|_| {
+                let cache = cache.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..100 {
+                        assert!(cache.lookup("www.google.com", QueryType::A).is_some());
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let entry = cache.get("www.google.com").unwrap().unwrap();
+        assert_eq!(402, entry.hits.get());
+    }
+
+    #[test]
+    fn test_trust() {
+        let mut cache = Cache::new();
+
+        let glue = a_record("ns1.google.com", 3600);
+        let answer = DnsRecord::A {
+            domain: "ns1.google.com".to_string(),
+            addr: "127.0.0.2".parse().unwrap(),
+            ttl: TransientTtl(3600),
+        };
+
+        // Authoritative data replaces glue
+        cache.store_with_trust(&[glue.clone()], Trust::Glue);
+        cache.store_with_trust(&[answer.clone()], Trust::Answer);
+
+        let packet = cache.lookup("ns1.google.com", QueryType::A).unwrap();
+        assert_eq!(vec![answer.clone()], packet.answers);
+
+        // But glue never replaces authoritative data
+        cache.store_with_trust(&[glue.clone()], Trust::Glue);
+
+        let packet = cache.lookup("ns1.google.com", QueryType::A).unwrap();
+        assert_eq!(vec![answer.clone()], packet.answers);
+
+        // Unless the authoritative data has expired
+        cache.store_with_trust(
+            &[DnsRecord::A {
+                domain: "ns1.google.com".to_string(),
+                addr: "127.0.0.2".parse().unwrap(),
+                ttl: TransientTtl(0),
+            }],
+            Trust::Answer,
+        );
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        cache.store_with_trust(&[glue.clone()], Trust::Glue);
+
+        let packet = cache.lookup("ns1.google.com", QueryType::A).unwrap();
+        assert_eq!(vec![glue], packet.answers);
     }
 }
@@ -2,7 +2,7 @@
 
 use std::io::Write;
 use std::marker::{Send, Sync};
-use std::net::{TcpStream, UdpSocket};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc::{channel, Sender};
 use std::sync::{Arc, Mutex};
@@ -23,6 +23,7 @@ pub enum ClientError {
     PoisonedLock,
     LookupFailed,
     TimeOut,
+    Ipv6Unavailable,
 }
 
 type Result<T> = std::result::Result<T, ClientError>;
@@ -32,6 +33,11 @@ pub trait DnsClient {
     fn get_failed_count(&self) -> usize;
 
     fn run(&self) -> Result<()>;
+
+    /// Set how long to wait for a response before giving up on a query. This
+    /// has to be done before the client is started.
+    fn set_timeout(&mut self, _timeout: SleepDuration) {}
+
     fn send_query(
         &self,
         qname: &str,
@@ -58,8 +64,15 @@ pub struct DnsNetworkClient {
     /// The listener socket
     socket: UdpSocket,
 
+    /// The listener socket for servers reached over IPv6, if the host
+    /// supports it
+    socket_v6: Option<UdpSocket>,
+
     /// Queries in progress
     pending_queries: Arc<Mutex<Vec<PendingQuery>>>,
+
+    /// How long to wait for a response
+    timeout: SleepDuration,
 }
 
 /// A query in progress. This struct holds the `id` if the request, and a channel
@@ -81,7 +94,11 @@ impl DnsNetworkClient {
             total_failed: AtomicUsize::new(0),
             seq: AtomicUsize::new(0),
             socket: UdpSocket::bind(("0.0.0.0", port)).unwrap(),
+            // On most systems an IPv6 socket claims the IPv4 port as well, so
+            // this one is left to pick a port of its own
+            socket_v6: UdpSocket::bind(("::", 0)).ok(),
             pending_queries: Arc::new(Mutex::new(Vec::new())),
+            timeout: SleepDuration::from_secs(1),
         }
     }
 
@@ -115,7 +132,13 @@ impl DnsNetworkClient {
         let mut req_buffer = BytePacketBuffer::new();
         packet.write(&mut req_buffer, 0xFFFF)?;
 
-        let mut socket = TcpStream::connect(server)?;
+        let server_addr = server
+            .to_socket_addrs()?
+            .next()
+            .ok_or(ClientError::LookupFailed)?;
+        let mut socket = TcpStream::connect_timeout(&server_addr, self.timeout)?;
+        socket.set_read_timeout(Some(self.timeout))?;
+        socket.set_write_timeout(Some(self.timeout))?;
 
         write_packet_length(&mut socket, req_buffer.pos())?;
         socket.write(&req_buffer.buf[0..req_buffer.pos])?;
@@ -160,6 +183,19 @@ impl DnsNetworkClient {
             .questions
             .push(DnsQuestion::new(qname.to_string(), qtype));
 
+        // Pick the socket matching the address family of the server
+        let server_addr = server
+            .to_socket_addrs()?
+            .next()
+            .ok_or(ClientError::LookupFailed)?;
+        let socket = match server_addr {
+            SocketAddr::V4(_) => &self.socket,
+            SocketAddr::V6(_) => self
+                .socket_v6
+                .as_ref()
+                .ok_or(ClientError::Ipv6Unavailable)?,
+        };
+
         // Create a return channel, and add a `PendingQuery` to the list of lookups
         // in progress
         let (tx, rx) = channel();
@@ -178,8 +214,7 @@ impl DnsNetworkClient {
         // Send query
         let mut req_buffer = BytePacketBuffer::new();
         packet.write(&mut req_buffer, 512)?;
-        self.socket
-            .send_to(&req_buffer.buf[0..req_buffer.pos], server)?;
+        socket.send_to(&req_buffer.buf[0..req_buffer.pos], server_addr)?;
 
         // Wait for response
         match rx.recv() {
@@ -196,6 +231,65 @@ impl DnsNetworkClient {
     }
 }
 
+/// Launch a thread reading responses from `socket`, and handing each of them
+/// to the matching query in `pending_queries`
+fn spawn_worker(
+    name: &str,
+    socket: UdpSocket,
+    pending_queries_lock: Arc<Mutex<Vec<PendingQuery>>>,
+) -> Result<()> {
+    Builder::new().name(name.into()).spawn(move || {
+        loop {
+            // Read data into a buffer
+            let mut res_buffer = BytePacketBuffer::new();
+            match socket.recv_from(&mut res_buffer.buf) {
+                Ok(_) => {}
+                Err(_) => {
+                    continue;
+                }
+            }
+
+            // Construct a DnsPacket from buffer, skipping the packet if parsing
+            // failed
+            let packet = match DnsPacket::from_buffer(&mut res_buffer) {
+                Ok(packet) => packet,
+                Err(err) => {
+                    println!(
+                        "DnsNetworkClient failed to parse packet with error: {}",
+                        err
+                    );
+                    continue;
+                }
+            };
+
+            // Acquire a lock on the pending_queries list, and search for a
+            // matching PendingQuery to which to deliver the response.
+            if let Ok(mut pending_queries) = pending_queries_lock.lock() {
+                let mut matched_query = None;
+                for (i, pending_query) in pending_queries.iter().enumerate() {
+                    if pending_query.seq == packet.header.id {
+                        // Matching query found, send the response
+                        let _ = pending_query.tx.send(Some(packet.clone()));
+
+                        // Mark this index for removal from list
+                        matched_query = Some(i);
+
+                        break;
+                    }
+                }
+
+                if let Some(idx) = matched_query {
+                    pending_queries.remove(idx);
+                } else {
+                    println!("Discarding response for: {:?}", packet.questions[0]);
+                }
+            }
+        }
+    })?;
+
+    Ok(())
+}
+
 impl DnsClient for DnsNetworkClient {
     fn get_sent_count(&self) -> usize {
         self.total_sent.load(Ordering::Acquire)
@@ -205,74 +299,36 @@ impl DnsClient for DnsNetworkClient {
         self.total_failed.load(Ordering::Acquire)
     }
 
+    fn set_timeout(&mut self, timeout: SleepDuration) {
+        self.timeout = timeout;
+    }
+
     /// The run method launches a worker thread. Unless this thread is running, no
     /// responses will ever be generated, and clients will just block indefinitely.
     fn run(&self) -> Result<()> {
-        // Start the thread for handling incoming responses
-        {
-            let socket_copy = self.socket.try_clone()?;
-            let pending_queries_lock = self.pending_queries.clone();
-
-            Builder::new()
-                .name("DnsNetworkClient-worker-thread".into())
-                .spawn(move || {
-                    loop {
-                        // Read data into a buffer
-                        let mut res_buffer = BytePacketBuffer::new();
-                        match socket_copy.recv_from(&mut res_buffer.buf) {
-                            Ok(_) => {}
-                            Err(_) => {
-                                continue;
-                            }
-                        }
-
-                        // Construct a DnsPacket from buffer, skipping the packet if parsing
-                        // failed
-                        let packet = match DnsPacket::from_buffer(&mut res_buffer) {
-                            Ok(packet) => packet,
-                            Err(err) => {
-                                println!(
-                                    "DnsNetworkClient failed to parse packet with error: {}",
-                                    err
-                                );
-                                continue;
-                            }
-                        };
-
-                        // Acquire a lock on the pending_queries list, and search for a
-                        // matching PendingQuery to which to deliver the response.
-                        if let Ok(mut pending_queries) = pending_queries_lock.lock() {
-                            let mut matched_query = None;
-                            for (i, pending_query) in pending_queries.iter().enumerate() {
-                                if pending_query.seq == packet.header.id {
-                                    // Matching query found, send the response
-                                    let _ = pending_query.tx.send(Some(packet.clone()));
-
-                                    // Mark this index for removal from list
-                                    matched_query = Some(i);
-
-                                    break;
-                                }
-                            }
-
-                            if let Some(idx) = matched_query {
-                                pending_queries.remove(idx);
-                            } else {
-                                println!("Discarding response for: {:?}", packet.questions[0]);
-                            }
-                        }
-                    }
-                })?;
+        // Start the threads for handling incoming responses
+        spawn_worker(
+            "DnsNetworkClient-worker-thread",
+            self.socket.try_clone()?,
+            self.pending_queries.clone(),
+        )?;
+
+        if let Some(ref socket_v6) = self.socket_v6 {
+            spawn_worker(
+                "DnsNetworkClient-worker-thread-v6",
+                socket_v6.try_clone()?,
+                self.pending_queries.clone(),
+            )?;
         }
 
         // Start the thread for timing out requests
         {
             let pending_queries_lock = self.pending_queries.clone();
+            let timeout = Duration::from_std(self.timeout).unwrap_or_else(|_| Duration::seconds(1));
 
             Builder::new()
                 .name("DnsNetworkClient-timeout-thread".into())
                 .spawn(move || {
-                    let timeout = Duration::seconds(1);
                     loop {
                         if let Ok(mut pending_queries) = pending_queries_lock.lock() {
                             let mut finished_queries = Vec::new();
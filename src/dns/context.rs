@@ -1,31 +1,93 @@
 //! The `ServerContext in this thread holds the common state across the server
 
+use std::collections::HashMap;
 use std::fs;
+use std::net::IpAddr;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::thread::{sleep, Builder};
+use std::time::Duration;
 
 use derive_more::{Display, Error, From};
 
 use crate::dns::authority::Authority;
 use crate::dns::cache::SynchronizedCache;
 use crate::dns::client::{DnsClient, DnsNetworkClient};
-use crate::dns::resolve::{DnsResolver, ForwardingDnsResolver, RecursiveDnsResolver};
+use crate::dns::forward::{ForwardOrder, Forwarder, ForwarderHealth};
+use crate::dns::hints::prime;
+use crate::dns::https::{DnsHttpsClient, HttpsMethod};
+use crate::dns::inflight::InflightQueries;
+use crate::dns::infra::InfraCache;
+use crate::dns::pool::WorkerPool;
+use crate::dns::protocol::{DnsRecord, QueryType, ResultCode};
+use crate::dns::resolve::{
+    send_to_forwarder, DnsResolver, ForwardingDnsResolver, RecursiveDnsResolver,
+};
+use crate::dns::route::Router;
+use crate::dns::tls::DnsTlsClient;
 
 #[derive(Debug, Display, From, Error)]
 pub enum ContextError {
     Authority(crate::dns::authority::AuthorityError),
+    Cache(crate::dns::cache::CacheError),
     Client(crate::dns::client::ClientError),
+    Tls(crate::dns::tls::TlsError),
+    Https(crate::dns::https::HttpsError),
     Io(std::io::Error),
 }
 
 type Result<T> = std::result::Result<T, ContextError>;
 
+/// Cache lookups for a single type of record
+#[derive(Default)]
+pub struct TypeStatistics {
+    pub cache_hits: AtomicUsize,
+    pub cache_negative_hits: AtomicUsize,
+    pub cache_misses: AtomicUsize,
+}
+
+/// The types of record that are counted separately
+const COUNTED_TYPES: [QueryType; 9] = [
+    QueryType::A,
+    QueryType::NS,
+    QueryType::CNAME,
+    QueryType::SOA,
+    QueryType::MX,
+    QueryType::TXT,
+    QueryType::AAAA,
+    QueryType::SRV,
+    QueryType::OPT,
+];
+
 pub struct ServerStatistics {
     pub tcp_query_count: AtomicUsize,
     pub udp_query_count: AtomicUsize,
+    /// Cache lookups by type. Every known type is added up front, so that
+    /// counting doesn't require a lock.
+    pub types: HashMap<QueryType, TypeStatistics>,
+    /// Responses sent to clients, indexed by result code
+    pub responses: [AtomicUsize; 6],
+}
+
+impl Default for ServerStatistics {
+    fn default() -> Self {
+        ServerStatistics::new()
+    }
 }
 
 impl ServerStatistics {
+    pub fn new() -> ServerStatistics {
+        ServerStatistics {
+            tcp_query_count: AtomicUsize::new(0),
+            udp_query_count: AtomicUsize::new(0),
+            types: COUNTED_TYPES
+                .iter()
+                .map(|qtype| (*qtype, TypeStatistics::default()))
+                .collect(),
+            responses: Default::default(),
+        }
+    }
+
     pub fn get_tcp_query_count(&self) -> usize {
         self.tcp_query_count.load(Ordering::Acquire)
     }
@@ -33,26 +95,163 @@ impl ServerStatistics {
     pub fn get_udp_query_count(&self) -> usize {
         self.udp_query_count.load(Ordering::Acquire)
     }
+
+    /// Count an answer served from the cache, which is negative if it says
+    /// that the name or type doesn't exist
+    pub fn record_cache_hit(&self, qtype: QueryType, negative: bool) {
+        if let Some(stats) = self.types.get(&qtype) {
+            let counter = if negative {
+                &stats.cache_negative_hits
+            } else {
+                &stats.cache_hits
+            };
+            counter.fetch_add(1, Ordering::Release);
+        }
+    }
+
+    pub fn record_cache_miss(&self, qtype: QueryType) {
+        if let Some(stats) = self.types.get(&qtype) {
+            stats.cache_misses.fetch_add(1, Ordering::Release);
+        }
+    }
+
+    pub fn record_response(&self, rescode: ResultCode) {
+        self.responses[rescode as usize].fetch_add(1, Ordering::Release);
+    }
+
+    pub fn get_cache_hit_count(&self) -> usize {
+        self.types
+            .values()
+            .map(|x| x.cache_hits.load(Ordering::Acquire))
+            .sum()
+    }
+
+    pub fn get_cache_negative_hit_count(&self) -> usize {
+        self.types
+            .values()
+            .map(|x| x.cache_negative_hits.load(Ordering::Acquire))
+            .sum()
+    }
+
+    pub fn get_cache_miss_count(&self) -> usize {
+        self.types
+            .values()
+            .map(|x| x.cache_misses.load(Ordering::Acquire))
+            .sum()
+    }
+
+    pub fn get_response_count(&self, rescode: ResultCode) -> usize {
+        self.responses[rescode as usize].load(Ordering::Acquire)
+    }
 }
 
 pub enum ResolveStrategy {
     Recursive,
-    Forward { host: String, port: u16 },
+    Forward {
+        servers: Vec<Forwarder>,
+        order: ForwardOrder,
+    },
+}
+
+/// The kinds of address that other name servers are contacted on
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum IpPreference {
+    V4Only,
+    V6Only,
+    DualStack,
+}
+
+impl IpPreference {
+    pub fn allows(&self, addr: &IpAddr) -> bool {
+        match *self {
+            IpPreference::V4Only => addr.is_ipv4(),
+            IpPreference::V6Only => addr.is_ipv6(),
+            IpPreference::DualStack => true,
+        }
+    }
+
+    /// The record types to look up the address of a name server with, in the
+    /// order they're tried
+    pub fn address_types(&self) -> &'static [QueryType] {
+        match *self {
+            IpPreference::V4Only => &[QueryType::A],
+            IpPreference::V6Only => &[QueryType::AAAA],
+            IpPreference::DualStack => &[QueryType::A, QueryType::AAAA],
+        }
+    }
 }
 
 pub struct ServerContext {
     pub authority: Authority,
     pub cache: SynchronizedCache,
+    /// Measurements of how quickly other name servers respond
+    pub infra: InfraCache,
+    /// Queries to other name servers awaiting a response, which identical
+    /// queries wait for rather than being sent as well
+    pub inflight: InflightQueries,
+    /// Threads refreshing cache entries in the background
+    pub refresh_pool: WorkerPool,
+    /// Which of the forwarders are answering, when forwarding
+    pub forwarders: ForwarderHealth,
+    /// Rules for resolving some domains differently from everything else
+    pub routes: Router,
     pub client: Box<dyn DnsClient + Sync + Send>,
+    /// Client for the forwarders reached over TLS
+    pub tls_client: DnsTlsClient,
+    /// Client for the forwarders reached over HTTPS
+    pub https_client: DnsHttpsClient,
+    /// Whether queries are sent to forwarders over HTTPS as POST or GET
+    /// requests
+    pub https_method: HttpsMethod,
+    /// Certificate authorities to trust for forwarders reached over TLS or
+    /// HTTPS, instead of the usual ones of the web
+    pub tls_ca_file: Option<String>,
+    /// Digests of the public keys that forwarders reached over TLS or HTTPS
+    /// may present, in place of checking their certificates (RFC 7858)
+    pub tls_pins: Vec<String>,
     pub dns_port: u16,
     pub api_port: u16,
     pub resolve_strategy: ResolveStrategy,
+    /// Which name server addresses to use during recursive resolution
+    pub ip_preference: IpPreference,
+    /// The root name servers that recursive resolution starts out from until
+    /// priming has found the current ones, or nothing when forwarding
+    pub root_hints: Vec<DnsRecord>,
+    /// Seconds between attempts at priming when it fails, and ahead of the
+    /// expiry of the primed root name servers that it's repeated
+    pub priming_retry_interval: u64,
+    /// Milliseconds to wait for each query to another name server
+    pub query_timeout: u64,
+    /// Milliseconds to spend on resolving a name, across every name server
+    /// tried along the way
+    pub resolve_timeout: u64,
+    /// Milliseconds to wait for a name to be resolved when expired records
+    /// for it could be answered with instead, after which they are, while
+    /// resolving carries on in the background (RFC 8767)
+    pub stale_answer_timeout: u64,
+    /// Whether to only reveal as much of a name to each name server as it
+    /// needs to refer us further (RFC 9156)
+    pub qname_minimisation: bool,
+    /// The most referrals to follow when resolving a name
+    pub max_referrals: usize,
+    /// The most name servers without glue to look up within one another
+    pub max_nested_lookups: usize,
+    /// The most queries to send to other name servers when resolving a name
+    pub max_upstream_queries: usize,
     pub allow_recursive: bool,
     pub enable_udp: bool,
     pub enable_tcp: bool,
     pub enable_api: bool,
     pub statistics: ServerStatistics,
-    pub zones_dir: &'static str
+    pub zones_dir: &'static str,
+    /// Seconds between each sweep of expired entries from the cache
+    pub cache_sweep_interval: u64,
+    /// Where to keep a snapshot of the cache between restarts, if anywhere
+    pub cache_file: Option<String>,
+    /// Seconds between each snapshot of the cache
+    pub cache_snapshot_interval: u64,
+    /// Seconds between each probe of the forwarders that are down
+    pub forwarder_probe_interval: u64,
 }
 
 impl Default for ServerContext {
@@ -66,19 +265,40 @@ impl ServerContext {
         ServerContext {
             authority: Authority::new(),
             cache: SynchronizedCache::new(),
+            infra: InfraCache::new(),
+            inflight: InflightQueries::new(),
+            refresh_pool: WorkerPool::new("DnsResolver-refresh", 4, 1000),
+            forwarders: ForwarderHealth::new(),
+            routes: Router::new(),
             client: Box::new(DnsNetworkClient::new(34255)),
+            tls_client: DnsTlsClient::new(),
+            https_client: DnsHttpsClient::new(),
+            https_method: HttpsMethod::Post,
+            tls_ca_file: None,
+            tls_pins: Vec::new(),
             dns_port: 53,
             api_port: 5380,
             resolve_strategy: ResolveStrategy::Recursive,
+            ip_preference: IpPreference::V4Only,
+            root_hints: Vec::new(),
+            priming_retry_interval: 60,
+            query_timeout: 1000,
+            resolve_timeout: 10000,
+            stale_answer_timeout: 1800,
+            qname_minimisation: true,
+            max_referrals: 20,
+            max_nested_lookups: 5,
+            max_upstream_queries: 64,
             allow_recursive: true,
             enable_udp: true,
             enable_tcp: true,
             enable_api: true,
-            statistics: ServerStatistics {
-                tcp_query_count: AtomicUsize::new(0),
-                udp_query_count: AtomicUsize::new(0),
-            },
+            statistics: ServerStatistics::new(),
             zones_dir: "zones",
+            cache_sweep_interval: 60,
+            cache_file: None,
+            cache_snapshot_interval: 300,
+            forwarder_probe_interval: 30,
         }
     }
 
@@ -87,19 +307,179 @@ impl ServerContext {
         fs::create_dir_all(self.zones_dir)?;
 
         // Start UDP client thread
+        self.client
+            .set_timeout(Duration::from_millis(self.query_timeout));
         self.client.run()?;
 
+        self.tls_client = DnsTlsClient::with_settings(
+            self.tls_ca_file.as_deref(),
+            &self.tls_pins,
+            Duration::from_millis(self.query_timeout),
+        )?;
+        self.https_client = DnsHttpsClient::with_settings(
+            self.tls_ca_file.as_deref(),
+            &self.tls_pins,
+            self.https_method,
+            Duration::from_millis(self.query_timeout),
+        )?;
+
         // Load authority data
         self.authority.load()?;
 
+        if !self.root_hints.is_empty() {
+            self.cache.store_hints(&self.root_hints)?;
+        }
+
+        self.restore_cache();
+
         Ok(())
     }
 
+    /// Warm up the cache from the last snapshot. A snapshot that can't be
+    /// read is moved out of the way rather than keeping the server from
+    /// starting, since an empty cache does just as well.
+    pub fn restore_cache(&self) {
+        let cache_file = match self.cache_file {
+            Some(ref x) => x,
+            None => return,
+        };
+
+        match self.cache.load(cache_file) {
+            Ok(count) => println!("Restored {} domains from {}", count, cache_file),
+            Err(e) => {
+                println!("Failed to restore the cache from {}: {:?}", cache_file, e);
+
+                let corrupt_file = format!("{}.corrupt", cache_file);
+                match fs::rename(cache_file, &corrupt_file) {
+                    Ok(_) => println!("Moved {} to {}", cache_file, corrupt_file),
+                    Err(e) => println!("Failed to move {}: {:?}", cache_file, e),
+                }
+            }
+        }
+    }
+
+    /// Launch the threads performing periodic maintenance, such as removing
+    /// expired entries from the cache and writing it to disk
+    pub fn run_background_tasks(context: Arc<ServerContext>) -> Result<()> {
+        let sweep_context = context.clone();
+        Builder::new()
+            .name("ServerContext-cache-sweeper".into())
+            .spawn(move || loop {
+                sleep(Duration::from_secs(sweep_context.cache_sweep_interval));
+
+                match sweep_context.cache.sweep() {
+                    Ok(0) => {}
+                    Ok(count) => println!("Swept {} expired records from the cache", count),
+                    Err(e) => println!("Failed to sweep cache: {:?}", e),
+                }
+            })?;
+
+        let probe_context = context.clone();
+        Builder::new()
+            .name("ServerContext-forwarder-probe".into())
+            .spawn(move || loop {
+                sleep(Duration::from_secs(probe_context.forwarder_probe_interval));
+
+                // Ask the forwarders that are down for the root servers, to
+                // find out when they're back
+                for forwarder in probe_context
+                    .forwarders
+                    .down(&probe_context.all_forwarders())
+                {
+                    match send_to_forwarder(&probe_context, &forwarder, "", QueryType::NS) {
+                        Ok(_) => probe_context.forwarders.record_success(forwarder.addr),
+                        Err(_) => probe_context.forwarders.record_failure(forwarder.addr),
+                    }
+                }
+            })?;
+
+        if !context.root_hints.is_empty() {
+            let priming_context = context.clone();
+            Builder::new()
+                .name("ServerContext-root-priming".into())
+                .spawn(move || loop {
+                    let retry = priming_context.priming_retry_interval;
+
+                    // Leave the root name servers alone while forwarding,
+                    // until a route to the recursive resolver is added
+                    if !priming_context.uses_recursion() {
+                        sleep(Duration::from_secs(retry));
+                        continue;
+                    }
+
+                    let wait = match prime(&priming_context) {
+                        Ok(ttl) => (ttl as u64).saturating_sub(retry).max(retry),
+                        Err(e) => {
+                            println!("Failed to prime the root name servers: {:?}", e);
+
+                            // Should the primed servers expire before the
+                            // next attempt succeeds, there are still the hints
+                            let _ = priming_context
+                                .cache
+                                .store_hints(&priming_context.root_hints);
+
+                            retry
+                        }
+                    };
+
+                    sleep(Duration::from_secs(wait));
+                })?;
+        }
+
+        if context.cache_file.is_some() {
+            Builder::new()
+                .name("ServerContext-cache-snapshot".into())
+                .spawn(move || loop {
+                    sleep(Duration::from_secs(context.cache_snapshot_interval));
+
+                    if let Some(ref cache_file) = context.cache_file {
+                        if let Err(e) = context.cache.save(cache_file) {
+                            println!("Failed to write cache snapshot: {:?}", e);
+                        }
+                    }
+                })?;
+        }
+
+        Ok(())
+    }
+
+    /// Whether names can be resolved from the root servers, which takes the
+    /// root hints to start out from
+    pub fn recursion_available(&self) -> bool {
+        self.allow_recursive && !self.root_hints.is_empty()
+    }
+
+    /// Whether any names are currently resolved from the root servers, either
+    /// all of them or those of a route
+    pub fn uses_recursion(&self) -> bool {
+        match self.resolve_strategy {
+            ResolveStrategy::Recursive => true,
+            ResolveStrategy::Forward { .. } => self.routes.has_recursive(),
+        }
+    }
+
+    /// Every server queries are forwarded to, whether for all domains or
+    /// only for those with a routing rule
+    pub fn all_forwarders(&self) -> Vec<Forwarder> {
+        let mut servers = match self.resolve_strategy {
+            ResolveStrategy::Forward { ref servers, .. } => servers.clone(),
+            ResolveStrategy::Recursive => Vec::new(),
+        };
+
+        for forwarder in self.routes.forwarders() {
+            if !servers.contains(&forwarder) {
+                servers.push(forwarder);
+            }
+        }
+
+        servers
+    }
+
     pub fn create_resolver(&self, ptr: Arc<ServerContext>) -> Box<dyn DnsResolver> {
         match self.resolve_strategy {
             ResolveStrategy::Recursive => Box::new(RecursiveDnsResolver::new(ptr)),
-            ResolveStrategy::Forward { ref host, port } => {
-                Box::new(ForwardingDnsResolver::new(ptr, (host.clone(), port)))
+            ResolveStrategy::Forward { ref servers, order } => {
+                Box::new(ForwardingDnsResolver::new(ptr, servers.clone(), order))
             }
         }
     }
@@ -108,7 +488,6 @@ impl ServerContext {
 #[cfg(test)]
 pub mod tests {
 
-    use std::sync::atomic::AtomicUsize;
     use std::sync::Arc;
 
     use crate::dns::authority::Authority;
@@ -122,19 +501,66 @@ pub mod tests {
         Arc::new(ServerContext {
             authority: Authority::new(),
             cache: SynchronizedCache::new(),
+            infra: InfraCache::new(),
+            inflight: InflightQueries::new(),
+            refresh_pool: WorkerPool::new("DnsResolver-refresh", 4, 1000),
+            forwarders: ForwarderHealth::new(),
+            routes: Router::new(),
             client: Box::new(DnsStubClient::new(callback)),
+            tls_client: DnsTlsClient::new(),
+            https_client: DnsHttpsClient::new(),
+            https_method: HttpsMethod::Post,
+            tls_ca_file: None,
+            tls_pins: Vec::new(),
             dns_port: 53,
             api_port: 5380,
             resolve_strategy: ResolveStrategy::Recursive,
+            ip_preference: IpPreference::V4Only,
+            root_hints: Vec::new(),
+            priming_retry_interval: 60,
+            query_timeout: 1000,
+            resolve_timeout: 10000,
+            stale_answer_timeout: 1800,
+            qname_minimisation: true,
+            max_referrals: 20,
+            max_nested_lookups: 5,
+            max_upstream_queries: 64,
             allow_recursive: true,
             enable_udp: true,
             enable_tcp: true,
             enable_api: true,
-            statistics: ServerStatistics {
-                tcp_query_count: AtomicUsize::new(0),
-                udp_query_count: AtomicUsize::new(0),
-            },
+            statistics: ServerStatistics::new(),
             zones_dir: "zones",
+            cache_sweep_interval: 60,
+            cache_file: None,
+            cache_snapshot_interval: 300,
+            forwarder_probe_interval: 30,
         })
     }
+
+    #[test]
+    fn test_restore_corrupt_cache() {
+        let path = std::env::temp_dir().join(format!("hermes-corrupt-{}.json", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let corrupt_path = format!("{}.corrupt", path);
+        fs::write(&path, "[{\"domain\": \"www.google.com\", \"reco").unwrap();
+
+        let mut context = create_test_context(Box::new(|_, _, _, _| {
+            Err(crate::dns::client::ClientError::TimeOut)
+        }));
+
+        match Arc::get_mut(&mut context) {
+            Some(ctx) => ctx.cache_file = Some(path.clone()),
+            None => panic!(),
+        }
+
+        // The server starts out with an empty cache instead, and the snapshot
+        // is kept aside for inspection
+        context.restore_cache();
+        assert_eq!(0, context.cache.statistics().unwrap().domains);
+        assert!(!std::path::Path::new(&path).exists());
+        assert!(std::path::Path::new(&corrupt_path).exists());
+
+        fs::remove_file(&corrupt_path).unwrap();
+    }
 }
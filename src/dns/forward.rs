@@ -0,0 +1,397 @@
+//! tracking of which upstream servers are answering, when running as a
+//! forwarder with more than one of them
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::RwLock;
+
+use chrono::*;
+use derive_more::{Display, Error, From};
+use rand::seq::SliceRandom;
+use rand::thread_rng;
+use serde_derive::{Deserialize, Serialize};
+
+#[derive(Debug, Display, From, Error)]
+pub enum ForwardError {
+    PoisonedLock,
+}
+
+type Result<T> = std::result::Result<T, ForwardError>;
+
+/// Failures in a row after which a forwarder is considered down, and is only
+/// probed in the background until it answers again
+pub const MAX_CONSECUTIVE_FAILURES: u32 = 3;
+
+/// How the forwarders are tried
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ForwardOrder {
+    /// In the order they were configured, falling back to the next one when a
+    /// forwarder fails
+    Ordered,
+    /// In a different random order for each query, spreading the load
+    Random,
+}
+
+/// How queries reach a forwarder
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Transport {
+    /// Over UDP, or TCP when the response doesn't fit
+    Plain,
+    /// Over TLS (RFC 7858), checking that the certificate is for
+    /// `server_name`, or for the address of the forwarder without one
+    Tls { server_name: Option<String> },
+    /// Over HTTPS (RFC 8484), to the DNS API at `path`, checking the
+    /// certificate the same way as over TLS
+    Https {
+        path: String,
+        server_name: Option<String>,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Forwarder {
+    pub addr: SocketAddr,
+    pub transport: Transport,
+}
+
+impl Forwarder {
+    pub fn plain(addr: SocketAddr) -> Forwarder {
+        Forwarder {
+            addr,
+            transport: Transport::Plain,
+        }
+    }
+}
+
+impl fmt::Display for Forwarder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.transport {
+            Transport::Plain => write!(f, "{}", self.addr),
+            Transport::Tls {
+                server_name: Some(ref name),
+            } => write!(f, "tls://{}#{}", self.addr, name),
+            Transport::Tls { server_name: None } => write!(f, "tls://{}", self.addr),
+            Transport::Https {
+                ref path,
+                server_name: Some(ref name),
+            } => write!(f, "https://{}{}#{}", self.addr, path, name),
+            Transport::Https {
+                ref path,
+                server_name: None,
+            } => write!(f, "https://{}{}", self.addr, path),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ForwarderStatus {
+    pub addr: SocketAddr,
+    /// The forwarder as configured, including how it's reached
+    pub server: String,
+    pub up: bool,
+    pub successes: u64,
+    pub failures: u64,
+    /// Failures since the last successful query
+    pub consecutive_failures: u32,
+    pub last_success: Option<DateTime<Local>>,
+    pub last_failure: Option<DateTime<Local>>,
+}
+
+impl ForwarderStatus {
+    fn new(addr: SocketAddr) -> ForwarderStatus {
+        ForwarderStatus {
+            addr,
+            server: addr.to_string(),
+            up: true,
+            successes: 0,
+            failures: 0,
+            consecutive_failures: 0,
+            last_success: None,
+            last_failure: None,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct ForwarderHealth {
+    servers: RwLock<HashMap<SocketAddr, ForwarderStatus>>,
+}
+
+impl ForwarderHealth {
+    pub fn new() -> ForwarderHealth {
+        ForwarderHealth::default()
+    }
+
+    pub fn record_success(&self, addr: SocketAddr) {
+        if let Ok(mut servers) = self.servers.write() {
+            let status = servers
+                .entry(addr)
+                .or_insert_with(|| ForwarderStatus::new(addr));
+            if !status.up {
+                println!("Forwarder {} is up again", addr);
+            }
+
+            status.up = true;
+            status.successes += 1;
+            status.consecutive_failures = 0;
+            status.last_success = Some(Local::now());
+        }
+    }
+
+    pub fn record_failure(&self, addr: SocketAddr) {
+        if let Ok(mut servers) = self.servers.write() {
+            let status = servers
+                .entry(addr)
+                .or_insert_with(|| ForwarderStatus::new(addr));
+            status.failures += 1;
+            status.consecutive_failures += 1;
+            status.last_failure = Some(Local::now());
+
+            if status.up && status.consecutive_failures >= MAX_CONSECUTIVE_FAILURES {
+                println!("Forwarder {} is down", addr);
+                status.up = false;
+            }
+        }
+    }
+
+    pub fn is_up(&self, addr: &SocketAddr) -> bool {
+        match self.servers.read() {
+            Ok(servers) => servers.get(addr).map(|x| x.up).unwrap_or(true),
+            Err(_) => true,
+        }
+    }
+
+    /// The forwarders to try for a query, in the order to try them. Those
+    /// that are down are left out, unless all of them are, in which case
+    /// they're all tried anyway rather than failing without trying.
+    pub fn order(&self, forwarders: &[Forwarder], order: ForwardOrder) -> Vec<Forwarder> {
+        let mut result = forwarders
+            .iter()
+            .filter(|x| self.is_up(&x.addr))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        if result.is_empty() {
+            result = forwarders.to_vec();
+        }
+
+        if order == ForwardOrder::Random {
+            result.shuffle(&mut thread_rng());
+        }
+
+        result
+    }
+
+    /// The forwarders that are down, and need to be probed
+    pub fn down(&self, forwarders: &[Forwarder]) -> Vec<Forwarder> {
+        forwarders
+            .iter()
+            .filter(|x| !self.is_up(&x.addr))
+            .cloned()
+            .collect()
+    }
+
+    pub fn list(&self, forwarders: &[Forwarder]) -> Result<Vec<ForwarderStatus>> {
+        let servers = self
+            .servers
+            .read()
+            .map_err(|_| ForwardError::PoisonedLock)?;
+
+        Ok(forwarders
+            .iter()
+            .map(|forwarder| {
+                let mut status = servers
+                    .get(&forwarder.addr)
+                    .cloned()
+                    .unwrap_or_else(|| ForwarderStatus::new(forwarder.addr));
+                status.server = forwarder.to_string();
+                status
+            })
+            .collect())
+    }
+}
+
+/// Parse the address of a forwarder, either as a bare IP address using port
+/// 53, or with a port as in `192.0.2.1:5353` or `[2001:db8::1]:5353`
+///
+/// Forwarders reached over TLS are prefixed with `tls://`, use port 853
+/// unless given another, and may be followed by the name to expect on their
+/// certificate, as in `tls://1.1.1.1#cloudflare-dns.com`. Those reached over
+/// HTTPS are given the same way with an `https://` prefix, use port 443, and
+/// may have the path of the DNS API after the address, which otherwise is
+/// `/dns-query`, as in `https://1.1.1.1/dns-query#cloudflare-dns.com`.
+pub fn parse_forwarder(s: &str) -> Option<Forwarder> {
+    if let Some(rest) = s.strip_prefix("tls://") {
+        let mut parts = rest.splitn(2, '#');
+        let addr = parse_address(parts.next()?, 853)?;
+        let server_name = match parts.next() {
+            Some("") => return None,
+            Some(name) => Some(name.to_string()),
+            None => None,
+        };
+
+        return Some(Forwarder {
+            addr,
+            transport: Transport::Tls { server_name },
+        });
+    }
+
+    if let Some(rest) = s.strip_prefix("https://") {
+        let mut parts = rest.splitn(2, '#');
+        let location = parts.next()?;
+        let server_name = match parts.next() {
+            Some("") => return None,
+            Some(name) => Some(name.to_string()),
+            None => None,
+        };
+
+        let (addr, path) = match location.find('/') {
+            Some(i) => (&location[..i], &location[i..]),
+            None => (location, "/dns-query"),
+        };
+
+        return Some(Forwarder {
+            addr: parse_address(addr, 443)?,
+            transport: Transport::Https {
+                path: path.to_string(),
+                server_name,
+            },
+        });
+    }
+
+    parse_address(s, 53).map(Forwarder::plain)
+}
+
+fn parse_address(s: &str, default_port: u16) -> Option<SocketAddr> {
+    s.parse::<SocketAddr>().ok().or_else(|| {
+        s.parse::<IpAddr>()
+            .ok()
+            .map(|ip| SocketAddr::new(ip, default_port))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_parse_forwarder() {
+        let plain = |s: &str| Some(Forwarder::plain(s.parse().unwrap()));
+        let tls = |s: &str, name: Option<&str>| {
+            Some(Forwarder {
+                addr: s.parse().unwrap(),
+                transport: Transport::Tls {
+                    server_name: name.map(|x| x.to_string()),
+                },
+            })
+        };
+
+        assert_eq!(plain("192.0.2.1:53"), parse_forwarder("192.0.2.1"));
+        assert_eq!(plain("192.0.2.1:5353"), parse_forwarder("192.0.2.1:5353"));
+        assert_eq!(plain("[2001:db8::1]:53"), parse_forwarder("2001:db8::1"));
+        assert_eq!(
+            plain("[2001:db8::1]:5353"),
+            parse_forwarder("[2001:db8::1]:5353")
+        );
+        assert_eq!(None, parse_forwarder("dns.example.com"));
+        assert_eq!(None, parse_forwarder("192.0.2.1:dns"));
+
+        assert_eq!(
+            tls("192.0.2.1:853", None),
+            parse_forwarder("tls://192.0.2.1")
+        );
+        assert_eq!(
+            tls("[2001:db8::1]:8853", Some("dns.example.com")),
+            parse_forwarder("tls://[2001:db8::1]:8853#dns.example.com")
+        );
+        assert_eq!(None, parse_forwarder("tls://dns.example.com"));
+        assert_eq!(None, parse_forwarder("tls://192.0.2.1#"));
+
+        let https = |s: &str, path: &str, name: Option<&str>| {
+            Some(Forwarder {
+                addr: s.parse().unwrap(),
+                transport: Transport::Https {
+                    path: path.to_string(),
+                    server_name: name.map(|x| x.to_string()),
+                },
+            })
+        };
+
+        assert_eq!(
+            https("192.0.2.1:443", "/dns-query", None),
+            parse_forwarder("https://192.0.2.1")
+        );
+        assert_eq!(
+            https("[2001:db8::1]:8443", "/resolve", Some("dns.example.com")),
+            parse_forwarder("https://[2001:db8::1]:8443/resolve#dns.example.com")
+        );
+        assert_eq!(None, parse_forwarder("https://dns.example.com/dns-query"));
+
+        // Forwarders are shown the way they're given
+        for s in &[
+            "192.0.2.1:53",
+            "tls://192.0.2.1:853#dns.example.com",
+            "https://192.0.2.1:443/dns-query#dns.example.com",
+        ] {
+            assert_eq!(*s, parse_forwarder(s).unwrap().to_string());
+        }
+    }
+
+    #[test]
+    fn test_health() {
+        let health = ForwarderHealth::new();
+        let first: SocketAddr = "192.0.2.1:53".parse().unwrap();
+        let second: SocketAddr = "[2001:db8::1]:5353".parse().unwrap();
+        let forwarders = vec![
+            Forwarder::plain(first),
+            parse_forwarder("tls://[2001:db8::1]:5353").unwrap(),
+        ];
+
+        assert_eq!(forwarders, health.order(&forwarders, ForwardOrder::Ordered));
+
+        // A forwarder stays up until it has failed repeatedly
+        for _ in 0..MAX_CONSECUTIVE_FAILURES - 1 {
+            health.record_failure(first);
+        }
+        assert!(health.is_up(&first));
+
+        health.record_failure(first);
+        assert!(!health.is_up(&first));
+        assert_eq!(vec![forwarders[0].clone()], health.down(&forwarders));
+        assert_eq!(
+            vec![forwarders[1].clone()],
+            health.order(&forwarders, ForwardOrder::Ordered)
+        );
+        assert_eq!(
+            vec![forwarders[1].clone()],
+            health.order(&forwarders, ForwardOrder::Random)
+        );
+
+        // With every forwarder down, they're all tried anyway
+        for _ in 0..MAX_CONSECUTIVE_FAILURES {
+            health.record_failure(second);
+        }
+        assert_eq!(forwarders, health.order(&forwarders, ForwardOrder::Ordered));
+
+        // A single success brings a forwarder back
+        health.record_success(first);
+        assert!(health.is_up(&first));
+        assert_eq!(
+            vec![forwarders[0].clone()],
+            health.order(&forwarders, ForwardOrder::Ordered)
+        );
+
+        let list = health.list(&forwarders).unwrap();
+        assert_eq!(2, list.len());
+        assert_eq!(first, list[0].addr);
+        assert!(list[0].up);
+        assert_eq!("192.0.2.1:53", list[0].server);
+        assert_eq!(1, list[0].successes);
+        assert_eq!(MAX_CONSECUTIVE_FAILURES as u64, list[0].failures);
+        assert_eq!(0, list[0].consecutive_failures);
+        assert!(!list[1].up);
+        assert_eq!("tls://[2001:db8::1]:5353", list[1].server);
+    }
+}
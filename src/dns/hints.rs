@@ -0,0 +1,310 @@
+//! the root hints that recursive resolution starts out from, and the priming
+//! of the root name servers from them (RFC 8109)
+
+use std::fs;
+use std::net::IpAddr;
+
+use derive_more::{Display, Error, From};
+use rand::seq::SliceRandom;
+use rand::thread_rng;
+
+use crate::dns::context::ServerContext;
+use crate::dns::protocol::{DnsPacket, DnsRecord, QueryType, ResultCode};
+use crate::dns::zonefile::parse_zone;
+
+#[derive(Debug, Display, From, Error)]
+pub enum HintsError {
+    Client(crate::dns::client::ClientError),
+    Cache(crate::dns::cache::CacheError),
+    ZoneFile(crate::dns::zonefile::ZoneFileError),
+    Io(std::io::Error),
+    /// Hints without the address of any root name server
+    NoRootServers,
+    /// None of the root name servers tried answered with the root name
+    /// servers
+    PrimingFailed,
+}
+
+type Result<T> = std::result::Result<T, HintsError>;
+
+/// The root hints published by IANA, used unless others are configured
+const DEFAULT_ROOT_HINTS: &str = include_str!("named.root");
+
+/// Root name servers to ask in turn when priming, before giving up until the
+/// next attempt
+const MAX_PRIMING_ATTEMPTS: usize = 3;
+
+pub fn default_root_hints() -> Vec<DnsRecord> {
+    parse_root_hints(DEFAULT_ROOT_HINTS).expect("bundled root hints are valid")
+}
+
+/// Read root hints from a file in the format of `named.root`
+pub fn load_root_hints(path: &str) -> Result<Vec<DnsRecord>> {
+    parse_root_hints(&fs::read_to_string(path)?)
+}
+
+pub fn parse_root_hints(data: &str) -> Result<Vec<DnsRecord>> {
+    let records = parse_zone(data, "")?
+        .records
+        .into_iter()
+        .collect::<Vec<_>>();
+
+    if root_server_addresses(&records).is_empty() {
+        return Err(HintsError::NoRootServers);
+    }
+
+    Ok(records)
+}
+
+fn root_server_hosts(records: &[DnsRecord]) -> Vec<&str> {
+    records
+        .iter()
+        .filter_map(|rec| match *rec {
+            DnsRecord::NS {
+                ref domain,
+                ref host,
+                ..
+            } if domain.is_empty() => Some(host.as_str()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// The addresses given for the root name servers among `records`
+fn root_server_addresses(records: &[DnsRecord]) -> Vec<IpAddr> {
+    let hosts = root_server_hosts(records);
+
+    records
+        .iter()
+        .filter_map(|rec| match *rec {
+            DnsRecord::A {
+                ref domain, addr, ..
+            } if hosts.contains(&domain.as_str()) => Some(IpAddr::V4(addr)),
+            DnsRecord::AAAA {
+                ref domain, addr, ..
+            } if hosts.contains(&domain.as_str()) => Some(IpAddr::V6(addr)),
+            _ => None,
+        })
+        .collect()
+}
+
+/// The addresses of the root name servers in the cache, whether they came
+/// from priming or from the hints, or those of the hints if the cache has
+/// none
+fn known_root_servers(context: &ServerContext) -> Vec<IpAddr> {
+    let mut addrs = Vec::new();
+    if let Some(qr) = context.cache.lookup("", QueryType::NS) {
+        for host in qr.get_ns_hosts("") {
+            for qtype in context.ip_preference.address_types() {
+                if let Some(qr) = context.cache.lookup(&host, *qtype) {
+                    addrs.extend(qr.get_addresses());
+                }
+            }
+        }
+    }
+
+    if addrs.is_empty() {
+        addrs = root_server_addresses(&context.root_hints);
+    }
+
+    addrs.retain(|addr| context.ip_preference.allows(addr));
+    addrs
+}
+
+/// The root name servers and their addresses from a response to a priming
+/// query, if it's a usable one
+fn priming_records(response: &DnsPacket) -> Option<Vec<DnsRecord>> {
+    if response.header.rescode != ResultCode::NOERROR {
+        return None;
+    }
+
+    let mut records = response
+        .answers
+        .iter()
+        .filter(|rec| match **rec {
+            DnsRecord::NS { ref domain, .. } => domain.is_empty(),
+            _ => false,
+        })
+        .cloned()
+        .collect::<Vec<_>>();
+
+    if records.is_empty() {
+        return None;
+    }
+
+    let hosts = root_server_hosts(&records)
+        .into_iter()
+        .map(|x| x.to_string())
+        .collect::<Vec<_>>();
+
+    records.extend(
+        response
+            .resources
+            .iter()
+            .filter(|rec| match **rec {
+                DnsRecord::A { ref domain, .. } | DnsRecord::AAAA { ref domain, .. } => {
+                    hosts.contains(domain)
+                }
+                _ => false,
+            })
+            .cloned(),
+    );
+
+    Some(records)
+}
+
+/// Ask a root name server for the current set of root name servers, and
+/// cache it in place of the hints (RFC 8109). Returns the number of seconds
+/// until the set expires, by which time priming is due again.
+pub fn prime(context: &ServerContext) -> Result<u32> {
+    let mut addrs = known_root_servers(context);
+    addrs.shuffle(&mut thread_rng());
+
+    for addr in addrs.iter().take(MAX_PRIMING_ATTEMPTS) {
+        let server = addr.to_string();
+        let response =
+            match context
+                .client
+                .send_query("", QueryType::NS, (server.as_str(), 53), false)
+            {
+                Ok(x) => x,
+                Err(e) => {
+                    println!("Priming query to {} failed: {:?}", addr, e);
+                    continue;
+                }
+            };
+
+        let records = match priming_records(&response) {
+            Some(x) => x,
+            None => {
+                println!("Priming query to {} got no root name servers", addr);
+                continue;
+            }
+        };
+
+        context.cache.store_priming(&records)?;
+
+        let ttl = records
+            .iter()
+            .filter(|rec| rec.get_querytype() == QueryType::NS)
+            .map(|rec| rec.get_ttl())
+            .min()
+            .unwrap_or(0);
+
+        return Ok(ttl);
+    }
+
+    Err(HintsError::PrimingFailed)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use crate::dns::context::tests::create_test_context;
+    use crate::dns::protocol::TransientTtl;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_root_hints() {
+        let hints = default_root_hints();
+
+        assert_eq!(13, root_server_hosts(&hints).len());
+        assert_eq!(26, root_server_addresses(&hints).len());
+        assert!(hints.contains(&DnsRecord::A {
+            domain: "b.root-servers.net".to_string(),
+            addr: "170.247.170.2".parse().unwrap(),
+            ttl: TransientTtl(3600000),
+        }));
+
+        let hints = parse_root_hints(
+            ".                        3600000      NS    A.ROOT-SERVERS.NET.\n\
+             A.ROOT-SERVERS.NET.      3600000      A     198.41.0.4\n",
+        )
+        .unwrap();
+        assert_eq!(2, hints.len());
+
+        // Name servers are of no use without their addresses
+        assert!(parse_root_hints(".  3600000  NS  A.ROOT-SERVERS.NET.\n").is_err());
+        assert!(parse_root_hints("").is_err());
+    }
+
+    #[test]
+    fn test_prime() {
+        let queries = Arc::new(AtomicUsize::new(0));
+        let counter = queries.clone();
+
+        let mut context =
+            create_test_context(Box::new(move |qname, qtype, (server, _), recursive| {
+                counter.fetch_add(1, Ordering::SeqCst);
+
+                assert_eq!("", qname);
+                assert_eq!(QueryType::NS, qtype);
+                assert!(!recursive);
+
+                if server != "192.0.2.1" {
+                    return Err(crate::dns::client::ClientError::TimeOut);
+                }
+
+                let mut packet = DnsPacket::new();
+                packet.header.authoritative_answer = true;
+                packet.answers.push(DnsRecord::NS {
+                    domain: "".to_string(),
+                    host: "a.root-servers.net".to_string(),
+                    ttl: TransientTtl(518400),
+                });
+                packet.resources.push(DnsRecord::A {
+                    domain: "a.root-servers.net".to_string(),
+                    addr: "192.0.2.53".parse().unwrap(),
+                    ttl: TransientTtl(518400),
+                });
+                packet.resources.push(DnsRecord::A {
+                    domain: "ns.example.com".to_string(),
+                    addr: "192.0.2.99".parse().unwrap(),
+                    ttl: TransientTtl(518400),
+                });
+
+                Ok(packet)
+            }));
+
+        let hints = parse_root_hints(
+            ".                    3600000  NS  A.ROOT-SERVERS.NET.\n\
+             A.ROOT-SERVERS.NET.  3600000  A   192.0.2.1\n",
+        )
+        .unwrap();
+
+        match Arc::get_mut(&mut context) {
+            Some(ctx) => ctx.root_hints = hints.clone(),
+            None => panic!(),
+        }
+        context.cache.store_hints(&hints).unwrap();
+
+        // The primed address replaces the one from the hints, and nothing
+        // but the addresses of the root name servers is taken from the
+        // response
+        assert_eq!(518400, prime(&context).unwrap());
+        assert_eq!(1, queries.load(Ordering::SeqCst));
+        assert_eq!(
+            vec!["192.0.2.53".parse::<IpAddr>().unwrap()],
+            known_root_servers(&context)
+        );
+        assert!(context
+            .cache
+            .lookup("ns.example.com", QueryType::A)
+            .is_none());
+
+        // The server primed from is no longer a root name server, so there's
+        // no one left to ask
+        assert!(prime(&context).is_err());
+        assert_eq!(2, queries.load(Ordering::SeqCst));
+
+        // The hints are kept for when the primed servers have expired
+        assert_eq!(
+            vec!["192.0.2.1".parse::<IpAddr>().unwrap()],
+            root_server_addresses(&context.root_hints)
+        );
+    }
+}
@@ -0,0 +1,440 @@
+//! client for sending DNS queries to forwarders over HTTPS (RFC 8484), for
+//! networks that let nothing but HTTPS out
+
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use base64::Engine;
+use derive_more::{Display, Error, From};
+use reqwest::blocking::Client;
+use reqwest::header::{ACCEPT, CONTENT_TYPE};
+use rustls::ClientConfig;
+
+use crate::dns::buffer::{BytePacketBuffer, PacketBuffer, StreamPacketBuffer};
+use crate::dns::protocol::{DnsPacket, DnsQuestion, QueryType};
+use crate::dns::tls::client_config;
+
+#[derive(Debug, Display, From, Error)]
+pub enum HttpsError {
+    Protocol(crate::dns::protocol::ProtocolError),
+    Http(reqwest::Error),
+    Tls(crate::dns::tls::TlsError),
+    PoisonedLock,
+    /// A response with a status other than 200
+    UnexpectedStatus,
+    /// A response that isn't a DNS message
+    UnexpectedContentType,
+}
+
+type Result<T> = std::result::Result<T, HttpsError>;
+
+const DNS_MESSAGE: &str = "application/dns-message";
+
+/// Seconds that a connection to a forwarder is kept open without any queries
+const IDLE_TIMEOUT: u64 = 90;
+
+/// How queries are sent over HTTPS (RFC 8484, section 4.1)
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum HttpsMethod {
+    /// As the body of a POST request
+    Post,
+    /// Encoded in the URL of a GET request, which caches along the way can
+    /// answer
+    Get,
+}
+
+pub struct DnsHttpsClient {
+    config: ClientConfig,
+    method: HttpsMethod,
+    timeout: Duration,
+
+    /// An HTTP client for each forwarder, by address and server name, each
+    /// keeping its connection open between queries
+    clients: Mutex<HashMap<(SocketAddr, Option<String>), Client>>,
+
+    total_sent: AtomicUsize,
+    total_failed: AtomicUsize,
+}
+
+impl Default for DnsHttpsClient {
+    fn default() -> Self {
+        DnsHttpsClient::new()
+    }
+}
+
+impl DnsHttpsClient {
+    /// A client trusting the usual certificate authorities of the web, and
+    /// sending its queries as POST requests
+    pub fn new() -> DnsHttpsClient {
+        // Without a CA file or any pins, there's nothing to fail on
+        let config = client_config(None, &[]).expect("default TLS settings");
+
+        DnsHttpsClient::with_config(config, HttpsMethod::Post, Duration::new(1, 0))
+    }
+
+    /// A client checking certificates as described for `client_config`
+    pub fn with_settings(
+        ca_file: Option<&str>,
+        pins: &[String],
+        method: HttpsMethod,
+        timeout: Duration,
+    ) -> Result<DnsHttpsClient> {
+        Ok(DnsHttpsClient::with_config(
+            client_config(ca_file, pins)?,
+            method,
+            timeout,
+        ))
+    }
+
+    fn with_config(
+        mut config: ClientConfig,
+        method: HttpsMethod,
+        timeout: Duration,
+    ) -> DnsHttpsClient {
+        // Prefer HTTP/2, which carries any number of queries at once over a
+        // single connection
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+        DnsHttpsClient {
+            config,
+            method,
+            timeout,
+            clients: Mutex::new(HashMap::new()),
+            total_sent: AtomicUsize::new(0),
+            total_failed: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn get_sent_count(&self) -> usize {
+        self.total_sent.load(Ordering::Acquire)
+    }
+
+    pub fn get_failed_count(&self) -> usize {
+        self.total_failed.load(Ordering::Acquire)
+    }
+
+    /// Send a query to the DNS API at `path` on the forwarder at `addr`,
+    /// checking its certificate against `server_name`, or against its address
+    /// without one
+    pub fn send_query(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        addr: SocketAddr,
+        path: &str,
+        server_name: Option<&str>,
+    ) -> Result<DnsPacket> {
+        let _ = self.total_sent.fetch_add(1, Ordering::Release);
+
+        let result = self.perform_query(qname, qtype, addr, path, server_name);
+        if result.is_err() {
+            let _ = self.total_failed.fetch_add(1, Ordering::Release);
+        }
+
+        result
+    }
+
+    fn perform_query(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        addr: SocketAddr,
+        path: &str,
+        server_name: Option<&str>,
+    ) -> Result<DnsPacket> {
+        // The id is left at zero, so that identical queries are identical
+        // requests as far as HTTP caches are concerned
+        let mut packet = DnsPacket::new();
+        packet.header.id = 0;
+        packet.header.questions = 1;
+        packet.header.recursion_desired = true;
+        packet.questions.push(DnsQuestion::new(qname.into(), qtype));
+
+        let mut req_buffer = BytePacketBuffer::new();
+        packet.write(&mut req_buffer, 0xFFFF)?;
+        let request = req_buffer.buf[0..req_buffer.pos()].to_vec();
+
+        let host = match (server_name, addr.ip()) {
+            (Some(name), _) => name.to_string(),
+            (None, IpAddr::V4(ip)) => ip.to_string(),
+            (None, IpAddr::V6(ip)) => format!("[{}]", ip),
+        };
+        let url = format!("https://{}:{}{}", host, addr.port(), path);
+
+        let client = self.get_client(addr, server_name)?;
+        let request = match self.method {
+            HttpsMethod::Post => client
+                .post(&url)
+                .header(CONTENT_TYPE, DNS_MESSAGE)
+                .body(request),
+            HttpsMethod::Get => {
+                let dns = base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(&request);
+                client.get(format!("{}?dns={}", url, dns))
+            }
+        };
+
+        let response = request.header(ACCEPT, DNS_MESSAGE).send()?;
+        if !response.status().is_success() {
+            return Err(HttpsError::UnexpectedStatus);
+        }
+
+        let is_dns_message = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|x| x.to_str().ok())
+            .map(|x| x.starts_with(DNS_MESSAGE))
+            .unwrap_or(false);
+        if !is_dns_message {
+            return Err(HttpsError::UnexpectedContentType);
+        }
+
+        let body = response.bytes()?;
+        let mut reader = &body[..];
+        let mut stream_buffer = StreamPacketBuffer::new(&mut reader);
+
+        Ok(DnsPacket::from_buffer(&mut stream_buffer)?)
+    }
+
+    fn get_client(&self, addr: SocketAddr, server_name: Option<&str>) -> Result<Client> {
+        let mut clients = self.clients.lock().map_err(|_| HttpsError::PoisonedLock)?;
+
+        let key = (addr, server_name.map(|x| x.to_string()));
+        if let Some(client) = clients.get(&key) {
+            return Ok(client.clone());
+        }
+
+        let mut builder = Client::builder()
+            .use_preconfigured_tls(self.config.clone())
+            .https_only(true)
+            .timeout(self.timeout)
+            .connect_timeout(self.timeout)
+            .pool_idle_timeout(Duration::from_secs(IDLE_TIMEOUT))
+            .tcp_keepalive(Duration::from_secs(IDLE_TIMEOUT));
+
+        // The name is only for the certificate and the request, and is never
+        // itself looked up
+        if let Some(name) = server_name {
+            builder = builder.resolve(name, addr);
+        }
+
+        let client = builder.build()?;
+        clients.insert(key, client.clone());
+
+        Ok(client)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::convert::Infallible;
+    use std::net::Ipv4Addr;
+    use std::sync::Arc;
+    use std::thread;
+
+    use hyper::server::conn::Http;
+    use hyper::service::service_fn;
+    use hyper::{Body, Method, Request, Response, Version};
+    use rustls::{Certificate, PrivateKey, ServerConfig};
+    use tokio::net::TcpListener;
+    use tokio_rustls::TlsAcceptor;
+
+    use crate::dns::protocol::{DnsRecord, TransientTtl};
+    use crate::dns::tls::tests::{create_test_certificate, TestCertificate};
+
+    use super::*;
+
+    struct StandIn {
+        addr: SocketAddr,
+        ca_file: String,
+        connections: Arc<AtomicUsize>,
+        /// The method and HTTP version of every request received
+        requests: Arc<Mutex<Vec<(Method, Version)>>>,
+    }
+
+    async fn answer(req: Request<Body>) -> std::result::Result<Response<Body>, Infallible> {
+        let query = match *req.method() {
+            Method::GET => req
+                .uri()
+                .query()
+                .and_then(|x| x.strip_prefix("dns="))
+                .and_then(|x| {
+                    base64::engine::general_purpose::URL_SAFE_NO_PAD
+                        .decode(x)
+                        .ok()
+                })
+                .unwrap_or_default(),
+            _ => hyper::body::to_bytes(req.into_body())
+                .await
+                .map(|x| x.to_vec())
+                .unwrap_or_default(),
+        };
+
+        let mut reader = &query[..];
+        let query = match DnsPacket::from_buffer(&mut StreamPacketBuffer::new(&mut reader)) {
+            Ok(x) if !x.questions.is_empty() => x,
+            _ => {
+                let mut response = Response::new(Body::empty());
+                *response.status_mut() = hyper::StatusCode::BAD_REQUEST;
+                return Ok(response);
+            }
+        };
+
+        let mut packet = DnsPacket::new();
+        packet.header.id = query.header.id;
+        packet.header.response = true;
+        packet.questions = query.questions.clone();
+        packet.answers.push(DnsRecord::A {
+            domain: query.questions[0].name.clone(),
+            addr: Ipv4Addr::new(127, 0, 0, 1),
+            ttl: TransientTtl(3600),
+        });
+
+        let mut buffer = BytePacketBuffer::new();
+        packet.write(&mut buffer, 0xFFFF).unwrap();
+
+        let mut response = Response::new(Body::from(buffer.buf[0..buffer.pos()].to_vec()));
+        response
+            .headers_mut()
+            .insert(CONTENT_TYPE, DNS_MESSAGE.parse().unwrap());
+
+        Ok(response)
+    }
+
+    /// Run a DNS over HTTPS server on a local port, answering every query for
+    /// an A record with 127.0.0.1
+    fn start_stand_in(name: &str) -> StandIn {
+        let TestCertificate {
+            ca_file,
+            cert_der,
+            key_der,
+            ..
+        } = create_test_certificate(name);
+
+        let mut config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(vec![Certificate(cert_der)], PrivateKey(key_der))
+            .unwrap();
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let connections = Arc::new(AtomicUsize::new(0));
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let counter = connections.clone();
+        let log = requests.clone();
+
+        thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+
+            runtime.block_on(async move {
+                let listener = TcpListener::from_std(listener).unwrap();
+                loop {
+                    let (socket, _) = match listener.accept().await {
+                        Ok(x) => x,
+                        Err(_) => continue,
+                    };
+
+                    counter.fetch_add(1, Ordering::SeqCst);
+
+                    let acceptor = acceptor.clone();
+                    let log = log.clone();
+                    tokio::spawn(async move {
+                        let stream = match acceptor.accept(socket).await {
+                            Ok(x) => x,
+                            Err(_) => return,
+                        };
+
+                        let service = service_fn(move |req: Request<Body>| {
+                            log.lock()
+                                .unwrap()
+                                .push((req.method().clone(), req.version()));
+                            answer(req)
+                        });
+
+                        let _ = Http::new().serve_connection(stream, service).await;
+                    });
+                }
+            });
+        });
+
+        StandIn {
+            addr,
+            ca_file,
+            connections,
+            requests,
+        }
+    }
+
+    #[test]
+    fn test_https_client() {
+        let stand_in = start_stand_in("https");
+
+        for &method in &[HttpsMethod::Post, HttpsMethod::Get] {
+            let client = DnsHttpsClient::with_settings(
+                Some(&stand_in.ca_file),
+                &[],
+                method,
+                Duration::new(5, 0),
+            )
+            .unwrap();
+
+            for qname in &["www.google.com", "www.yahoo.com", "www.bing.com"] {
+                let res = client
+                    .send_query(
+                        qname,
+                        QueryType::A,
+                        stand_in.addr,
+                        "/dns-query",
+                        Some("dns.test"),
+                    )
+                    .unwrap();
+
+                assert_eq!(1, res.answers.len());
+                match res.answers[0] {
+                    DnsRecord::A { ref domain, .. } => assert_eq!(qname, domain),
+                    _ => panic!(),
+                }
+            }
+
+            assert_eq!(3, client.get_sent_count());
+            assert_eq!(0, client.get_failed_count());
+        }
+
+        // Each client sent all of its queries over a single HTTP/2 connection
+        assert_eq!(2, stand_in.connections.load(Ordering::SeqCst));
+
+        let requests = stand_in.requests.lock().unwrap().clone();
+        assert_eq!(6, requests.len());
+        assert!(requests
+            .iter()
+            .all(|(_, version)| *version == Version::HTTP_2));
+        assert_eq!(3, requests.iter().filter(|x| x.0 == Method::POST).count());
+        assert_eq!(3, requests.iter().filter(|x| x.0 == Method::GET).count());
+
+        // The certificate of the stand-in isn't trusted by default
+        let client = DnsHttpsClient::new();
+        assert!(client
+            .send_query(
+                "www.google.com",
+                QueryType::A,
+                stand_in.addr,
+                "/dns-query",
+                Some("dns.test")
+            )
+            .is_err());
+        assert_eq!(1, client.get_failed_count());
+
+        let _ = std::fs::remove_file(&stand_in.ca_file);
+    }
+}
@@ -0,0 +1,246 @@
+//! coalescing of identical queries to other name servers, so that while one
+//! is in flight the others wait for its response rather than being sent too
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+
+use derive_more::{Display, Error, From};
+
+use crate::dns::protocol::{DnsPacket, QueryType};
+
+#[derive(Debug, Display, From, Error)]
+pub enum InflightError {
+    PoisonedLock,
+    /// The query that was waited on got no response
+    QueryFailed,
+}
+
+/// A query is identified by the name and type asked for, and the server
+/// asked
+type QueryKey = (String, QueryType, String);
+
+/// A query in flight, which completes with the response, or with nothing if
+/// it failed
+#[derive(Default)]
+struct PendingQuery {
+    outcome: Mutex<Option<Option<DnsPacket>>>,
+    done: Condvar,
+}
+
+impl PendingQuery {
+    fn complete(&self, response: Option<DnsPacket>) {
+        if let Ok(mut outcome) = self.outcome.lock() {
+            *outcome = Some(response);
+        }
+        self.done.notify_all();
+    }
+
+    fn wait(&self) -> Result<DnsPacket, InflightError> {
+        let mut outcome = self
+            .outcome
+            .lock()
+            .map_err(|_| InflightError::PoisonedLock)?;
+
+        loop {
+            match *outcome {
+                Some(Some(ref response)) => return Ok(response.clone()),
+                Some(None) => return Err(InflightError::QueryFailed),
+                None => {
+                    outcome = self
+                        .done
+                        .wait(outcome)
+                        .map_err(|_| InflightError::PoisonedLock)?;
+                }
+            }
+        }
+    }
+}
+
+/// Held by the thread sending a query on behalf of everyone asking the same,
+/// which completes the query when dropped, even if sending it panicked
+struct Leader<'a> {
+    queries: &'a InflightQueries,
+    key: QueryKey,
+    pending: Arc<PendingQuery>,
+    response: Option<DnsPacket>,
+}
+
+impl<'a> Drop for Leader<'a> {
+    fn drop(&mut self) {
+        if let Ok(mut pending) = self.queries.pending.lock() {
+            pending.remove(&self.key);
+        }
+        self.pending.complete(self.response.take());
+    }
+}
+
+#[derive(Default)]
+pub struct InflightQueries {
+    pending: Mutex<HashMap<QueryKey, Arc<PendingQuery>>>,
+    coalesced: AtomicUsize,
+}
+
+impl InflightQueries {
+    pub fn new() -> InflightQueries {
+        InflightQueries::default()
+    }
+
+    /// Send a query with `send`, unless the same query to the same server is
+    /// already in flight, in which case its response is waited for and
+    /// shared instead. When the query being waited for fails, so does this
+    /// one, rather than trying again with yet another query.
+    pub fn send<F, E>(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        server: &str,
+        send: F,
+    ) -> Result<DnsPacket, E>
+    where
+        F: FnOnce() -> Result<DnsPacket, E>,
+        E: From<InflightError>,
+    {
+        let key = (qname.to_lowercase(), qtype, server.to_string());
+
+        let mut leader = {
+            let mut pending = self
+                .pending
+                .lock()
+                .map_err(|_| InflightError::PoisonedLock)?;
+
+            if let Some(query) = pending.get(&key) {
+                let query = query.clone();
+                drop(pending);
+
+                self.coalesced.fetch_add(1, Ordering::Release);
+                return Ok(query.wait()?);
+            }
+
+            let query = Arc::new(PendingQuery::default());
+            pending.insert(key.clone(), query.clone());
+
+            Leader {
+                queries: self,
+                key,
+                pending: query,
+                response: None,
+            }
+        };
+
+        let result = send();
+        if let Ok(ref response) = result {
+            leader.response = Some(response.clone());
+        }
+
+        result
+    }
+
+    /// The number of queries that were answered with the response to another
+    /// one rather than being sent
+    pub fn get_coalesced_count(&self) -> usize {
+        self.coalesced.load(Ordering::Acquire)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Barrier};
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_coalesce_queries() {
+        let queries = Arc::new(InflightQueries::new());
+        let sent = Arc::new(AtomicUsize::new(0));
+        let barrier = Arc::new(Barrier::new(5));
+
+        // Every thread asks for the same thing at once, while the one that
+        // gets to send it takes its time
+        let threads = (0..5)
+            .map(|i| {
+                let queries = queries.clone();
+                let sent = sent.clone();
+                let barrier = barrier.clone();
+                thread::spawn(move || {
+                    barrier.wait();
+                    if i > 0 {
+                        thread::sleep(Duration::from_millis(50));
+                    }
+
+                    queries.send("Google.com", QueryType::A, "8.8.8.8", || {
+                        sent.fetch_add(1, Ordering::SeqCst);
+                        thread::sleep(Duration::from_millis(300));
+
+                        let mut packet = DnsPacket::new();
+                        packet.header.id = 1234;
+                        Ok::<_, InflightError>(packet)
+                    })
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for thread in threads {
+            assert_eq!(1234, thread.join().unwrap().unwrap().header.id);
+        }
+
+        assert_eq!(1, sent.load(Ordering::SeqCst));
+        assert_eq!(4, queries.get_coalesced_count());
+
+        // Once it's answered, the same query is sent again, while a different
+        // type or server never waits for it
+        for (qtype, server) in &[
+            (QueryType::A, "8.8.8.8"),
+            (QueryType::AAAA, "8.8.8.8"),
+            (QueryType::A, "8.8.4.4"),
+        ] {
+            queries
+                .send("google.com", *qtype, server, || {
+                    sent.fetch_add(1, Ordering::SeqCst);
+                    Ok::<_, InflightError>(DnsPacket::new())
+                })
+                .unwrap();
+        }
+
+        assert_eq!(4, sent.load(Ordering::SeqCst));
+        assert_eq!(4, queries.get_coalesced_count());
+    }
+
+    #[test]
+    fn test_coalesce_failed_query() {
+        let queries = Arc::new(InflightQueries::new());
+        let sent = Arc::new(AtomicUsize::new(0));
+
+        let leader = {
+            let queries = queries.clone();
+            let sent = sent.clone();
+            thread::spawn(move || {
+                queries.send("google.com", QueryType::A, "8.8.8.8", || {
+                    sent.fetch_add(1, Ordering::SeqCst);
+                    thread::sleep(Duration::from_millis(300));
+                    Err(InflightError::PoisonedLock)
+                })
+            })
+        };
+
+        thread::sleep(Duration::from_millis(50));
+
+        // Waiting on a query that fails is a failure too, without another
+        // query being sent
+        let result = queries.send("google.com", QueryType::A, "8.8.8.8", || {
+            sent.fetch_add(1, Ordering::SeqCst);
+            Ok::<_, InflightError>(DnsPacket::new())
+        });
+
+        match result {
+            Err(InflightError::QueryFailed) => {}
+            _ => panic!(),
+        }
+        assert!(leader.join().unwrap().is_err());
+        assert_eq!(1, sent.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,214 @@
+//! tracking of how quickly other name servers respond, so that the fastest
+//! ones can be preferred during recursive resolution
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::RwLock;
+
+use chrono::*;
+use derive_more::{Display, Error, From};
+use rand::random;
+use serde_derive::Serialize;
+
+#[derive(Debug, Display, From, Error)]
+pub enum InfraError {
+    PoisonedLock,
+}
+
+type Result<T> = std::result::Result<T, InfraError>;
+
+/// The round trip time assumed for servers that haven't been measured yet, in
+/// milliseconds. It's low enough for new servers to be tried alongside known
+/// fast ones.
+pub const UNKNOWN_RTT: u32 = 376;
+
+/// Servers this many milliseconds slower than the fastest candidate are still
+/// picked some of the time, so that their measurements stay current
+pub const RTT_BAND: u32 = 400;
+
+/// The highest round trip time a server can be penalized to, in milliseconds
+pub const MAX_RTT: u32 = 120_000;
+
+/// Seconds until the measurements for a server are forgotten, giving servers
+/// that failed in the past another chance
+pub const INFRA_TTL: i64 = 900;
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ServerStats {
+    /// The smoothed round trip time, in milliseconds
+    pub srtt: u32,
+    pub successes: u64,
+    pub failures: u64,
+    /// Failures since the last successful query
+    pub consecutive_failures: u32,
+    pub updated: DateTime<Local>,
+}
+
+impl ServerStats {
+    fn new(now: DateTime<Local>) -> ServerStats {
+        ServerStats {
+            srtt: UNKNOWN_RTT,
+            successes: 0,
+            failures: 0,
+            consecutive_failures: 0,
+            updated: now,
+        }
+    }
+
+    fn is_expired(&self, now: DateTime<Local>) -> bool {
+        self.updated + Duration::seconds(INFRA_TTL) < now
+    }
+}
+
+#[derive(Default)]
+pub struct InfraCache {
+    servers: RwLock<HashMap<IpAddr, ServerStats>>,
+}
+
+impl InfraCache {
+    pub fn new() -> InfraCache {
+        InfraCache::default()
+    }
+
+    /// Fold a new measurement into the smoothed round trip time, the same way
+    /// TCP does (RFC 6298)
+    pub fn record_success(&self, addr: IpAddr, rtt: std::time::Duration) {
+        let rtt = rtt.as_millis().min(MAX_RTT as u128) as u32;
+        let now = Local::now();
+
+        if let Ok(mut servers) = self.servers.write() {
+            let stats = servers.entry(addr).or_insert_with(|| ServerStats::new(now));
+            if stats.successes == 0 || stats.is_expired(now) {
+                stats.srtt = rtt;
+            } else {
+                stats.srtt = ((stats.srtt as u64 * 7 + rtt as u64) / 8) as u32;
+            }
+
+            stats.successes += 1;
+            stats.consecutive_failures = 0;
+            stats.updated = now;
+        }
+    }
+
+    /// Back off from a server that didn't answer, by doubling its round trip
+    /// time
+    pub fn record_failure(&self, addr: IpAddr) {
+        let now = Local::now();
+
+        if let Ok(mut servers) = self.servers.write() {
+            let stats = servers.entry(addr).or_insert_with(|| ServerStats::new(now));
+            if stats.is_expired(now) {
+                stats.srtt = UNKNOWN_RTT;
+            }
+
+            stats.srtt = stats.srtt.max(UNKNOWN_RTT).saturating_mul(2).min(MAX_RTT);
+            stats.failures += 1;
+            stats.consecutive_failures += 1;
+            stats.updated = now;
+        }
+    }
+
+    /// The round trip time to expect from a server
+    pub fn get_rtt(&self, addr: &IpAddr) -> u32 {
+        let now = Local::now();
+
+        match self.servers.read() {
+            Ok(servers) => match servers.get(addr) {
+                Some(stats) if !stats.is_expired(now) => stats.srtt,
+                _ => UNKNOWN_RTT,
+            },
+            Err(_) => UNKNOWN_RTT,
+        }
+    }
+
+    /// Pick the server to query out of `candidates`. Rather than always using
+    /// the fastest, a random one is chosen out of those within `RTT_BAND` of
+    /// it, which includes servers that haven't been tried yet.
+    pub fn select(&self, candidates: &[IpAddr]) -> Option<IpAddr> {
+        let rtts = candidates
+            .iter()
+            .map(|addr| (*addr, self.get_rtt(addr)))
+            .collect::<Vec<_>>();
+
+        let best = rtts.iter().map(|x| x.1).min()?;
+        let band = rtts
+            .into_iter()
+            .filter(|x| x.1 <= best.saturating_add(RTT_BAND))
+            .collect::<Vec<_>>();
+
+        Some(band[random::<usize>() % band.len()].0)
+    }
+
+    pub fn list(&self) -> Result<Vec<(IpAddr, ServerStats)>> {
+        let servers = self.servers.read().map_err(|_| InfraError::PoisonedLock)?;
+
+        Ok(servers
+            .iter()
+            .map(|(addr, stats)| (*addr, stats.clone()))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::time::Duration as StdDuration;
+
+    use super::*;
+
+    #[test]
+    fn test_rtt() {
+        let infra = InfraCache::new();
+        let addr: IpAddr = "192.0.2.1".parse().unwrap();
+
+        assert_eq!(UNKNOWN_RTT, infra.get_rtt(&addr));
+
+        infra.record_success(addr, StdDuration::from_millis(100));
+        assert_eq!(100, infra.get_rtt(&addr));
+
+        infra.record_success(addr, StdDuration::from_millis(20));
+        assert_eq!(90, infra.get_rtt(&addr));
+
+        infra.record_failure(addr);
+        assert_eq!(UNKNOWN_RTT * 2, infra.get_rtt(&addr));
+
+        for _ in 0..20 {
+            infra.record_failure(addr);
+        }
+        assert_eq!(MAX_RTT, infra.get_rtt(&addr));
+
+        let list = infra.list().unwrap();
+        assert_eq!(1, list.len());
+        assert_eq!(2, list[0].1.successes);
+        assert_eq!(21, list[0].1.failures);
+        assert_eq!(21, list[0].1.consecutive_failures);
+    }
+
+    #[test]
+    fn test_select() {
+        let infra = InfraCache::new();
+        let fast: IpAddr = "192.0.2.1".parse().unwrap();
+        let slow: IpAddr = "192.0.2.2".parse().unwrap();
+        let dead: IpAddr = "192.0.2.3".parse().unwrap();
+        let unknown: IpAddr = "2001:db8::1".parse().unwrap();
+
+        infra.record_success(fast, StdDuration::from_millis(10));
+        infra.record_success(slow, StdDuration::from_millis(2000));
+        infra.record_failure(dead);
+
+        assert_eq!(None, infra.select(&[]));
+        assert_eq!(Some(dead), infra.select(&[dead]));
+
+        // Slow and failing servers are avoided, but servers that haven't been
+        // measured yet are explored
+        let mut selected = Vec::new();
+        for _ in 0..100 {
+            selected.push(infra.select(&[fast, slow, dead, unknown]).unwrap());
+        }
+
+        assert!(selected.contains(&fast));
+        assert!(selected.contains(&unknown));
+        assert!(!selected.contains(&slow));
+        assert!(!selected.contains(&dead));
+    }
+}
@@ -1,12 +1,22 @@
 //! The dns module implements the DNS protocol and the related functions
 
+pub mod audit;
 pub mod authority;
 pub mod buffer;
 pub mod cache;
 pub mod client;
 pub mod context;
+pub mod forward;
+pub mod hints;
+pub mod https;
+pub mod inflight;
+pub mod infra;
+pub mod pool;
 pub mod protocol;
 pub mod resolve;
+pub mod route;
 pub mod server;
+pub mod tls;
+pub mod zonefile;
 
 mod netutil;
@@ -0,0 +1,190 @@
+//! a small pool of threads for work done in the background, such as
+//! refreshing cache entries, which starts threads as work comes in and lets
+//! them go once there's nothing left to do
+
+use std::collections::VecDeque;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+use std::thread::Builder;
+
+use derive_more::{Display, Error, From};
+
+#[derive(Debug, Display, From, Error)]
+pub enum PoolError {
+    Io(std::io::Error),
+    PoisonedLock,
+    /// Too much work is waiting already
+    QueueFull,
+}
+
+type Result<T> = std::result::Result<T, PoolError>;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+#[derive(Default)]
+struct PoolState {
+    queue: VecDeque<Job>,
+    threads: usize,
+}
+
+pub struct WorkerPool {
+    name: String,
+    max_threads: usize,
+    max_queued: usize,
+    state: Arc<Mutex<PoolState>>,
+}
+
+impl WorkerPool {
+    /// A pool running at most `max_threads` jobs at once, while at most
+    /// `max_queued` more wait for their turn
+    pub fn new(name: &str, max_threads: usize, max_queued: usize) -> WorkerPool {
+        WorkerPool {
+            name: name.to_string(),
+            max_threads,
+            max_queued,
+            state: Arc::new(Mutex::new(PoolState::default())),
+        }
+    }
+
+    /// Run `job` on one of the threads of the pool. When it can't be taken
+    /// on, it's dropped without running.
+    pub fn execute<F>(&self, job: F) -> Result<()>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let mut state = self.state.lock().map_err(|_| PoolError::PoisonedLock)?;
+
+        if state.queue.len() >= self.max_queued {
+            return Err(PoolError::QueueFull);
+        }
+
+        state.queue.push_back(Box::new(job));
+        if state.threads >= self.max_threads {
+            return Ok(());
+        }
+
+        let worker_state = self.state.clone();
+        let spawned = Builder::new()
+            .name(self.name.clone())
+            .spawn(move || work(worker_state));
+
+        match spawned {
+            Ok(_) => {
+                state.threads += 1;
+                Ok(())
+            }
+            Err(e) => {
+                // Leave the job to the threads already running, unless there
+                // are none
+                if state.threads == 0 {
+                    state.queue.pop_back();
+                    return Err(e.into());
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// The number of jobs waiting for a thread
+    pub fn get_queued_count(&self) -> usize {
+        match self.state.lock() {
+            Ok(state) => state.queue.len(),
+            Err(_) => 0,
+        }
+    }
+}
+
+/// Run jobs off the queue until it's empty
+fn work(state: Arc<Mutex<PoolState>>) {
+    loop {
+        let job = match state.lock() {
+            Ok(mut state) => match state.queue.pop_front() {
+                Some(job) => job,
+                None => {
+                    state.threads -= 1;
+                    return;
+                }
+            },
+            Err(_) => return,
+        };
+
+        // A job that panics takes nothing else down with it
+        let _ = catch_unwind(AssertUnwindSafe(job));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc::channel;
+    use std::sync::Barrier;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_execute() {
+        let pool = WorkerPool::new("test", 2, 2);
+        let barrier = Arc::new(Barrier::new(3));
+        let (started_tx, started_rx) = channel();
+        let (done_tx, done_rx) = channel();
+
+        // Two jobs keep both threads busy
+        for _ in 0..2 {
+            let barrier = barrier.clone();
+            let started_tx = started_tx.clone();
+            let done_tx = done_tx.clone();
+            pool.execute(move || {
+                let _ = started_tx.send(());
+                barrier.wait();
+                let _ = done_tx.send(());
+            })
+            .unwrap();
+        }
+
+        for _ in 0..2 {
+            started_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        }
+
+        // Two more wait for them, after which the queue is full
+        let ran = Arc::new(AtomicUsize::new(0));
+        for _ in 0..2 {
+            let ran = ran.clone();
+            let done_tx = done_tx.clone();
+            pool.execute(move || {
+                ran.fetch_add(1, Ordering::SeqCst);
+                let _ = done_tx.send(());
+            })
+            .unwrap();
+        }
+
+        assert_eq!(2, pool.get_queued_count());
+        match pool.execute(|| {}) {
+            Err(PoolError::QueueFull) => {}
+            _ => panic!(),
+        }
+
+        barrier.wait();
+        for _ in 0..4 {
+            done_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        }
+
+        assert_eq!(2, ran.load(Ordering::SeqCst));
+        assert_eq!(0, pool.get_queued_count());
+    }
+
+    #[test]
+    fn test_execute_after_panic() {
+        let pool = WorkerPool::new("test", 1, 10);
+        let (tx, rx) = channel();
+
+        pool.execute(|| panic!("job failed")).unwrap();
+        pool.execute(move || {
+            let _ = tx.send(());
+        })
+        .unwrap();
+
+        rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    }
+}
@@ -4,7 +4,7 @@
 use std::cmp::Ordering;
 use std::fmt;
 use std::hash::{Hash, Hasher};
-use std::net::{Ipv4Addr, Ipv6Addr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
 use derive_more::{Display, Error, From};
 use rand::random;
@@ -533,6 +533,21 @@ impl DnsRecord {
         }
     }
 
+    pub fn set_domain(&mut self, new_domain: String) {
+        match *self {
+            DnsRecord::A { ref mut domain, .. }
+            | DnsRecord::AAAA { ref mut domain, .. }
+            | DnsRecord::NS { ref mut domain, .. }
+            | DnsRecord::CNAME { ref mut domain, .. }
+            | DnsRecord::SRV { ref mut domain, .. }
+            | DnsRecord::MX { ref mut domain, .. }
+            | DnsRecord::UNKNOWN { ref mut domain, .. }
+            | DnsRecord::SOA { ref mut domain, .. }
+            | DnsRecord::TXT { ref mut domain, .. } => *domain = new_domain,
+            DnsRecord::OPT { .. } => {}
+        }
+    }
+
     pub fn get_ttl(&self) -> u32 {
         match *self {
             DnsRecord::A {
@@ -574,10 +589,25 @@ impl DnsRecord {
             DnsRecord::OPT { .. } => 0,
         }
     }
+
+    pub fn set_ttl(&mut self, new_ttl: u32) {
+        match *self {
+            DnsRecord::A { ref mut ttl, .. }
+            | DnsRecord::AAAA { ref mut ttl, .. }
+            | DnsRecord::NS { ref mut ttl, .. }
+            | DnsRecord::CNAME { ref mut ttl, .. }
+            | DnsRecord::SRV { ref mut ttl, .. }
+            | DnsRecord::MX { ref mut ttl, .. }
+            | DnsRecord::UNKNOWN { ref mut ttl, .. }
+            | DnsRecord::SOA { ref mut ttl, .. }
+            | DnsRecord::TXT { ref mut ttl, .. } => *ttl = TransientTtl(new_ttl),
+            DnsRecord::OPT { .. } => {}
+        }
+    }
 }
 
 /// The result code for a DNS query, as described in the specification
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum ResultCode {
     NOERROR = 0,
     FORMERR = 1,
@@ -873,14 +903,19 @@ impl DnsPacket {
         }
     }
 
+    pub fn get_soa(&self) -> Option<&DnsRecord> {
+        self.authorities
+            .iter()
+            .find(|x| matches!(x, DnsRecord::SOA { .. }))
+    }
+
+    /// The time a negative answer may be cached for, which is the lesser of
+    /// the TTL and the minimum field of the SOA (RFC 2308)
     pub fn get_ttl_from_soa(&self) -> Option<u32> {
-        for answer in &self.authorities {
-            if let DnsRecord::SOA { minimum, .. } = *answer {
-                return Some(minimum);
-            }
+        match self.get_soa() {
+            Some(soa @ DnsRecord::SOA { minimum, .. }) => Some(soa.get_ttl().min(*minimum)),
+            _ => None,
         }
-
-        None
     }
 
     pub fn get_random_a(&self) -> Option<String> {
@@ -895,27 +930,28 @@ impl DnsPacket {
         None
     }
 
-    pub fn get_unresolved_cnames(&self) -> Vec<DnsRecord> {
-        let mut unresolved = Vec::new();
-        for answer in &self.answers {
-            let mut matched = false;
-            if let DnsRecord::CNAME { ref host, .. } = *answer {
-                for answer2 in &self.answers {
-                    if let DnsRecord::A { ref domain, .. } = *answer2 {
-                        if domain == host {
-                            matched = true;
-                            break;
-                        }
-                    }
+    /// The CNAME records among the answers that point at names without any
+    /// records of `qtype` or further CNAME records among them, which have to
+    /// be resolved separately to complete the chain
+    pub fn get_unresolved_cnames(&self, qtype: QueryType) -> Vec<DnsRecord> {
+        let is_resolved = |host: &str| {
+            self.answers.iter().any(|rec| match rec.get_domain() {
+                Some(ref domain) if domain.eq_ignore_ascii_case(host) => {
+                    let rtype = rec.get_querytype();
+                    rtype == qtype || rtype == QueryType::CNAME
                 }
-            }
-
-            if !matched {
-                unresolved.push(answer.clone());
-            }
-        }
+                _ => false,
+            })
+        };
 
-        unresolved
+        self.answers
+            .iter()
+            .filter(|answer| match **answer {
+                DnsRecord::CNAME { ref host, .. } => !is_resolved(host),
+                _ => false,
+            })
+            .cloned()
+            .collect()
     }
 
     pub fn get_resolved_ns(&self, qname: &str) -> Option<String> {
@@ -964,6 +1000,66 @@ impl DnsPacket {
         None
     }
 
+    /// The IPv4 and IPv6 addresses of the given A and AAAA records
+    fn addresses<'a, I>(records: I) -> Vec<IpAddr>
+    where
+        I: Iterator<Item = &'a DnsRecord>,
+    {
+        records
+            .filter_map(|rec| match *rec {
+                DnsRecord::A { addr, .. } => Some(IpAddr::V4(addr)),
+                DnsRecord::AAAA { addr, .. } => Some(IpAddr::V6(addr)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// All addresses in the answer section, both IPv4 and IPv6
+    pub fn get_addresses(&self) -> Vec<IpAddr> {
+        DnsPacket::addresses(self.answers.iter())
+    }
+
+    /// The addresses of the name servers for `qname` that were included as
+    /// glue in the additional section, both IPv4 and IPv6
+    pub fn get_resolved_ns_addresses(&self, qname: &str) -> Vec<IpAddr> {
+        let hosts = self
+            .authorities
+            .iter()
+            .filter_map(|auth| match *auth {
+                DnsRecord::NS {
+                    ref domain,
+                    ref host,
+                    ..
+                } if is_subdomain(qname, domain) => Some(host),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        DnsPacket::addresses(
+            self.resources
+                .iter()
+                .filter(|rsrc| match rsrc.get_domain() {
+                    Some(ref domain) => hosts.contains(&domain),
+                    None => false,
+                }),
+        )
+    }
+
+    /// The names of all name servers for `qname` in the authority section
+    pub fn get_ns_hosts(&self, qname: &str) -> Vec<String> {
+        self.authorities
+            .iter()
+            .filter_map(|auth| match *auth {
+                DnsRecord::NS {
+                    ref domain,
+                    ref host,
+                    ..
+                } if is_subdomain(qname, domain) => Some(host.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
     pub fn get_unresolved_ns(&self, qname: &str) -> Option<String> {
         let mut new_authorities = Vec::new();
         for auth in &self.authorities {
@@ -1043,12 +1139,33 @@ impl DnsPacket {
     }
 }
 
+/// Check whether `name` equals `zone` or is located below it, comparing
+/// complete labels rather than just the trailing characters
+pub fn is_subdomain(name: &str, zone: &str) -> bool {
+    if zone.is_empty() || name == zone {
+        return true;
+    }
+
+    name.len() > zone.len()
+        && name.ends_with(zone)
+        && name.as_bytes()[name.len() - zone.len() - 1] == b'.'
+}
+
 #[cfg(test)]
 mod tests {
 
     use super::*;
     use crate::dns::buffer::{PacketBuffer, VectorPacketBuffer};
 
+    #[test]
+    fn test_is_subdomain() {
+        assert!(is_subdomain("www.google.com", "google.com"));
+        assert!(is_subdomain("google.com", "google.com"));
+        assert!(is_subdomain("google.com", ""));
+        assert!(!is_subdomain("notgoogle.com", "google.com"));
+        assert!(!is_subdomain("com", "google.com"));
+    }
+
     #[test]
     fn test_packet() {
         let mut packet = DnsPacket::new();
@@ -1093,4 +1210,68 @@ mod tests {
         assert_eq!(packet.answers[2], parsed_packet.answers[2]);
         assert_eq!(packet.answers[3], parsed_packet.answers[3]);
     }
+
+    #[test]
+    fn test_get_unresolved_cnames() {
+        let cname = |domain: &str, host: &str| DnsRecord::CNAME {
+            domain: domain.to_string(),
+            host: host.to_string(),
+            ttl: TransientTtl(3600),
+        };
+
+        let mut packet = DnsPacket::new();
+        packet
+            .answers
+            .push(cname("www.google.com", "www.l.google.com"));
+        packet
+            .answers
+            .push(cname("www.l.google.com", "cdn.google.com"));
+        packet.answers.push(DnsRecord::A {
+            domain: "CDN.google.com".to_string(),
+            addr: "127.0.0.1".parse().unwrap(),
+            ttl: TransientTtl(3600),
+        });
+
+        // The chain ends in an address of the right type
+        assert!(packet.get_unresolved_cnames(QueryType::A).is_empty());
+
+        // But not in one of any other type
+        assert_eq!(
+            vec![cname("www.l.google.com", "cdn.google.com")],
+            packet.get_unresolved_cnames(QueryType::AAAA)
+        );
+        assert_eq!(
+            vec![cname("www.l.google.com", "cdn.google.com")],
+            packet.get_unresolved_cnames(QueryType::MX)
+        );
+    }
+
+    #[test]
+    fn test_get_ns_hosts() {
+        let mut packet = DnsPacket::new();
+        for (domain, host) in &[
+            ("example.com", "ns1.example.com"),
+            ("ample.com", "ns.ample.com"),
+        ] {
+            packet.authorities.push(DnsRecord::NS {
+                domain: domain.to_string(),
+                host: host.to_string(),
+                ttl: TransientTtl(3600),
+            });
+            packet.resources.push(DnsRecord::A {
+                domain: host.to_string(),
+                addr: "127.0.0.1".parse().unwrap(),
+                ttl: TransientTtl(3600),
+            });
+        }
+
+        // Only the name servers of zones that the name is within count, not
+        // those of zones whose names merely end the same way
+        assert_eq!(
+            vec!["ns1.example.com".to_string()],
+            packet.get_ns_hosts("www.example.com")
+        );
+        assert_eq!(1, packet.get_resolved_ns_addresses("www.example.com").len());
+        assert!(packet.get_ns_hosts("example.org").is_empty());
+    }
 }
@@ -1,20 +1,54 @@
 //! resolver implementations implementing different strategies for answering
 //! incoming queries
 
+use std::net::IpAddr;
+use std::sync::mpsc::{channel, Receiver};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use std::vec::Vec;
 
 use derive_more::{Display, Error, From};
+use rand::random;
 
+use crate::dns::cache::Trust;
 use crate::dns::context::ServerContext;
-use crate::dns::protocol::{DnsPacket, QueryType, ResultCode};
+use crate::dns::forward::{ForwardOrder, Forwarder, Transport};
+use crate::dns::protocol::{is_subdomain, DnsPacket, DnsRecord, QueryType, ResultCode};
+use crate::dns::route::RouteTarget;
+
+/// The most queries to spend on revealing a name one label at a time, before
+/// falling back to asking for all of it (RFC 9156)
+const MAX_MINIMISE_STEPS: usize = 10;
 
 #[derive(Debug, Display, From, Error)]
 pub enum ResolveError {
     Client(crate::dns::client::ClientError),
+    Tls(crate::dns::tls::TlsError),
+    Https(crate::dns::https::HttpsError),
+    Inflight(crate::dns::inflight::InflightError),
     Cache(crate::dns::cache::CacheError),
     Io(std::io::Error),
     NoServerFound,
+    #[display(fmt = "Gave up after {} ms", timeout)]
+    #[from(ignore)]
+    TimeOut {
+        timeout: u64,
+    },
+    #[display(fmt = "Gave up after following {} referrals", limit)]
+    #[from(ignore)]
+    TooManyReferrals {
+        limit: usize,
+    },
+    #[display(fmt = "Gave up after {} nested lookups of name servers", limit)]
+    #[from(ignore)]
+    TooManyNestedLookups {
+        limit: usize,
+    },
+    #[display(fmt = "Gave up after sending {} queries", limit)]
+    #[from(ignore)]
+    TooManyQueries {
+        limit: usize,
+    },
 }
 
 type Result<T> = std::result::Result<T, ResolveError>;
@@ -23,6 +57,23 @@ pub trait DnsResolver {
     fn get_context(&self) -> Arc<ServerContext>;
 
     fn resolve(&mut self, qname: &str, qtype: QueryType, recursive: bool) -> Result<DnsPacket> {
+        self.resolve_counted(qname, qtype, recursive, true)
+    }
+
+    /// Resolve a query made by the server itself rather than by a client,
+    /// such as the lookup of a name server, which is left out of the cache
+    /// statistics
+    fn resolve_internal(&mut self, qname: &str, qtype: QueryType) -> Result<DnsPacket> {
+        self.resolve_counted(qname, qtype, true, false)
+    }
+
+    fn resolve_counted(
+        &mut self,
+        qname: &str,
+        qtype: QueryType,
+        recursive: bool,
+        counted: bool,
+    ) -> Result<DnsPacket> {
         if let QueryType::UNKNOWN(_) = qtype {
             let mut packet = DnsPacket::new();
             packet.header.rescode = ResultCode::NOTIMP;
@@ -42,34 +93,170 @@ pub trait DnsResolver {
         }
 
         if let Some(qr) = context.cache.lookup(qname, qtype) {
+            if counted {
+                context
+                    .statistics
+                    .record_cache_hit(qtype, qr.answers.is_empty());
+            }
+
+            if context.cache.needs_prefetch(qname, qtype) {
+                refresh_in_background(context, qname, qtype);
+            }
+
             return Ok(qr);
         }
 
-        if qtype == QueryType::A || qtype == QueryType::AAAA {
+        // A name with a CNAME record has no records of any other type, so the
+        // CNAME answers for all of them
+        if qtype != QueryType::CNAME {
             if let Some(qr) = context.cache.lookup(qname, QueryType::CNAME) {
+                if counted {
+                    context.statistics.record_cache_hit(qtype, false);
+                }
                 return Ok(qr);
             }
         }
 
-        self.perform(qname, qtype)
+        if counted {
+            context.statistics.record_cache_miss(qtype);
+        }
+
+        let stale = context
+            .cache
+            .lookup_stale(qname, qtype)
+            .or_else(|| match qtype {
+                QueryType::CNAME => None,
+                _ => context.cache.lookup_stale(qname, QueryType::CNAME),
+            });
+
+        let stale = match stale {
+            Some(x) => x,
+            None => return self.perform_routed(qname, qtype),
+        };
+
+        // With an expired answer to fall back on, resolve the name in the
+        // background, and only wait a little while for it before answering
+        // with the expired one instead (RFC 8767)
+        let refreshed = match refresh_in_background(context.clone(), qname, qtype) {
+            Some(x) => x,
+            None => return Ok(stale),
+        };
+
+        match refreshed.recv_timeout(Duration::from_millis(context.stale_answer_timeout)) {
+            Ok(qr) => Ok(qr),
+            Err(_) => Ok(stale),
+        }
+    }
+
+    /// Resolve a query with the resolver that the routing rule for the name
+    /// points at, or with this one if there's no such rule
+    fn perform_routed(&mut self, qname: &str, qtype: QueryType) -> Result<DnsPacket> {
+        let context = self.get_context();
+        match context.routes.lookup(qname) {
+            Some(RouteTarget::Forward(servers)) => {
+                ForwardingDnsResolver::new(context, servers, ForwardOrder::Ordered)
+                    .perform(qname, qtype)
+            }
+            Some(RouteTarget::Recursive) if !self.is_recursive() => {
+                RecursiveDnsResolver::new(context).perform(qname, qtype)
+            }
+            _ => self.perform(qname, qtype),
+        }
+    }
+
+    /// Whether this resolver resolves names from the root servers
+    fn is_recursive(&self) -> bool {
+        false
     }
 
     fn perform(&mut self, qname: &str, qtype: QueryType) -> Result<DnsPacket>;
 }
 
+/// Cache an NXDOMAIN or NODATA response, for as long as the SOA allows
+fn store_negative(context: &ServerContext, qname: &str, qtype: QueryType, response: &DnsPacket) {
+    let ttl = match response.get_ttl_from_soa() {
+        Some(x) => x,
+        None => return,
+    };
+
+    match response.header.rescode {
+        ResultCode::NXDOMAIN => {
+            let _ = context.cache.store_nxdomain(qname, ttl, response.get_soa());
+        }
+        ResultCode::NOERROR if response.answers.is_empty() => {
+            let _ = context
+                .cache
+                .store_nodata(qname, qtype, ttl, response.get_soa());
+        }
+        _ => {}
+    }
+}
+
+/// Resolve a query again on the refresh pool, either to replace a stale answer
+/// once the upstream servers are reachable again, or to prefetch a popular
+/// record before it expires. At most one refresh per name and type is in
+/// flight at any time, and none while one has recently failed. The response
+/// is passed on through the returned channel, unless resolving fails.
+fn refresh_in_background(
+    context: Arc<ServerContext>,
+    qname: &str,
+    qtype: QueryType,
+) -> Option<Receiver<DnsPacket>> {
+    match context.cache.start_refresh(qname, qtype) {
+        Ok(true) => {}
+        _ => return None,
+    }
+
+    let (tx, rx) = channel();
+    let job_context = context.clone();
+    let job_qname = qname.to_string();
+    let queued = context.refresh_pool.execute(move || {
+        let mut resolver = job_context.create_resolver(job_context.clone());
+        let failed = match resolver.perform_routed(&job_qname, qtype) {
+            Ok(qr) => {
+                let failed = qr.header.rescode == ResultCode::SERVFAIL;
+                if !failed {
+                    let _ = tx.send(qr);
+                }
+                failed
+            }
+            Err(e) => {
+                println!("Failed to refresh {:?} {}: {:?}", qtype, job_qname, e);
+                true
+            }
+        };
+
+        let _ = job_context.cache.finish_refresh(&job_qname, qtype, failed);
+    });
+
+    match queued {
+        Ok(_) => Some(rx),
+        Err(_) => {
+            let _ = context.cache.finish_refresh(qname, qtype, false);
+            None
+        }
+    }
+}
+
 /// A Forwarding DNS Resolver
 ///
 /// This resolver uses an external DNS server to service a query
 pub struct ForwardingDnsResolver {
     context: Arc<ServerContext>,
-    server: (String, u16),
+    servers: Vec<Forwarder>,
+    order: ForwardOrder,
 }
 
 impl ForwardingDnsResolver {
-    pub fn new(context: Arc<ServerContext>, server: (String, u16)) -> ForwardingDnsResolver {
+    pub fn new(
+        context: Arc<ServerContext>,
+        servers: Vec<Forwarder>,
+        order: ForwardOrder,
+    ) -> ForwardingDnsResolver {
         ForwardingDnsResolver {
             context: context,
-            server: server,
+            servers,
+            order,
         }
     }
 }
@@ -80,15 +267,79 @@ impl DnsResolver for ForwardingDnsResolver {
     }
 
     fn perform(&mut self, qname: &str, qtype: QueryType) -> Result<DnsPacket> {
-        let &(ref host, port) = &self.server;
-        let result = self
-            .context
-            .client
-            .send_query(qname, qtype, (host.as_str(), port), true)?;
+        let deadline = Instant::now() + Duration::from_millis(self.context.resolve_timeout);
+        let mut last_error = ResolveError::NoServerFound;
+
+        // Fall back on the next forwarder whenever one doesn't answer
+        for forwarder in self.context.forwarders.order(&self.servers, self.order) {
+            if Instant::now() >= deadline {
+                return Err(ResolveError::TimeOut {
+                    timeout: self.context.resolve_timeout,
+                });
+            }
+
+            let server = forwarder.to_string();
+            let sent = self.context.inflight.send(qname, qtype, &server, || {
+                send_to_forwarder(&self.context, &forwarder, qname, qtype)
+            });
 
-        self.context.cache.store(&result.answers)?;
+            let result = match sent {
+                Ok(x) => x,
+                Err(e) => {
+                    println!("Forwarder {} failed: {:?}", forwarder, e);
+                    if !matches!(e, ResolveError::Inflight(_)) {
+                        self.context.forwarders.record_failure(forwarder.addr);
+                    }
+                    last_error = e;
+                    continue;
+                }
+            };
+
+            self.context.forwarders.record_success(forwarder.addr);
+
+            self.context.cache.store(&result.answers)?;
+            store_negative(&self.context, qname, qtype, &result);
 
-        Ok(result)
+            return Ok(result);
+        }
+
+        Err(last_error)
+    }
+}
+
+/// Send a query to a forwarder, over whichever transport it's reached with
+pub fn send_to_forwarder(
+    context: &ServerContext,
+    forwarder: &Forwarder,
+    qname: &str,
+    qtype: QueryType,
+) -> Result<DnsPacket> {
+    match forwarder.transport {
+        Transport::Plain => {
+            let host = forwarder.addr.ip().to_string();
+            Ok(context.client.send_query(
+                qname,
+                qtype,
+                (host.as_str(), forwarder.addr.port()),
+                true,
+            )?)
+        }
+        Transport::Tls { ref server_name } => Ok(context.tls_client.send_query(
+            qname,
+            qtype,
+            forwarder.addr,
+            server_name.as_deref(),
+        )?),
+        Transport::Https {
+            ref path,
+            ref server_name,
+        } => Ok(context.https_client.send_query(
+            qname,
+            qtype,
+            forwarder.addr,
+            path,
+            server_name.as_deref(),
+        )?),
     }
 }
 
@@ -97,108 +348,429 @@ impl DnsResolver for ForwardingDnsResolver {
 /// This resolver can answer any request using the root servers of the internet
 pub struct RecursiveDnsResolver {
     context: Arc<ServerContext>,
+    /// What's left to spend on the resolution in progress
+    budget: Option<Budget>,
+}
+
+/// The work that a single resolution is allowed to do, including the lookups
+/// of name servers along the way, so that referral loops and long chains of
+/// name servers without glue are given up on rather than followed forever
+struct Budget {
+    deadline: Instant,
+    referrals: usize,
+    queries: usize,
+    /// How many lookups of name servers are currently nested
+    depth: usize,
 }
 
 impl RecursiveDnsResolver {
     pub fn new(context: Arc<ServerContext>) -> RecursiveDnsResolver {
-        RecursiveDnsResolver { context: context }
+        RecursiveDnsResolver {
+            context: context,
+            budget: None,
+        }
     }
-}
 
-impl DnsResolver for RecursiveDnsResolver {
-    fn get_context(&self) -> Arc<ServerContext> {
-        self.context.clone()
+    /// Cache the records of a response, ranked by the section they're from
+    fn store_response(&self, response: &DnsPacket) {
+        let cache = &self.context.cache;
+        let _ = cache.store_with_trust(&response.answers, Trust::Answer);
+        let _ = cache.store_with_trust(&response.authorities, Trust::Authority);
+        let _ = cache.store_with_trust(&response.resources, Trust::Glue);
     }
 
-    fn perform(&mut self, qname: &str, qtype: QueryType) -> Result<DnsPacket> {
-        // Find the closest name server by splitting the label and progessively
-        // moving towards the root servers. I.e. check "google.com", then "com",
-        // and finally "".
-        let mut tentative_ns = None;
+    /// Account for a query about to be sent to another name server
+    fn spend_query(&mut self) -> Result<()> {
+        match self.budget {
+            Some(ref budget) if Instant::now() >= budget.deadline => Err(ResolveError::TimeOut {
+                timeout: self.context.resolve_timeout,
+            }),
+            Some(ref budget) if budget.queries == 0 => Err(ResolveError::TooManyQueries {
+                limit: self.context.max_upstream_queries,
+            }),
+            Some(ref mut budget) => {
+                budget.queries -= 1;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
 
+    /// Account for a referral about to be followed
+    fn spend_referral(&mut self) -> Result<()> {
+        match self.budget {
+            Some(ref budget) if budget.referrals == 0 => Err(ResolveError::TooManyReferrals {
+                limit: self.context.max_referrals,
+            }),
+            Some(ref mut budget) => {
+                budget.referrals -= 1;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Find the closest zone to `qname` with name servers in the cache, by
+    /// splitting the label and progessively moving towards the root servers.
+    /// I.e. check "google.com", then "com", and finally "". Returns the zone
+    /// along with every known address of its name servers.
+    fn find_closest_zone(&self, qname: &str) -> Option<(String, Vec<IpAddr>)> {
         let labels = qname.split('.').collect::<Vec<&str>>();
         for lbl_idx in 0..labels.len() + 1 {
             let domain = labels[lbl_idx..].join(".");
 
-            match self
-                .context
-                .cache
-                .lookup(&domain, QueryType::NS)
-                .and_then(|qr| qr.get_unresolved_ns(&domain))
-                .and_then(|ns| self.context.cache.lookup(&ns, QueryType::A))
-                .and_then(|qr| qr.get_random_a())
+            let ns_names = match self.context.cache.lookup(&domain, QueryType::NS) {
+                Some(qr) => qr.get_ns_hosts(&domain),
+                None => continue,
+            };
+
+            let mut addrs = Vec::new();
+            for ns_name in &ns_names {
+                for addr_qtype in self.context.ip_preference.address_types() {
+                    if let Some(qr) = self.context.cache.lookup(ns_name, *addr_qtype) {
+                        addrs.extend(qr.get_addresses());
+                    }
+                }
+            }
+
+            if addrs
+                .iter()
+                .any(|addr| self.context.ip_preference.allows(addr))
             {
-                Some(addr) => {
-                    tentative_ns = Some(addr);
+                return Some((domain, addrs));
+            }
+        }
+
+        None
+    }
+
+    /// Look up the addresses of a name server that came without glue, trying
+    /// each type of address the server is allowed to use until one is found.
+    /// Only running out of budget is an error, since other name servers of
+    /// the zone can still be tried if this one can't be found.
+    fn resolve_ns_addresses(&mut self, ns_name: &str) -> Result<Vec<IpAddr>> {
+        let max_depth = self.context.max_nested_lookups;
+        match self.budget {
+            Some(ref budget) if budget.depth >= max_depth => {
+                return Err(ResolveError::TooManyNestedLookups { limit: max_depth })
+            }
+            Some(ref mut budget) => budget.depth += 1,
+            None => {}
+        }
+
+        let mut result = Ok(Vec::new());
+        for addr_qtype in self.context.ip_preference.address_types() {
+            match self.resolve_internal(ns_name, *addr_qtype) {
+                Ok(qr) if !qr.get_addresses().is_empty() => {
+                    result = Ok(qr.get_addresses());
                     break;
                 }
-                None => continue,
+                Ok(_) => {}
+                Err(e @ ResolveError::TimeOut { .. })
+                | Err(e @ ResolveError::TooManyReferrals { .. })
+                | Err(e @ ResolveError::TooManyNestedLookups { .. })
+                | Err(e @ ResolveError::TooManyQueries { .. }) => {
+                    result = Err(e);
+                    break;
+                }
+                Err(e) => println!("Failed to resolve name server {}: {:?}", ns_name, e),
             }
         }
 
-        let mut ns = tentative_ns.ok_or_else(|| ResolveError::NoServerFound)?;
+        if let Some(ref mut budget) = self.budget {
+            budget.depth -= 1;
+        }
+
+        result
+    }
+
+    fn resolve_recursively(&mut self, qname: &str, qtype: QueryType) -> Result<DnsPacket> {
+        // The zone that the current name servers are authoritative for, which
+        // is all they're allowed to tell us about
+        let (mut zone, mut candidates) = self
+            .find_closest_zone(qname)
+            .ok_or_else(|| ResolveError::NoServerFound)?;
+
+        // Name servers of the zone that have yet to be looked up, and the
+        // addresses that have been tried already
+        let mut unresolved: Vec<String> = Vec::new();
+        let mut tried: Vec<IpAddr> = Vec::new();
+
+        // What to return if every name server fails
+        let mut fallback = Err(ResolveError::NoServerFound);
+
+        // How many labels of the name to reveal to the current name servers,
+        // and how many more queries can be spent on revealing them one by one
+        let labels = qname.split('.').collect::<Vec<&str>>();
+        let mut revealed = label_count(&zone) + 1;
+        let mut minimise = self.context.qname_minimisation;
+        let mut minimise_steps = MAX_MINIMISE_STEPS;
 
         // Start querying name servers
         loop {
-            println!("attempting lookup of {:?} {} with ns {}", qtype, qname, ns);
+            let untried = candidates
+                .iter()
+                .filter(|addr| !tried.contains(addr))
+                .cloned()
+                .collect::<Vec<_>>();
 
-            let ns_copy = ns.clone();
+            let ns = match pick_address(&self.context, &untried) {
+                Some(x) => x,
+                None if !unresolved.is_empty() => {
+                    let ns_name = unresolved.swap_remove(random::<usize>() % unresolved.len());
+                    candidates.extend(self.resolve_ns_addresses(&ns_name)?);
+                    continue;
+                }
+                None => return fallback,
+            };
+
+            self.spend_query()?;
+            tried.push(ns);
+
+            // Only ask for the full name once the servers of its closest zone
+            // are reached. Until then, the name servers higher up just need to
+            // know enough to refer us further down the tree.
+            let minimised = minimise && minimise_steps > 0 && revealed < labels.len();
+            let (ask_name, ask_qtype) = if minimised {
+                (labels[labels.len() - revealed..].join("."), QueryType::A)
+            } else {
+                (qname.to_string(), qtype)
+            };
+
+            println!(
+                "attempting lookup of {:?} {} with ns {} for zone {:?}",
+                ask_qtype, ask_name, ns, zone
+            );
+
+            let ns_copy = ns.to_string();
 
             let server = (ns_copy.as_str(), 53);
-            let response = self
+            let started = Instant::now();
+            let client = &self.context.client;
+            let sent = self
                 .context
-                .client
-                .send_query(qname, qtype.clone(), server, false)?;
+                .inflight
+                .send(&ask_name, ask_qtype, &ns_copy, || {
+                    client
+                        .send_query(&ask_name, ask_qtype, server, false)
+                        .map_err(ResolveError::from)
+                });
+
+            let mut response = match sent {
+                Ok(x) => x,
+                Err(e) => {
+                    // Move on to the next name server. A failure shared with
+                    // an identical query has been counted against it already.
+                    println!("Name server {} failed: {:?}", ns, e);
+                    if !matches!(e, ResolveError::Inflight(_)) {
+                        self.context.infra.record_failure(ns);
+                    }
+                    fallback = Err(e);
+                    continue;
+                }
+            };
 
-            // If we've got an actual answer, we're done!
-            if !response.answers.is_empty() && response.header.rescode == ResultCode::NOERROR {
-                let _ = self.context.cache.store(&response.answers);
-                let _ = self.context.cache.store(&response.authorities);
-                let _ = self.context.cache.store(&response.resources);
-                return Ok(response.clone());
+            // Some servers get confused by names they weren't expecting, so
+            // ask the same server for the full name instead
+            if minimised
+                && response.header.rescode != ResultCode::NOERROR
+                && response.header.rescode != ResultCode::NXDOMAIN
+            {
+                minimise = false;
+                tried.retain(|addr| *addr != ns);
+                continue;
+            }
+
+            // A server that can't or won't answer for the zone is no better
+            // than one that doesn't answer at all
+            if response.header.rescode == ResultCode::SERVFAIL
+                || response.header.rescode == ResultCode::REFUSED
+            {
+                self.context.infra.record_failure(ns);
+                fallback = Ok(response);
+                continue;
             }
 
-            if response.header.rescode == ResultCode::NXDOMAIN {
-                if let Some(ttl) = response.get_ttl_from_soa() {
-                    let _ = self.context.cache.store_nxdomain(qname, qtype, ttl);
+            self.context.infra.record_success(ns, started.elapsed());
+
+            filter_bailiwick(&mut response, &zone);
+
+            if minimised {
+                minimise_steps -= 1;
+
+                match get_referral_zone(&response, &ask_name) {
+                    // Handled like any other referral below
+                    Some(ref x) if x.len() > zone.len() => {}
+                    // The name is supposed to be missing altogether, but not
+                    // every server agrees that this includes the names below
+                    // it, so ask the same server for the full name instead
+                    _ if response.header.rescode == ResultCode::NXDOMAIN => {
+                        minimise = false;
+                        tried.retain(|addr| *addr != ns);
+                        continue;
+                    }
+                    // There's no zone cut at this name, so reveal another
+                    // label to the same servers
+                    _ => {
+                        revealed += 1;
+                        tried.clear();
+                        continue;
+                    }
                 }
-                return Ok(response.clone());
             }
 
-            // Otherwise, try to find a new nameserver based on NS and a
-            // corresponding A record in the additional section
-            if let Some(new_ns) = response.get_resolved_ns(qname) {
-                // If there is such a record, we can retry the loop with that NS
-                ns = new_ns.clone();
-                let _ = self.context.cache.store(&response.answers);
-                let _ = self.context.cache.store(&response.authorities);
-                let _ = self.context.cache.store(&response.resources);
+            // If we've got an actual answer, we're done! Answers to minimised
+            // queries only come along with a referral, and aren't what was
+            // asked for.
+            if !minimised
+                && !response.answers.is_empty()
+                && response.header.rescode == ResultCode::NOERROR
+            {
+                self.store_response(&response);
+                return Ok(response);
+            }
 
-                continue;
+            // A missing name, or a name with no records of the requested
+            // type, is indicated by the SOA of the zone rather than a referral
+            if !minimised
+                && (response.header.rescode == ResultCode::NXDOMAIN
+                    || (response.header.rescode == ResultCode::NOERROR
+                        && response.answers.is_empty()
+                        && response.get_soa().is_some()))
+            {
+                store_negative(&self.context, qname, qtype, &response);
+                return Ok(response);
             }
 
-            // If not, we'll have to resolve the ip of a NS record
-            let new_ns_name = match response.get_unresolved_ns(qname) {
-                Some(x) => x,
-                None => return Ok(response.clone()),
+            // Anything else should be a referral to a zone further down the
+            // tree. Referrals elsewhere would have us going around in circles.
+            let new_zone = match get_referral_zone(&response, qname) {
+                Some(x) if x.len() > zone.len() => x,
+                _ => return Ok(response),
             };
 
-            // Recursively resolve the NS
-            let recursive_response = self.resolve(&new_ns_name, QueryType::A, true)?;
-
-            // Pick a random IP and restart
-            if let Some(new_ns) = recursive_response.get_random_a() {
-                ns = new_ns.clone();
-            } else {
-                return Ok(response.clone());
+            self.spend_referral()?;
+
+            // Carry on with the name servers of the new zone, starting with
+            // those that have a corresponding A or AAAA record in the
+            // additional section
+            candidates = response.get_resolved_ns_addresses(qname);
+            // Glue of a kind of address we won't use is as good as none
+            let preference = self.context.ip_preference;
+            unresolved = response
+                .get_ns_hosts(qname)
+                .into_iter()
+                .filter(|host| {
+                    !response.resources.iter().any(|rsrc| match *rsrc {
+                        DnsRecord::A {
+                            ref domain, addr, ..
+                        } => domain == host && preference.allows(&IpAddr::V4(addr)),
+                        DnsRecord::AAAA {
+                            ref domain, addr, ..
+                        } => domain == host && preference.allows(&IpAddr::V6(addr)),
+                        _ => false,
+                    })
+                })
+                .collect();
+            tried.clear();
+            revealed = label_count(&new_zone) + 1;
+            zone = new_zone;
+
+            if !candidates.is_empty() {
+                self.store_response(&response);
             }
+
+            fallback = Ok(response);
         }
     }
 }
 
+impl DnsResolver for RecursiveDnsResolver {
+    fn get_context(&self) -> Arc<ServerContext> {
+        self.context.clone()
+    }
+
+    fn is_recursive(&self) -> bool {
+        true
+    }
+
+    fn perform(&mut self, qname: &str, qtype: QueryType) -> Result<DnsPacket> {
+        // Name servers looked up along the way share the budget of the query
+        // that needed them
+        let outermost = self.budget.is_none();
+        if outermost {
+            let timeout = Duration::from_millis(self.context.resolve_timeout);
+            self.budget = Some(Budget {
+                deadline: Instant::now() + timeout,
+                referrals: self.context.max_referrals,
+                queries: self.context.max_upstream_queries,
+                depth: 0,
+            });
+        }
+
+        let result = self.resolve_recursively(qname, qtype);
+
+        if outermost {
+            self.budget = None;
+        }
+
+        result
+    }
+}
+
+/// Pick the address to query out of `addrs`, preferring the servers that
+/// have been quickest to respond
+fn pick_address(context: &ServerContext, addrs: &[IpAddr]) -> Option<IpAddr> {
+    let allowed = addrs
+        .iter()
+        .filter(|addr| context.ip_preference.allows(addr))
+        .cloned()
+        .collect::<Vec<_>>();
+
+    context.infra.select(&allowed)
+}
+
+/// Drop every record which the name server for `zone` has no authority over.
+/// Otherwise any server could plant records for names outside its own zone,
+/// which would then be cached and handed out to clients.
+fn filter_bailiwick(response: &mut DnsPacket, zone: &str) {
+    let in_bailiwick = |rec: &DnsRecord| match rec.get_domain() {
+        Some(domain) => is_subdomain(&domain, zone),
+        None => false,
+    };
+
+    response.answers.retain(in_bailiwick);
+    response.authorities.retain(in_bailiwick);
+    response.resources.retain(in_bailiwick);
+}
+
+/// The number of labels in a domain, with the root having none
+fn label_count(domain: &str) -> usize {
+    if domain.is_empty() {
+        0
+    } else {
+        domain.split('.').count()
+    }
+}
+
+/// Find the zone that a referral delegates `qname` to
+fn get_referral_zone(response: &DnsPacket, qname: &str) -> Option<String> {
+    response
+        .authorities
+        .iter()
+        .filter_map(|rec| match *rec {
+            DnsRecord::NS { ref domain, .. } if is_subdomain(qname, domain) => Some(domain),
+            _ => None,
+        })
+        .max_by_key(|domain| domain.len())
+        .cloned()
+}
+
 #[cfg(test)]
 mod tests {
 
+    use std::net::Ipv4Addr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
     use std::sync::Arc;
 
     use crate::dns::protocol::{DnsPacket, DnsRecord, QueryType, ResultCode, TransientTtl};
@@ -206,7 +778,8 @@ mod tests {
     use super::*;
 
     use crate::dns::context::tests::create_test_context;
-    use crate::dns::context::ResolveStrategy;
+    use crate::dns::context::{IpPreference, ResolveStrategy};
+    use crate::dns::forward::ForwardOrder;
 
     #[test]
     fn test_forwarding_resolver() {
@@ -229,8 +802,8 @@ mod tests {
         match Arc::get_mut(&mut context) {
             Some(mut ctx) => {
                 ctx.resolve_strategy = ResolveStrategy::Forward {
-                    host: "127.0.0.1".to_string(),
-                    port: 53,
+                    servers: vec![Forwarder::plain("127.0.0.1:53".parse().unwrap())],
+                    order: ForwardOrder::Ordered,
                 };
             }
             None => panic!(),
@@ -274,7 +847,7 @@ mod tests {
 
             assert_eq!("google.com", list[0].domain);
             assert_eq!(1, list[0].record_types.len());
-            assert_eq!(1, list[0].hits);
+            assert_eq!(1, list[0].hits.get());
         };
 
         // Do a failed lookup
@@ -284,9 +857,283 @@ mod tests {
                 Err(_) => panic!(),
             };
 
-            assert_eq!(0, res.answers.len());
-            assert_eq!(ResultCode::NXDOMAIN, res.header.rescode);
-        };
+            assert_eq!(0, res.answers.len());
+            assert_eq!(ResultCode::NXDOMAIN, res.header.rescode);
+        };
+
+        // One of the lookups was answered from the cache
+        {
+            let stats = &context.statistics;
+            assert_eq!(1, stats.get_cache_hit_count());
+            assert_eq!(0, stats.get_cache_negative_hit_count());
+            assert_eq!(2, stats.get_cache_miss_count());
+            assert_eq!(
+                2,
+                stats.types[&QueryType::A]
+                    .cache_misses
+                    .load(std::sync::atomic::Ordering::Acquire)
+            );
+        };
+    }
+
+    #[test]
+    fn test_forwarding_resolver_failover() {
+        let mut context = create_test_context(Box::new(|qname, _, (server, port), _| {
+            if server != "2001:db8::1" || port != 5353 {
+                return Err(crate::dns::client::ClientError::TimeOut);
+            }
+
+            let mut packet = DnsPacket::new();
+            packet.answers.push(DnsRecord::A {
+                domain: qname.to_string(),
+                addr: "127.0.0.1".parse().unwrap(),
+                ttl: TransientTtl(3600),
+            });
+            Ok(packet)
+        }));
+
+        let dead = Forwarder::plain("127.0.0.1:53".parse().unwrap());
+        let alive = Forwarder::plain("[2001:db8::1]:5353".parse().unwrap());
+
+        match Arc::get_mut(&mut context) {
+            Some(mut ctx) => {
+                ctx.resolve_strategy = ResolveStrategy::Forward {
+                    servers: vec![dead.clone(), alive.clone()],
+                    order: ForwardOrder::Ordered,
+                };
+            }
+            None => panic!(),
+        }
+
+        let mut resolver = context.create_resolver(context.clone());
+
+        // The first forwarder is skipped once it has failed often enough
+        for i in 0..crate::dns::forward::MAX_CONSECUTIVE_FAILURES + 1 {
+            let qname = format!("{}.google.com", i);
+            let res = resolver.resolve(&qname, QueryType::A, true).unwrap();
+            assert_eq!(1, res.answers.len());
+        }
+
+        let list = context.forwarders.list(&[dead, alive]).unwrap();
+        assert!(!list[0].up);
+        assert_eq!(
+            crate::dns::forward::MAX_CONSECUTIVE_FAILURES as u64,
+            list[0].failures
+        );
+        assert!(list[1].up);
+        assert_eq!(
+            crate::dns::forward::MAX_CONSECUTIVE_FAILURES as u64 + 1,
+            list[1].successes
+        );
+    }
+
+    #[test]
+    fn test_forwarding_resolver_coalesces_queries() {
+        let queries = Arc::new(AtomicUsize::new(0));
+        let counter = queries.clone();
+
+        // The query is held up until everyone else is waiting for it
+        let (release, released) = std::sync::mpsc::channel::<()>();
+        let released = std::sync::Mutex::new(released);
+
+        let mut context = create_test_context(Box::new(move |qname, _, _, _| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            let _ = released.lock().unwrap().recv();
+
+            let mut packet = DnsPacket::new();
+            packet.answers.push(DnsRecord::A {
+                domain: qname.to_string(),
+                addr: "127.0.0.1".parse().unwrap(),
+                ttl: TransientTtl(3600),
+            });
+            Ok(packet)
+        }));
+
+        match Arc::get_mut(&mut context) {
+            Some(mut ctx) => {
+                ctx.resolve_strategy = ResolveStrategy::Forward {
+                    servers: vec![Forwarder::plain("127.0.0.1:53".parse().unwrap())],
+                    order: ForwardOrder::Ordered,
+                };
+            }
+            None => panic!(),
+        }
+
+        // Clients asking for the same name at once share a single query
+        let barrier = Arc::new(std::sync::Barrier::new(5));
+        let threads = (0..5)
+            .map(|_| {
+                let context = context.clone();
+                let barrier = barrier.clone();
+                std::thread::spawn(move || {
+                    barrier.wait();
+                    let mut resolver = context.create_resolver(context.clone());
+                    resolver.resolve("google.com", QueryType::A, true)
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while context.inflight.get_coalesced_count() < 4 && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        release.send(()).unwrap();
+
+        for thread in threads {
+            assert_eq!(1, thread.join().unwrap().unwrap().answers.len());
+        }
+
+        assert_eq!(1, queries.load(Ordering::SeqCst));
+        assert_eq!(4, context.inflight.get_coalesced_count());
+    }
+
+    #[test]
+    fn test_routed_resolver() {
+        let context = create_test_context(Box::new(|qname, _, (server, port), _| {
+            let addr = match (server, port) {
+                ("10.0.0.1", 53) => "10.1.1.1",
+                ("127.0.0.1", 8600) => "10.2.2.2",
+                _ => return Err(crate::dns::client::ClientError::TimeOut),
+            };
+
+            let mut packet = DnsPacket::new();
+            packet.answers.push(DnsRecord::A {
+                domain: qname.to_string(),
+                addr: addr.parse().unwrap(),
+                ttl: TransientTtl(3600),
+            });
+            Ok(packet)
+        }));
+
+        let _ = context.routes.add(
+            "corp.example",
+            RouteTarget::Forward(vec![Forwarder::plain("10.0.0.1:53".parse().unwrap())]),
+        );
+        let _ = context.routes.add(
+            "consul",
+            RouteTarget::Forward(vec![Forwarder::plain("127.0.0.1:8600".parse().unwrap())]),
+        );
+
+        let mut resolver = context.create_resolver(context.clone());
+
+        let res = resolver
+            .resolve("intranet.corp.example", QueryType::A, true)
+            .unwrap();
+        assert_eq!(
+            vec![DnsRecord::A {
+                domain: "intranet.corp.example".to_string(),
+                addr: "10.1.1.1".parse().unwrap(),
+                ttl: TransientTtl(3600),
+            }],
+            res.answers
+        );
+
+        let res = resolver
+            .resolve("web.service.consul", QueryType::A, true)
+            .unwrap();
+        assert_eq!(
+            vec![DnsRecord::A {
+                domain: "web.service.consul".to_string(),
+                addr: "10.2.2.2".parse().unwrap(),
+                ttl: TransientTtl(3600),
+            }],
+            res.answers
+        );
+
+        // Everything else is left to the recursive resolver, which has no
+        // root servers to start from
+        match resolver.resolve("google.com", QueryType::A, true) {
+            Err(ResolveError::NoServerFound) => {}
+            _ => panic!(),
+        }
+
+        // Rules can be removed while running
+        let _ = context.routes.remove("consul");
+        match resolver.resolve("db.service.consul", QueryType::A, true) {
+            Err(ResolveError::NoServerFound) => {}
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_recursive_route_while_forwarding() {
+        let mut context = create_test_context(Box::new(|qname, _, (server, _), recursive| {
+            let mut packet = DnsPacket::new();
+            match server {
+                // The forwarder
+                "10.0.0.1" if recursive => packet.answers.push(DnsRecord::A {
+                    domain: qname.to_string(),
+                    addr: "10.1.1.1".parse().unwrap(),
+                    ttl: TransientTtl(3600),
+                }),
+                // The root name server
+                "198.41.0.4" if !recursive => packet.answers.push(DnsRecord::A {
+                    domain: qname.to_string(),
+                    addr: "127.0.0.1".parse().unwrap(),
+                    ttl: TransientTtl(3600),
+                }),
+                _ => return Err(crate::dns::client::ClientError::TimeOut),
+            }
+            Ok(packet)
+        }));
+
+        let hints = vec![
+            DnsRecord::NS {
+                domain: "".to_string(),
+                host: "a.root-servers.net".to_string(),
+                ttl: TransientTtl(3600),
+            },
+            DnsRecord::A {
+                domain: "a.root-servers.net".to_string(),
+                addr: "198.41.0.4".parse().unwrap(),
+                ttl: TransientTtl(3600),
+            },
+        ];
+
+        match Arc::get_mut(&mut context) {
+            Some(mut ctx) => {
+                ctx.resolve_strategy = ResolveStrategy::Forward {
+                    servers: vec![Forwarder::plain("10.0.0.1:53".parse().unwrap())],
+                    order: ForwardOrder::Ordered,
+                };
+                ctx.qname_minimisation = false;
+            }
+            None => panic!(),
+        }
+
+        let request = || crate::web::routes::RouteRequest {
+            domain: "example.org".to_string(),
+            target: "recursive".to_string(),
+        };
+
+        // Without root hints there's nowhere to start recursive lookups from
+        assert!(crate::web::routes::route_create(&context, request()).is_err());
+
+        match Arc::get_mut(&mut context) {
+            Some(mut ctx) => ctx.root_hints = hints.clone(),
+            None => panic!(),
+        }
+        context.cache.store_hints(&hints).unwrap();
+        crate::web::routes::route_create(&context, request()).unwrap();
+        assert!(context.uses_recursion());
+
+        let mut resolver = context.create_resolver(context.clone());
+
+        // Names under the route are resolved from the root servers, and
+        // everything else is still forwarded
+        let res = resolver
+            .resolve("www.example.org", QueryType::A, true)
+            .unwrap();
+        assert_eq!(
+            Some("127.0.0.1".parse().unwrap()),
+            res.get_addresses().first().cloned()
+        );
+
+        let res = resolver.resolve("google.com", QueryType::A, true).unwrap();
+        assert_eq!(
+            Some("10.1.1.1".parse().unwrap()),
+            res.get_addresses().first().cloned()
+        );
     }
 
     #[test]
@@ -559,11 +1406,688 @@ mod tests {
 
             // Should have been hit two times for NS google.com and once for
             // A google.com
-            assert_eq!(3, list[1].hits);
+            assert_eq!(3, list[1].hits.get());
 
             assert_eq!("ns1.google.com", list[2].domain);
             assert_eq!(1, list[2].record_types.len());
-            assert_eq!(2, list[2].hits);
+            assert_eq!(2, list[2].hits.get());
+        };
+    }
+
+    #[test]
+    fn test_recursive_resolver_ipv6() {
+        let contacted = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let contacted_copy = contacted.clone();
+
+        let mut context = create_test_context(Box::new(move |qname, _, (server, _), _| {
+            contacted_copy.lock().unwrap().push(server.to_string());
+
+            let mut packet = DnsPacket::new();
+            match server {
+                "2001:503:ba3e::2:30" => {
+                    packet.authorities.push(DnsRecord::NS {
+                        domain: "com".to_string(),
+                        host: "a.gtld-servers.net".to_string(),
+                        ttl: TransientTtl(3600),
+                    });
+                    packet.resources.push(DnsRecord::A {
+                        domain: "a.gtld-servers.net".to_string(),
+                        addr: "192.5.6.30".parse().unwrap(),
+                        ttl: TransientTtl(3600),
+                    });
+                    packet.resources.push(DnsRecord::AAAA {
+                        domain: "a.gtld-servers.net".to_string(),
+                        addr: "2001:503:a83e::2:30".parse().unwrap(),
+                        ttl: TransientTtl(3600),
+                    });
+                }
+                "2001:503:a83e::2:30" => {
+                    packet.answers.push(DnsRecord::A {
+                        domain: qname.to_string(),
+                        addr: "127.0.0.1".parse().unwrap(),
+                        ttl: TransientTtl(3600),
+                    });
+                }
+                _ => packet.header.rescode = ResultCode::SERVFAIL,
+            }
+
+            Ok(packet)
+        }));
+
+        match Arc::get_mut(&mut context) {
+            Some(mut ctx) => {
+                ctx.ip_preference = IpPreference::V6Only;
+            }
+            None => panic!(),
+        }
+
+        let _ = context.cache.store_hints(&[
+            DnsRecord::NS {
+                domain: "".to_string(),
+                host: "a.root-servers.net".to_string(),
+                ttl: TransientTtl(3600),
+            },
+            DnsRecord::A {
+                domain: "a.root-servers.net".to_string(),
+                addr: "198.41.0.4".parse().unwrap(),
+                ttl: TransientTtl(3600),
+            },
+            DnsRecord::AAAA {
+                domain: "a.root-servers.net".to_string(),
+                addr: "2001:503:ba3e::2:30".parse().unwrap(),
+                ttl: TransientTtl(3600),
+            },
+        ]);
+
+        let mut resolver = context.create_resolver(context.clone());
+
+        // Both the root hint and the glue are used for their IPv6 addresses
+        let res = resolver.resolve("google.com", QueryType::A, true).unwrap();
+        assert_eq!(1, res.answers.len());
+        assert_eq!(
+            vec!["2001:503:ba3e::2:30", "2001:503:a83e::2:30"],
+            *contacted.lock().unwrap()
+        );
+
+        let addrs = vec![
+            "192.5.6.30".parse().unwrap(),
+            "2001:503:a83e::2:30".parse().unwrap(),
+        ];
+        // Both servers were timed
+        let infra = context.infra.list().unwrap();
+        assert_eq!(2, infra.len());
+        assert!(infra.iter().all(|(_, stats)| stats.successes == 1));
+
+        assert_eq!(Some(addrs[1]), pick_address(&context, &addrs));
+        assert_eq!(None, pick_address(&context, &addrs[..1]));
+    }
+
+    #[test]
+    fn test_recursive_resolver_unusable_glue() {
+        let contacted = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let contacted_copy = contacted.clone();
+
+        let mut context = create_test_context(Box::new(move |qname, _, (server, _), _| {
+            contacted_copy.lock().unwrap().push(server.to_string());
+
+            let mut packet = DnsPacket::new();
+            match server {
+                "198.41.0.4" if qname == "a.gtld-servers.net" => {
+                    packet.answers.push(DnsRecord::A {
+                        domain: qname.to_string(),
+                        addr: "192.5.6.30".parse().unwrap(),
+                        ttl: TransientTtl(3600),
+                    });
+                }
+                // The name server of the referral only comes with IPv6 glue
+                "198.41.0.4" => {
+                    packet.authorities.push(DnsRecord::NS {
+                        domain: "com".to_string(),
+                        host: "a.gtld-servers.net".to_string(),
+                        ttl: TransientTtl(3600),
+                    });
+                    packet.resources.push(DnsRecord::AAAA {
+                        domain: "a.gtld-servers.net".to_string(),
+                        addr: "2001:503:a83e::2:30".parse().unwrap(),
+                        ttl: TransientTtl(3600),
+                    });
+                }
+                "192.5.6.30" => {
+                    packet.answers.push(DnsRecord::A {
+                        domain: qname.to_string(),
+                        addr: "127.0.0.1".parse().unwrap(),
+                        ttl: TransientTtl(3600),
+                    });
+                }
+                _ => packet.header.rescode = ResultCode::SERVFAIL,
+            }
+
+            Ok(packet)
+        }));
+
+        match Arc::get_mut(&mut context) {
+            Some(mut ctx) => {
+                ctx.qname_minimisation = false;
+            }
+            None => panic!(),
+        }
+
+        let _ = context.cache.store_hints(&[
+            DnsRecord::NS {
+                domain: "".to_string(),
+                host: "a.root-servers.net".to_string(),
+                ttl: TransientTtl(3600),
+            },
+            DnsRecord::A {
+                domain: "a.root-servers.net".to_string(),
+                addr: "198.41.0.4".parse().unwrap(),
+                ttl: TransientTtl(3600),
+            },
+        ]);
+
+        let mut resolver = context.create_resolver(context.clone());
+
+        // Only reaching name servers over IPv4, the IPv4 address of the name
+        // server has to be looked up
+        let res = resolver.resolve("google.com", QueryType::A, true).unwrap();
+        assert_eq!(1, res.answers.len());
+        assert_eq!(
+            vec!["198.41.0.4", "198.41.0.4", "192.5.6.30"],
+            *contacted.lock().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_recursive_resolver_failover() {
+        let contacted = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let contacted_copy = contacted.clone();
+
+        let mut context = create_test_context(Box::new(move |qname, _, (server, _), _| {
+            contacted_copy.lock().unwrap().push(server.to_string());
+
+            let mut packet = DnsPacket::new();
+            match server {
+                // The first root server never answers
+                "198.41.0.4" => return Err(crate::dns::client::ClientError::TimeOut),
+                // The second refers to a server without glue, and one that
+                // refuses to answer
+                "199.9.14.201" if qname == "google.com" => {
+                    packet.authorities.push(DnsRecord::NS {
+                        domain: "com".to_string(),
+                        host: "a.gtld-servers.net".to_string(),
+                        ttl: TransientTtl(3600),
+                    });
+                    packet.authorities.push(DnsRecord::NS {
+                        domain: "com".to_string(),
+                        host: "b.gtld-servers.net".to_string(),
+                        ttl: TransientTtl(3600),
+                    });
+                    packet.resources.push(DnsRecord::A {
+                        domain: "a.gtld-servers.net".to_string(),
+                        addr: "192.5.6.30".parse().unwrap(),
+                        ttl: TransientTtl(3600),
+                    });
+                }
+                "199.9.14.201" if qname == "b.gtld-servers.net" => {
+                    packet.answers.push(DnsRecord::A {
+                        domain: qname.to_string(),
+                        addr: "192.33.14.30".parse().unwrap(),
+                        ttl: TransientTtl(3600),
+                    });
+                }
+                "192.5.6.30" => packet.header.rescode = ResultCode::REFUSED,
+                "192.33.14.30" => {
+                    packet.answers.push(DnsRecord::A {
+                        domain: qname.to_string(),
+                        addr: "127.0.0.1".parse().unwrap(),
+                        ttl: TransientTtl(3600),
+                    });
+                }
+                _ => packet.header.rescode = ResultCode::SERVFAIL,
+            }
+
+            Ok(packet)
+        }));
+
+        let _ = context.cache.store_hints(&[
+            DnsRecord::NS {
+                domain: "".to_string(),
+                host: "a.root-servers.net".to_string(),
+                ttl: TransientTtl(3600),
+            },
+            DnsRecord::NS {
+                domain: "".to_string(),
+                host: "b.root-servers.net".to_string(),
+                ttl: TransientTtl(3600),
+            },
+            DnsRecord::A {
+                domain: "a.root-servers.net".to_string(),
+                addr: "198.41.0.4".parse().unwrap(),
+                ttl: TransientTtl(3600),
+            },
+            DnsRecord::A {
+                domain: "b.root-servers.net".to_string(),
+                addr: "199.9.14.201".parse().unwrap(),
+                ttl: TransientTtl(3600),
+            },
+        ]);
+
+        // The second root server is known to be slow, so that the first one
+        // is always tried before it
+        context
+            .infra
+            .record_success("199.9.14.201".parse().unwrap(), Duration::from_millis(1000));
+
+        let mut resolver = context.create_resolver(context.clone());
+
+        let res = resolver.resolve("google.com", QueryType::A, true).unwrap();
+        assert_eq!(ResultCode::NOERROR, res.header.rescode);
+        assert_eq!(1, res.answers.len());
+
+        // Looking up the name server without glue along the way isn't counted
+        // as a query of its own
+        assert_eq!(1, context.statistics.get_cache_miss_count());
+        assert_eq!(0, context.statistics.get_cache_hit_count());
+
+        // Every server was tried, and the ones that failed were penalized
+        let contacted = contacted.lock().unwrap();
+        assert!(contacted.contains(&"198.41.0.4".to_string()));
+        assert!(contacted.contains(&"192.5.6.30".to_string()));
+        assert_eq!(Some(&"192.33.14.30".to_string()), contacted.last());
+
+        for failed in &["198.41.0.4", "192.5.6.30"] {
+            let failed: IpAddr = failed.parse().unwrap();
+            assert!(context.infra.get_rtt(&failed) > crate::dns::infra::UNKNOWN_RTT);
+        }
+    }
+
+    #[test]
+    fn test_qname_minimisation() {
+        let asked = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let asked_copy = asked.clone();
+
+        let context = create_test_context(Box::new(move |qname, qtype, (server, _), _| {
+            asked_copy
+                .lock()
+                .unwrap()
+                .push((server.to_string(), qname.to_string(), qtype));
+
+            let referral = |packet: &mut DnsPacket, zone: &str, addr: &str| {
+                packet.authorities.push(DnsRecord::NS {
+                    domain: zone.to_string(),
+                    host: format!("ns.{}", zone),
+                    ttl: TransientTtl(3600),
+                });
+                packet.resources.push(DnsRecord::A {
+                    domain: format!("ns.{}", zone),
+                    addr: addr.parse().unwrap(),
+                    ttl: TransientTtl(3600),
+                });
+            };
+
+            let mut packet = DnsPacket::new();
+            match (server, qname) {
+                ("127.0.0.1", "com") => referral(&mut packet, "com", "127.0.0.2"),
+                ("127.0.0.1", "org") => referral(&mut packet, "org", "127.0.0.4"),
+                ("127.0.0.2", "example.com") => referral(&mut packet, "example.com", "127.0.0.3"),
+                ("127.0.0.3", "dev.example.com") => {
+                    packet.authorities.push(DnsRecord::SOA {
+                        domain: "example.com".to_string(),
+                        m_name: "ns.example.com".to_string(),
+                        r_name: "hostmaster.example.com".to_string(),
+                        serial: 1,
+                        refresh: 3600,
+                        retry: 3600,
+                        expire: 3600,
+                        minimum: 3600,
+                        ttl: TransientTtl(3600),
+                    });
+                }
+                ("127.0.0.3", "www.dev.example.com") | ("127.0.0.4", "www.example.org") => {
+                    packet.answers.push(DnsRecord::AAAA {
+                        domain: qname.to_string(),
+                        addr: "::1".parse().unwrap(),
+                        ttl: TransientTtl(3600),
+                    });
+                }
+                _ => packet.header.rescode = ResultCode::NXDOMAIN,
+            }
+
+            Ok(packet)
+        }));
+
+        let _ = context.cache.store_hints(&[
+            DnsRecord::NS {
+                domain: "".to_string(),
+                host: "a.root-servers.net".to_string(),
+                ttl: TransientTtl(3600),
+            },
+            DnsRecord::A {
+                domain: "a.root-servers.net".to_string(),
+                addr: "127.0.0.1".parse().unwrap(),
+                ttl: TransientTtl(3600),
+            },
+        ]);
+
+        let mut resolver = context.create_resolver(context.clone());
+
+        // Each server only learns about one more label than it's responsible
+        // for, and the type is only revealed to the last one
+        let res = resolver
+            .resolve("www.dev.example.com", QueryType::AAAA, true)
+            .unwrap();
+        assert_eq!(1, res.answers.len());
+
+        let expected = vec![
+            ("127.0.0.1", "com", QueryType::A),
+            ("127.0.0.2", "example.com", QueryType::A),
+            ("127.0.0.3", "dev.example.com", QueryType::A),
+            ("127.0.0.3", "www.dev.example.com", QueryType::AAAA),
+        ];
+        let to_owned = |x: &[(&str, &str, QueryType)]| {
+            x.iter()
+                .map(|(server, qname, qtype)| (server.to_string(), qname.to_string(), *qtype))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(to_owned(&expected), *asked.lock().unwrap());
+
+        // A server that denies the existence of an intermediate name is asked
+        // for the full name instead
+        asked.lock().unwrap().clear();
+
+        let res = resolver
+            .resolve("www.example.org", QueryType::AAAA, true)
+            .unwrap();
+        assert_eq!(1, res.answers.len());
+
+        let expected = vec![
+            ("127.0.0.1", "org", QueryType::A),
+            ("127.0.0.4", "example.org", QueryType::A),
+            ("127.0.0.4", "www.example.org", QueryType::AAAA),
+        ];
+        assert_eq!(to_owned(&expected), *asked.lock().unwrap());
+    }
+
+    #[test]
+    fn test_recursive_resolver_timeout() {
+        let sent = Arc::new(AtomicUsize::new(0));
+        let sent_copy = sent.clone();
+        let mut context = create_test_context(Box::new(move |_, _, _, _| {
+            sent_copy.fetch_add(1, Ordering::SeqCst);
+            Err(crate::dns::client::ClientError::TimeOut)
+        }));
+
+        match Arc::get_mut(&mut context) {
+            Some(mut ctx) => {
+                ctx.resolve_timeout = 0;
+            }
+            None => panic!(),
+        }
+
+        let _ = context.cache.store_hints(&[
+            DnsRecord::NS {
+                domain: "".to_string(),
+                host: "a.root-servers.net".to_string(),
+                ttl: TransientTtl(3600),
+            },
+            DnsRecord::A {
+                domain: "a.root-servers.net".to_string(),
+                addr: "198.41.0.4".parse().unwrap(),
+                ttl: TransientTtl(3600),
+            },
+        ]);
+
+        let mut resolver = context.create_resolver(context.clone());
+
+        // Nothing is sent once the time is up
+        match resolver.resolve("google.com", QueryType::A, true) {
+            Err(ResolveError::TimeOut { .. }) => {}
+            _ => panic!(),
+        }
+        assert_eq!(0, sent.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_recursive_resolver_budget() {
+        let root_hints = |context: &ServerContext, count: u8| {
+            for i in 0..count {
+                let host = format!("{}.root-servers.net", (b'a' + i) as char);
+                let _ = context.cache.store_hints(&[
+                    DnsRecord::NS {
+                        domain: "".to_string(),
+                        host: host.clone(),
+                        ttl: TransientTtl(3600),
+                    },
+                    DnsRecord::A {
+                        domain: host,
+                        addr: format!("127.0.0.{}", i + 1).parse().unwrap(),
+                        ttl: TransientTtl(3600),
+                    },
+                ]);
+            }
+        };
+
+        // Every name is delegated one label further down, without end
+        let mut context = create_test_context(Box::new(|qname, _, _, _| {
+            let mut packet = DnsPacket::new();
+            packet.authorities.push(DnsRecord::NS {
+                domain: qname.to_string(),
+                host: format!("ns.{}", qname),
+                ttl: TransientTtl(3600),
+            });
+            packet.resources.push(DnsRecord::A {
+                domain: format!("ns.{}", qname),
+                addr: "127.0.0.1".parse().unwrap(),
+                ttl: TransientTtl(3600),
+            });
+            Ok(packet)
+        }));
+        Arc::get_mut(&mut context).unwrap().max_referrals = 2;
+        root_hints(&context, 1);
+
+        let mut resolver = context.create_resolver(context.clone());
+        match resolver.resolve("a.b.c.d", QueryType::A, true) {
+            Err(e @ ResolveError::TooManyReferrals { limit: 2 }) => {
+                assert_eq!("Gave up after following 2 referrals", e.to_string());
+            }
+            _ => panic!(),
+        }
+
+        // The name servers of each TLD are only to be found in the next one
+        let mut context = create_test_context(Box::new(|qname, _, _, _| {
+            let (zone, host) = match qname.rsplit('.').next() {
+                Some("com") => ("com", "ns.example.net"),
+                Some("net") => ("net", "ns.example.org"),
+                _ => ("org", "ns.example.com"),
+            };
+
+            let mut packet = DnsPacket::new();
+            packet.authorities.push(DnsRecord::NS {
+                domain: zone.to_string(),
+                host: host.to_string(),
+                ttl: TransientTtl(3600),
+            });
+            Ok(packet)
+        }));
+        Arc::get_mut(&mut context).unwrap().max_nested_lookups = 3;
+        root_hints(&context, 1);
+
+        let mut resolver = context.create_resolver(context.clone());
+        match resolver.resolve("google.com", QueryType::A, true) {
+            Err(ResolveError::TooManyNestedLookups { limit: 3 }) => {}
+            _ => panic!(),
+        }
+
+        // None of the root servers answer
+        let sent = Arc::new(AtomicUsize::new(0));
+        let sent_copy = sent.clone();
+        let mut context = create_test_context(Box::new(move |_, _, _, _| {
+            sent_copy.fetch_add(1, Ordering::SeqCst);
+            Err(crate::dns::client::ClientError::TimeOut)
+        }));
+        Arc::get_mut(&mut context).unwrap().max_upstream_queries = 3;
+        root_hints(&context, 5);
+
+        let mut resolver = context.create_resolver(context.clone());
+        match resolver.resolve("google.com", QueryType::A, true) {
+            Err(ResolveError::TooManyQueries { limit: 3 }) => {}
+            _ => panic!(),
+        }
+        assert_eq!(3, sent.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_serve_stale() {
+        let queries = Arc::new(AtomicUsize::new(0));
+        let queries_copy = queries.clone();
+
+        let mut context = create_test_context(Box::new(move |_, _, _, _| {
+            queries_copy.fetch_add(1, Ordering::SeqCst);
+            Err(crate::dns::client::ClientError::TimeOut)
+        }));
+
+        match Arc::get_mut(&mut context) {
+            Some(mut ctx) => {
+                ctx.resolve_strategy = ResolveStrategy::Forward {
+                    servers: vec![Forwarder::plain("127.0.0.1:53".parse().unwrap())],
+                    order: ForwardOrder::Ordered,
+                };
+            }
+            None => panic!(),
+        }
+
+        let _ = context.cache.store(&[DnsRecord::A {
+            domain: "google.com".to_string(),
+            addr: "127.0.0.1".parse().unwrap(),
+            ttl: TransientTtl(0),
+        }]);
+
+        std::thread::sleep(std::time::Duration::from_millis(10));
+
+        let mut resolver = context.create_resolver(context.clone());
+
+        // The upstream is unreachable, so the expired record is used instead
+        let res = match resolver.resolve("google.com", QueryType::A, true) {
+            Ok(x) => x,
+            Err(_) => panic!(),
+        };
+
+        assert_eq!(1, res.answers.len());
+        assert_eq!(crate::dns::cache::STALE_TTL, res.answers[0].get_ttl());
+        assert_eq!(1, queries.load(Ordering::SeqCst));
+
+        // Having just failed, the upstream isn't asked again for a while
+        let res = resolver.resolve("google.com", QueryType::A, true).unwrap();
+        assert_eq!(1, res.answers.len());
+        assert_eq!(1, queries.load(Ordering::SeqCst));
+
+        // Without anything cached the error is passed on
+        if let Ok(_) = resolver.resolve("yahoo.com", QueryType::A, true) {
+            panic!();
+        }
+    }
+
+    #[test]
+    fn test_serve_stale_timeout() {
+        let mut context = create_test_context(Box::new(|qname, _, _, _| {
+            std::thread::sleep(std::time::Duration::from_millis(500));
+
+            let mut packet = DnsPacket::new();
+            packet.answers.push(DnsRecord::A {
+                domain: qname.to_string(),
+                addr: "127.0.0.2".parse().unwrap(),
+                ttl: TransientTtl(3600),
+            });
+            Ok(packet)
+        }));
+
+        match Arc::get_mut(&mut context) {
+            Some(mut ctx) => {
+                ctx.resolve_strategy = ResolveStrategy::Forward {
+                    servers: vec![Forwarder::plain("127.0.0.1:53".parse().unwrap())],
+                    order: ForwardOrder::Ordered,
+                };
+                ctx.stale_answer_timeout = 50;
+            }
+            None => panic!(),
+        }
+
+        let _ = context.cache.store(&[DnsRecord::A {
+            domain: "google.com".to_string(),
+            addr: "127.0.0.1".parse().unwrap(),
+            ttl: TransientTtl(0),
+        }]);
+
+        std::thread::sleep(std::time::Duration::from_millis(10));
+
+        let mut resolver = context.create_resolver(context.clone());
+
+        // The upstream is slow to answer, so the expired record is used
+        // rather than waiting for it
+        let res = resolver.resolve("google.com", QueryType::A, true).unwrap();
+        assert_eq!(crate::dns::cache::STALE_TTL, res.answers[0].get_ttl());
+
+        // Resolving carries on, and replaces the expired record once done
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while context.cache.lookup("google.com", QueryType::A).is_none() {
+            assert!(Instant::now() < deadline);
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+
+        let res = resolver.resolve("google.com", QueryType::A, true).unwrap();
+        match res.answers[0] {
+            DnsRecord::A { addr, .. } => assert_eq!("127.0.0.2".parse::<Ipv4Addr>().unwrap(), addr),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_recursive_resolver_bailiwick() {
+        let context = create_test_context(Box::new(|qname, _, _, _| {
+            let mut packet = DnsPacket::new();
+
+            packet.answers.push(DnsRecord::A {
+                domain: qname.to_string(),
+                addr: "127.0.0.1".parse().unwrap(),
+                ttl: TransientTtl(3600),
+            });
+
+            // A server for google.com has no say over yahoo.com
+            packet.answers.push(DnsRecord::A {
+                domain: "www.yahoo.com".to_string(),
+                addr: "127.0.0.66".parse().unwrap(),
+                ttl: TransientTtl(3600),
+            });
+            packet.authorities.push(DnsRecord::NS {
+                domain: "yahoo.com".to_string(),
+                host: "ns1.google.com".to_string(),
+                ttl: TransientTtl(3600),
+            });
+            packet.resources.push(DnsRecord::A {
+                domain: "ns1.yahoo.com".to_string(),
+                addr: "127.0.0.66".parse().unwrap(),
+                ttl: TransientTtl(3600),
+            });
+
+            Ok(packet)
+        }));
+
+        let _ = context.cache.store(&[
+            DnsRecord::NS {
+                domain: "google.com".to_string(),
+                host: "ns1.google.com".to_string(),
+                ttl: TransientTtl(3600),
+            },
+            DnsRecord::A {
+                domain: "ns1.google.com".to_string(),
+                addr: "127.0.0.1".parse().unwrap(),
+                ttl: TransientTtl(3600),
+            },
+        ]);
+
+        let mut resolver = context.create_resolver(context.clone());
+
+        let res = match resolver.resolve("www.google.com", QueryType::A, true) {
+            Ok(x) => x,
+            Err(_) => panic!(),
         };
+
+        assert_eq!(1, res.answers.len());
+        assert!(res.authorities.is_empty());
+        assert!(res.resources.is_empty());
+
+        assert!(context
+            .cache
+            .lookup("www.google.com", QueryType::A)
+            .is_some());
+        assert!(context
+            .cache
+            .lookup("www.yahoo.com", QueryType::A)
+            .is_none());
+        assert!(context.cache.lookup("yahoo.com", QueryType::NS).is_none());
+        assert!(context
+            .cache
+            .lookup("ns1.yahoo.com", QueryType::A)
+            .is_none());
     }
 }
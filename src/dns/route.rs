@@ -0,0 +1,232 @@
+//! rules for sending the queries for some domains somewhere other than the
+//! resolver used for everything else
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::RwLock;
+
+use derive_more::{Display, Error, From};
+
+use crate::dns::forward::{parse_forwarder, Forwarder};
+use crate::dns::protocol::is_subdomain;
+
+#[derive(Debug, Display, From, Error)]
+pub enum RouteError {
+    PoisonedLock,
+}
+
+type Result<T> = std::result::Result<T, RouteError>;
+
+/// Where the queries for a domain are sent
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RouteTarget {
+    /// Resolved from the root servers
+    Recursive,
+    /// Forwarded to these servers, in order
+    Forward(Vec<Forwarder>),
+}
+
+impl RouteTarget {
+    /// Parse either `recursive`, or a comma separated list of forwarders as
+    /// accepted by `parse_forwarder`
+    pub fn parse(s: &str) -> Option<RouteTarget> {
+        if s == "recursive" {
+            return Some(RouteTarget::Recursive);
+        }
+
+        let servers = s
+            .split(',')
+            .map(|x| parse_forwarder(x.trim()))
+            .collect::<Option<Vec<_>>>()?;
+
+        if servers.is_empty() {
+            None
+        } else {
+            Some(RouteTarget::Forward(servers))
+        }
+    }
+}
+
+impl fmt::Display for RouteTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RouteTarget::Recursive => write!(f, "recursive"),
+            RouteTarget::Forward(ref servers) => {
+                let servers = servers.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+                write!(f, "{}", servers.join(","))
+            }
+        }
+    }
+}
+
+/// Parse a rule of the form `DOMAIN=TARGET`, such as `consul=127.0.0.1:8600`
+pub fn parse_rule(s: &str) -> Option<(String, RouteTarget)> {
+    let mut parts = s.splitn(2, '=');
+    let domain = normalize(parts.next()?);
+    let target = RouteTarget::parse(parts.next()?)?;
+
+    if domain.is_empty() {
+        return None;
+    }
+
+    Some((domain, target))
+}
+
+/// The form that domains given in the configuration are compared in,
+/// lowercase and without a trailing dot
+pub fn normalize(domain: &str) -> String {
+    domain.trim().trim_matches('.').to_lowercase()
+}
+
+#[derive(Default)]
+pub struct Router {
+    routes: RwLock<BTreeMap<String, RouteTarget>>,
+}
+
+impl Router {
+    pub fn new() -> Router {
+        Router::default()
+    }
+
+    /// Send the queries for a domain and everything below it to `target`,
+    /// replacing any rule already in place for it
+    pub fn add(&self, domain: &str, target: RouteTarget) -> Result<()> {
+        let mut routes = self.routes.write().map_err(|_| RouteError::PoisonedLock)?;
+        routes.insert(normalize(domain), target);
+
+        Ok(())
+    }
+
+    /// Whether any domain is resolved from the root servers
+    pub fn has_recursive(&self) -> bool {
+        match self.routes.read() {
+            Ok(routes) => routes.values().any(|x| *x == RouteTarget::Recursive),
+            Err(_) => false,
+        }
+    }
+
+    /// Remove the rule for a domain, returning whether there was one
+    pub fn remove(&self, domain: &str) -> Result<bool> {
+        let mut routes = self.routes.write().map_err(|_| RouteError::PoisonedLock)?;
+
+        Ok(routes.remove(&normalize(domain)).is_some())
+    }
+
+    /// Find the rule for the closest domain to `qname`, if there is one
+    pub fn lookup(&self, qname: &str) -> Option<RouteTarget> {
+        let routes = self.routes.read().ok()?;
+        if routes.is_empty() {
+            return None;
+        }
+
+        let qname = qname.to_lowercase();
+        routes
+            .iter()
+            .filter(|(domain, _)| is_subdomain(&qname, domain))
+            .max_by_key(|(domain, _)| domain.len())
+            .map(|(_, target)| target.clone())
+    }
+
+    pub fn list(&self) -> Result<Vec<(String, RouteTarget)>> {
+        let routes = self.routes.read().map_err(|_| RouteError::PoisonedLock)?;
+
+        Ok(routes
+            .iter()
+            .map(|(domain, target)| (domain.clone(), target.clone()))
+            .collect())
+    }
+
+    /// Every server that some rule forwards to
+    pub fn forwarders(&self) -> Vec<Forwarder> {
+        let mut servers = Vec::new();
+        if let Ok(routes) = self.routes.read() {
+            for target in routes.values() {
+                if let RouteTarget::Forward(ref forwarders) = *target {
+                    for forwarder in forwarders {
+                        if !servers.contains(forwarder) {
+                            servers.push(forwarder.clone());
+                        }
+                    }
+                }
+            }
+        }
+
+        servers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_parse_rule() {
+        assert_eq!(
+            Some((
+                "consul".to_string(),
+                RouteTarget::Forward(vec![Forwarder::plain("127.0.0.1:8600".parse().unwrap())])
+            )),
+            parse_rule("consul=127.0.0.1:8600")
+        );
+        assert_eq!(
+            Some((
+                "corp.example".to_string(),
+                RouteTarget::Forward(vec![
+                    Forwarder::plain("10.0.0.1:53".parse().unwrap()),
+                    parse_forwarder("tls://[2001:db8::1]:853#dns.corp.example").unwrap()
+                ])
+            )),
+            parse_rule("Corp.Example.=10.0.0.1, tls://2001:db8::1#dns.corp.example")
+        );
+        assert_eq!(
+            Some(("ext.corp.example".to_string(), RouteTarget::Recursive)),
+            parse_rule("ext.corp.example=recursive")
+        );
+
+        assert_eq!(None, parse_rule("consul"));
+        assert_eq!(None, parse_rule("=10.0.0.1"));
+        assert_eq!(None, parse_rule("consul=localhost"));
+        assert_eq!(None, parse_rule("consul="));
+    }
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!("corp.example", normalize("Corp.Example."));
+        assert_eq!("corp.example", normalize(" corp.example "));
+        assert_eq!("", normalize("."));
+    }
+
+    #[test]
+    fn test_lookup() {
+        let router = Router::new();
+        let office = RouteTarget::Forward(vec![Forwarder::plain("10.0.0.1:53".parse().unwrap())]);
+
+        assert_eq!(None, router.lookup("corp.example"));
+
+        router.add("corp.example", office.clone()).unwrap();
+        router
+            .add("ext.corp.example", RouteTarget::Recursive)
+            .unwrap();
+
+        // The most specific rule wins, and only whole labels match
+        assert_eq!(Some(office.clone()), router.lookup("corp.example"));
+        assert_eq!(Some(office.clone()), router.lookup("WWW.Corp.Example"));
+        assert_eq!(
+            Some(RouteTarget::Recursive),
+            router.lookup("www.ext.corp.example")
+        );
+        assert_eq!(None, router.lookup("notcorp.example"));
+        assert_eq!(None, router.lookup("example"));
+
+        assert_eq!(
+            vec![Forwarder::plain("10.0.0.1:53".parse().unwrap())],
+            router.forwarders()
+        );
+
+        assert!(router.remove("corp.example.").unwrap());
+        assert!(!router.remove("corp.example").unwrap());
+        assert_eq!(None, router.lookup("www.corp.example"));
+        assert_eq!(1, router.list().unwrap().len());
+    }
+}
@@ -58,32 +58,134 @@ pub trait DnsServer {
     fn run_server(self) -> Result<()>;
 }
 
-/// Utility function for resolving domains referenced in for example CNAME or SRV
-/// records. This usually spares the client from having to perform additional
-/// lookups.
+/// The most names to resolve when following a chain of CNAME records
+const MAX_CNAME_CHAIN: usize = 10;
+
+/// Utility function for resolving the targets of CNAME records that a response
+/// leaves unresolved, with the type of record originally asked for. This
+/// usually spares the client from having to perform additional lookups.
+/// Returns false if the chain can't be completed, because it loops back on
+/// itself or is longer than `MAX_CNAME_CHAIN`.
 fn resolve_cnames(
-    lookup_list: &[DnsRecord],
+    qname: &str,
+    qtype: QueryType,
     results: &mut Vec<DnsPacket>,
     resolver: &mut Box<dyn DnsResolver>,
-    depth: u16,
-) {
-    if depth > 10 {
-        return;
+) -> bool {
+    if qtype == QueryType::CNAME {
+        return true;
     }
 
-    for ref rec in lookup_list {
-        match **rec {
-            DnsRecord::CNAME { ref host, .. } | DnsRecord::SRV { ref host, .. } => {
-                if let Ok(result2) = resolver.resolve(host, QueryType::A, true) {
-                    let new_unmatched = result2.get_unresolved_cnames();
-                    results.push(result2);
+    let mut visited = vec![qname.to_lowercase()];
 
-                    resolve_cnames(&new_unmatched, results, resolver, depth + 1);
+    let mut idx = 0;
+    while idx < results.len() {
+        let unresolved = results[idx].get_unresolved_cnames(qtype);
+        idx += 1;
+
+        for rec in unresolved {
+            let host = match rec {
+                DnsRecord::CNAME { host, .. } => host,
+                _ => continue,
+            };
+
+            if visited.contains(&host.to_lowercase()) {
+                println!("CNAME loop at {} while resolving {}", host, qname);
+                return false;
+            }
+
+            if visited.len() > MAX_CNAME_CHAIN {
+                println!("CNAME chain too long while resolving {}", qname);
+                return false;
+            }
+
+            visited.push(host.to_lowercase());
+
+            match resolver.resolve_internal(&host, qtype) {
+                Ok(result) => results.push(result),
+                Err(e) => println!("Failed to resolve {:?} {}: {:?}", qtype, host, e),
+            }
+        }
+    }
+
+    // Records pointing at each other within a single response go around in
+    // circles without anything having to be resolved
+    if has_cname_loop(qname, results) {
+        println!("CNAME loop while resolving {}", qname);
+        return false;
+    }
+
+    true
+}
+
+/// Whether following the CNAME records of the responses from `qname` leads
+/// back to a name already passed
+fn has_cname_loop(qname: &str, results: &[DnsPacket]) -> bool {
+    let mut visited = Vec::new();
+    let mut name = qname.to_lowercase();
+
+    loop {
+        if visited.contains(&name) {
+            return true;
+        }
+
+        let next = results
+            .iter()
+            .flat_map(|result| result.answers.iter())
+            .find_map(|rec| match *rec {
+                DnsRecord::CNAME {
+                    ref domain,
+                    ref host,
+                    ..
+                } if domain.eq_ignore_ascii_case(&name) => Some(host.to_lowercase()),
+                _ => None,
+            });
+
+        visited.push(name);
+        match next {
+            Some(x) => name = x,
+            None => return false,
+        }
+    }
+}
+
+/// Look up the addresses of the targets of SRV records, for the additional
+/// section, unless the response already included them
+fn resolve_srv_targets(
+    results: &[DnsPacket],
+    resolver: &mut Box<dyn DnsResolver>,
+) -> Vec<DnsRecord> {
+    let mut targets: Vec<String> = Vec::new();
+    for result in results {
+        for rec in &result.answers {
+            if let DnsRecord::SRV { ref host, .. } = *rec {
+                let included = result.resources.iter().any(|rsrc| match rsrc.get_domain() {
+                    Some(ref domain) => domain.eq_ignore_ascii_case(host),
+                    None => false,
+                });
+
+                if !host.is_empty() && !included && !targets.contains(host) {
+                    targets.push(host.clone());
                 }
             }
-            _ => {}
         }
     }
+
+    let mut addresses = Vec::new();
+    for target in &targets {
+        for qtype in &[QueryType::A, QueryType::AAAA] {
+            if let Ok(result) = resolver.resolve_internal(target, *qtype) {
+                addresses.extend(
+                    result
+                        .answers
+                        .into_iter()
+                        .filter(|rec| rec.get_querytype() == *qtype),
+                );
+            }
+        }
+    }
+
+    addresses
 }
 
 /// Perform the actual work for a query
@@ -118,18 +220,29 @@ pub fn execute_query(context: Arc<ServerContext>, request: &DnsPacket) -> DnsPac
             request.header.recursion_desired,
         ) {
             Ok(result) => {
-                let rescode = result.header.rescode;
-
-                let unmatched = result.get_unresolved_cnames();
+                let mut rescode = result.header.rescode;
                 results.push(result);
 
-                resolve_cnames(&unmatched, &mut results, &mut resolver, 0);
+                // A chain that can't be followed to its end is no answer, the
+                // same as with any other resolver
+                if !resolve_cnames(&question.name, question.qtype, &mut results, &mut resolver) {
+                    results.clear();
+                    rescode = ResultCode::SERVFAIL;
+                }
+
+                // A name at the end of the chain that doesn't exist means
+                // that there's no answer to the question (RFC 6604)
+                if results.len() > 1
+                    && results.last().map(|x| x.header.rescode) == Some(ResultCode::NXDOMAIN)
+                {
+                    rescode = ResultCode::NXDOMAIN;
+                }
 
                 rescode
             }
             Err(err) => {
                 println!(
-                    "Failed to resolve {:?} {}: {:?}",
+                    "Failed to resolve {:?} {}: {}",
                     question.qtype, question.name, err
                 );
                 ResultCode::SERVFAIL
@@ -138,6 +251,8 @@ pub fn execute_query(context: Arc<ServerContext>, request: &DnsPacket) -> DnsPac
 
         packet.header.rescode = rescode;
 
+        let srv_addresses = resolve_srv_targets(&results, &mut resolver);
+
         for result in results {
             for rec in result.answers {
                 packet.answers.push(rec);
@@ -149,8 +264,11 @@ pub fn execute_query(context: Arc<ServerContext>, request: &DnsPacket) -> DnsPac
                 packet.resources.push(rec);
             }
         }
+        packet.resources.extend(srv_addresses);
     }
 
+    context.statistics.record_response(packet.header.rescode);
+
     packet
 }
 
@@ -426,6 +544,7 @@ mod tests {
 
     use crate::dns::context::tests::create_test_context;
     use crate::dns::context::ResolveStrategy;
+    use crate::dns::forward::{ForwardOrder, Forwarder};
 
     fn build_query(qname: &str, qtype: QueryType) -> DnsPacket {
         let mut query_packet = DnsPacket::new();
@@ -450,7 +569,7 @@ mod tests {
                     addr: "127.0.0.1".parse::<Ipv4Addr>().unwrap(),
                     ttl: TransientTtl(3600),
                 });
-            } else if qname == "www.facebook.com" && qtype == QueryType::CNAME {
+            } else if qname == "www.facebook.com" && qtype == QueryType::A {
                 packet.answers.push(DnsRecord::CNAME {
                     domain: "www.facebook.com".to_string(),
                     host: "cdn.facebook.com".to_string(),
@@ -461,7 +580,7 @@ mod tests {
                     addr: "127.0.0.1".parse::<Ipv4Addr>().unwrap(),
                     ttl: TransientTtl(3600),
                 });
-            } else if qname == "www.microsoft.com" && qtype == QueryType::CNAME {
+            } else if qname == "www.microsoft.com" && qtype == QueryType::A {
                 packet.answers.push(DnsRecord::CNAME {
                     domain: "www.microsoft.com".to_string(),
                     host: "cdn.microsoft.com".to_string(),
@@ -483,8 +602,8 @@ mod tests {
         match Arc::get_mut(&mut context) {
             Some(mut ctx) => {
                 ctx.resolve_strategy = ResolveStrategy::Forward {
-                    host: "127.0.0.1".to_string(),
-                    port: 53,
+                    servers: vec![Forwarder::plain("127.0.0.1:53".parse().unwrap())],
+                    order: ForwardOrder::Ordered,
                 };
             }
             None => panic!(),
@@ -507,7 +626,7 @@ mod tests {
         {
             let res = execute_query(
                 context.clone(),
-                &build_query("www.facebook.com", QueryType::CNAME),
+                &build_query("www.facebook.com", QueryType::A),
             );
             assert_eq!(2, res.answers.len());
 
@@ -530,7 +649,7 @@ mod tests {
         {
             let res = execute_query(
                 context.clone(),
-                &build_query("www.microsoft.com", QueryType::CNAME),
+                &build_query("www.microsoft.com", QueryType::A),
             );
             assert_eq!(2, res.answers.len());
 
@@ -580,6 +699,16 @@ mod tests {
             assert_eq!(0, res.answers.len());
         };
 
+        // Every response is counted by its result code
+        {
+            let stats = &context.statistics;
+            assert_eq!(3, stats.get_response_count(ResultCode::NOERROR));
+            assert_eq!(1, stats.get_response_count(ResultCode::NXDOMAIN));
+            assert_eq!(1, stats.get_response_count(ResultCode::REFUSED));
+            assert_eq!(1, stats.get_response_count(ResultCode::FORMERR));
+            assert_eq!(0, stats.get_response_count(ResultCode::SERVFAIL));
+        };
+
         // Now construct a context where the dns client will return a failure
         let mut context2 = create_test_context(Box::new(|_, _, _, _| {
             Err(crate::dns::client::ClientError::Io(std::io::Error::new(
@@ -591,8 +720,8 @@ mod tests {
         match Arc::get_mut(&mut context2) {
             Some(mut ctx) => {
                 ctx.resolve_strategy = ResolveStrategy::Forward {
-                    host: "127.0.0.1".to_string(),
-                    port: 53,
+                    servers: vec![Forwarder::plain("127.0.0.1:53".parse().unwrap())],
+                    order: ForwardOrder::Ordered,
                 };
             }
             None => panic!(),
@@ -605,4 +734,162 @@ mod tests {
             assert_eq!(0, res.answers.len());
         };
     }
+
+    #[test]
+    fn test_execute_query_follows_cnames() {
+        let mut context = create_test_context(Box::new(|qname, qtype, _, _| {
+            let mut packet = DnsPacket::new();
+
+            let cname = |domain: &str, host: &str| DnsRecord::CNAME {
+                domain: domain.to_string(),
+                host: host.to_string(),
+                ttl: TransientTtl(3600),
+            };
+
+            match (qname, qtype) {
+                ("www.microsoft.com", _) => {
+                    packet
+                        .answers
+                        .push(cname("www.microsoft.com", "cdn.microsoft.com"));
+                }
+                ("cdn.microsoft.com", QueryType::A) => packet.answers.push(DnsRecord::A {
+                    domain: "cdn.microsoft.com".to_string(),
+                    addr: "127.0.0.1".parse().unwrap(),
+                    ttl: TransientTtl(3600),
+                }),
+                ("cdn.microsoft.com", QueryType::AAAA) => packet.answers.push(DnsRecord::AAAA {
+                    domain: "cdn.microsoft.com".to_string(),
+                    addr: "::1".parse().unwrap(),
+                    ttl: TransientTtl(3600),
+                }),
+                ("cdn.microsoft.com", QueryType::MX) => packet.answers.push(DnsRecord::MX {
+                    domain: "cdn.microsoft.com".to_string(),
+                    priority: 10,
+                    host: "mail.microsoft.com".to_string(),
+                    ttl: TransientTtl(3600),
+                }),
+                ("loop1.example.com", _) => {
+                    packet
+                        .answers
+                        .push(cname("loop1.example.com", "loop2.example.com"));
+                }
+                ("loop2.example.com", _) => {
+                    packet
+                        .answers
+                        .push(cname("loop2.example.com", "loop1.example.com"));
+                }
+                ("loop3.example.com", _) => {
+                    packet
+                        .answers
+                        .push(cname("loop3.example.com", "loop4.example.com"));
+                    packet
+                        .answers
+                        .push(cname("loop4.example.com", "loop3.example.com"));
+                }
+                (name, _) if name.starts_with("chain") => {
+                    let next = name[5..name.find('.').unwrap()].parse::<usize>().unwrap() + 1;
+                    packet
+                        .answers
+                        .push(cname(name, &format!("chain{}.example.com", next)));
+                }
+                ("dangling.example.com", _) => {
+                    packet
+                        .answers
+                        .push(cname("dangling.example.com", "missing.example.com"));
+                }
+                ("_sip._tcp.example.com", QueryType::SRV) => packet.answers.push(DnsRecord::SRV {
+                    domain: "_sip._tcp.example.com".to_string(),
+                    priority: 10,
+                    weight: 5,
+                    port: 5060,
+                    host: "sip.example.com".to_string(),
+                    ttl: TransientTtl(3600),
+                }),
+                ("sip.example.com", QueryType::A) => packet.answers.push(DnsRecord::A {
+                    domain: "sip.example.com".to_string(),
+                    addr: "127.0.0.2".parse().unwrap(),
+                    ttl: TransientTtl(3600),
+                }),
+                ("sip.example.com", QueryType::AAAA) => {}
+                _ => packet.header.rescode = ResultCode::NXDOMAIN,
+            }
+
+            Ok(packet)
+        }));
+
+        match Arc::get_mut(&mut context) {
+            Some(mut ctx) => {
+                ctx.resolve_strategy = ResolveStrategy::Forward {
+                    servers: vec![Forwarder::plain("127.0.0.1:53".parse().unwrap())],
+                    order: ForwardOrder::Ordered,
+                };
+            }
+            None => panic!(),
+        }
+
+        // The target of a CNAME is resolved with the type asked for
+        for qtype in &[QueryType::AAAA, QueryType::MX, QueryType::A] {
+            let res = execute_query(context.clone(), &build_query("www.microsoft.com", *qtype));
+            assert_eq!(ResultCode::NOERROR, res.header.rescode);
+            assert_eq!(2, res.answers.len());
+            assert_eq!(QueryType::CNAME, res.answers[0].get_querytype());
+            assert_eq!(*qtype, res.answers[1].get_querytype());
+        }
+
+        // Asking for the CNAME itself needs nothing else
+        {
+            let res = execute_query(
+                context.clone(),
+                &build_query("www.microsoft.com", QueryType::CNAME),
+            );
+            assert_eq!(1, res.answers.len());
+        };
+
+        // CNAME records pointing at each other are only followed once, and
+        // leave the question without an answer, whether they come from
+        // separate responses or from the same one
+        for qname in &["loop1.example.com", "loop3.example.com"] {
+            let res = execute_query(context.clone(), &build_query(qname, QueryType::A));
+            assert_eq!(ResultCode::SERVFAIL, res.header.rescode);
+            assert!(res.answers.is_empty());
+        }
+
+        // So does a chain too long to follow to its end
+        {
+            let res = execute_query(
+                context.clone(),
+                &build_query("chain0.example.com", QueryType::A),
+            );
+            assert_eq!(ResultCode::SERVFAIL, res.header.rescode);
+            assert!(res.answers.is_empty());
+        };
+
+        // A chain ending in a name that doesn't exist has no answer
+        {
+            let res = execute_query(
+                context.clone(),
+                &build_query("dangling.example.com", QueryType::A),
+            );
+            assert_eq!(ResultCode::NXDOMAIN, res.header.rescode);
+            assert_eq!(1, res.answers.len());
+        };
+
+        // The addresses of SRV targets go in the additional section
+        {
+            let res = execute_query(
+                context.clone(),
+                &build_query("_sip._tcp.example.com", QueryType::SRV),
+            );
+            assert_eq!(1, res.answers.len());
+            assert_eq!(QueryType::SRV, res.answers[0].get_querytype());
+            assert_eq!(
+                vec![DnsRecord::A {
+                    domain: "sip.example.com".to_string(),
+                    addr: "127.0.0.2".parse().unwrap(),
+                    ttl: TransientTtl(3600),
+                }],
+                res.resources
+            );
+        };
+    }
 }
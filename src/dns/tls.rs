@@ -0,0 +1,694 @@
+//! client for sending DNS queries to forwarders over TLS (RFC 7858), keeping
+//! the connections open to be reused by later queries
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use base64::Engine;
+use derive_more::{Display, Error, From};
+use rand::random;
+use rustls::client::{ServerCertVerified, ServerCertVerifier};
+use rustls::{
+    Certificate, ClientConfig, ClientConnection, OwnedTrustAnchor, RootCertStore, ServerName,
+    StreamOwned,
+};
+use sha2::{Digest, Sha256};
+
+use crate::dns::buffer::{BytePacketBuffer, PacketBuffer, StreamPacketBuffer};
+use crate::dns::netutil::{read_packet_length, write_packet_length};
+use crate::dns::protocol::{DnsPacket, DnsQuestion, QueryType};
+
+#[derive(Debug, Display, From, Error)]
+pub enum TlsError {
+    Protocol(crate::dns::protocol::ProtocolError),
+    Io(std::io::Error),
+    Tls(rustls::Error),
+    PoisonedLock,
+    InvalidServerName,
+    /// A pin that isn't the base64 encoding of a SHA-256 digest
+    InvalidPin,
+    /// A CA file without any certificates in it
+    NoCertificates,
+    /// A response to some other query than the one sent
+    IdMismatch,
+    /// No time was left to send the query on a new connection
+    TimeOut,
+}
+
+type Result<T> = std::result::Result<T, TlsError>;
+
+/// Connections kept open to each forwarder, beyond which finished ones are
+/// closed instead
+const MAX_IDLE_CONNECTIONS: usize = 4;
+
+type TlsStream = StreamOwned<ClientConnection, TcpStream>;
+
+pub struct DnsTlsClient {
+    config: Arc<ClientConfig>,
+    timeout: Duration,
+
+    /// Open connections that aren't in use, by address and server name
+    idle: Mutex<HashMap<(SocketAddr, String), Vec<TlsStream>>>,
+
+    total_sent: AtomicUsize,
+    total_failed: AtomicUsize,
+    total_connections: AtomicUsize,
+}
+
+impl Default for DnsTlsClient {
+    fn default() -> Self {
+        DnsTlsClient::new()
+    }
+}
+
+/// The TLS settings for reaching forwarders, trusting only the certificate
+/// authorities in `ca_file` if one is given, and the usual ones of the web
+/// otherwise. With any `pins`, only servers presenting one of them are
+/// trusted instead. Each pin is the base64 encoded SHA-256 digest of the
+/// public key of a server (RFC 7858, section 4.2), and takes the place of
+/// checking the certificate chain altogether.
+pub fn client_config(ca_file: Option<&str>, pins: &[String]) -> Result<ClientConfig> {
+    if !pins.is_empty() {
+        let verifier = PinnedKeyVerifier {
+            pins: pins
+                .iter()
+                .map(|x| decode_pin(x))
+                .collect::<Result<Vec<_>>>()?,
+        };
+
+        return Ok(ClientConfig::builder()
+            .with_safe_defaults()
+            .with_custom_certificate_verifier(Arc::new(verifier))
+            .with_no_client_auth());
+    }
+
+    let roots = match ca_file {
+        Some(ca_file) => {
+            let mut reader = BufReader::new(File::open(ca_file)?);
+            let mut roots = RootCertStore::empty();
+            for cert in rustls_pemfile::certs(&mut reader)? {
+                roots.add(&Certificate(cert))?;
+            }
+
+            if roots.is_empty() {
+                return Err(TlsError::NoCertificates);
+            }
+
+            roots
+        }
+        None => default_roots(),
+    };
+
+    Ok(ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth())
+}
+
+fn default_roots() -> RootCertStore {
+    let mut roots = RootCertStore::empty();
+    roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(
+            ta.subject,
+            ta.spki,
+            ta.name_constraints,
+        )
+    }));
+
+    roots
+}
+
+impl DnsTlsClient {
+    /// A client trusting the usual certificate authorities of the web
+    pub fn new() -> DnsTlsClient {
+        DnsTlsClient::with_config(
+            ClientConfig::builder()
+                .with_safe_defaults()
+                .with_root_certificates(default_roots())
+                .with_no_client_auth(),
+            Duration::new(1, 0),
+        )
+    }
+
+    /// A client with the settings described for `client_config`
+    pub fn with_settings(
+        ca_file: Option<&str>,
+        pins: &[String],
+        timeout: Duration,
+    ) -> Result<DnsTlsClient> {
+        Ok(DnsTlsClient::with_config(
+            client_config(ca_file, pins)?,
+            timeout,
+        ))
+    }
+
+    fn with_config(config: ClientConfig, timeout: Duration) -> DnsTlsClient {
+        DnsTlsClient {
+            config: Arc::new(config),
+            timeout,
+            idle: Mutex::new(HashMap::new()),
+            total_sent: AtomicUsize::new(0),
+            total_failed: AtomicUsize::new(0),
+            total_connections: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn get_sent_count(&self) -> usize {
+        self.total_sent.load(Ordering::Acquire)
+    }
+
+    pub fn get_failed_count(&self) -> usize {
+        self.total_failed.load(Ordering::Acquire)
+    }
+
+    /// The number of connections opened, as opposed to reused
+    pub fn get_connection_count(&self) -> usize {
+        self.total_connections.load(Ordering::Acquire)
+    }
+
+    /// Send a query to the forwarder at `addr`, checking its certificate
+    /// against `server_name`, or against its address without one
+    ///
+    /// An idle connection to the forwarder is used when there is one. Since
+    /// the forwarder may have closed it in the meantime, the query is sent
+    /// once more on a new connection if that fails, within what's left of
+    /// the timeout.
+    pub fn send_query(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        addr: SocketAddr,
+        server_name: Option<&str>,
+    ) -> Result<DnsPacket> {
+        let _ = self.total_sent.fetch_add(1, Ordering::Release);
+
+        let name = match server_name {
+            Some(x) => x.to_string(),
+            None => addr.ip().to_string(),
+        };
+        let key = (addr, name);
+
+        let mut packet = DnsPacket::new();
+        packet.header.id = random::<u16>();
+        packet.header.questions = 1;
+        packet.header.recursion_desired = true;
+        packet.questions.push(DnsQuestion::new(qname.into(), qtype));
+
+        let mut req_buffer = BytePacketBuffer::new();
+        packet.write(&mut req_buffer, 0xFFFF)?;
+        let request = &req_buffer.buf[0..req_buffer.pos()];
+
+        let deadline = Instant::now() + self.timeout;
+        let reused = self.take_idle(&key)?;
+        let result = match reused {
+            Some(mut stream) => {
+                match set_timeout(&stream, self.timeout)
+                    .and_then(|_| exchange(&mut stream, request))
+                {
+                    Ok(response) => Ok((stream, response)),
+                    Err(_) => self.exchange_on_new_connection(&key, request, deadline),
+                }
+            }
+            None => self.exchange_on_new_connection(&key, request, deadline),
+        };
+
+        let (stream, response) = match result {
+            Ok(x) => x,
+            Err(e) => {
+                let _ = self.total_failed.fetch_add(1, Ordering::Release);
+                return Err(e);
+            }
+        };
+
+        if response.header.id != packet.header.id {
+            let _ = self.total_failed.fetch_add(1, Ordering::Release);
+            return Err(TlsError::IdMismatch);
+        }
+
+        self.put_idle(key, stream)?;
+
+        Ok(response)
+    }
+
+    fn exchange_on_new_connection(
+        &self,
+        key: &(SocketAddr, String),
+        request: &[u8],
+        deadline: Instant,
+    ) -> Result<(TlsStream, DnsPacket)> {
+        let timeout = match deadline.checked_duration_since(Instant::now()) {
+            Some(x) if x > Duration::from_millis(0) => x,
+            _ => return Err(TlsError::TimeOut),
+        };
+
+        let mut stream = self.connect(key, timeout)?;
+        let response = exchange(&mut stream, request)?;
+
+        Ok((stream, response))
+    }
+
+    fn connect(&self, key: &(SocketAddr, String), timeout: Duration) -> Result<TlsStream> {
+        let (addr, ref name) = *key;
+        let server_name = match name.parse() {
+            Ok(ip) => ServerName::IpAddress(ip),
+            Err(_) => {
+                ServerName::try_from(name.as_str()).map_err(|_| TlsError::InvalidServerName)?
+            }
+        };
+
+        let socket = TcpStream::connect_timeout(&addr, timeout)?;
+        socket.set_read_timeout(Some(timeout))?;
+        socket.set_write_timeout(Some(timeout))?;
+        socket.set_nodelay(true)?;
+
+        let _ = self.total_connections.fetch_add(1, Ordering::Release);
+
+        let connection = ClientConnection::new(self.config.clone(), server_name)?;
+
+        Ok(StreamOwned::new(connection, socket))
+    }
+
+    fn take_idle(&self, key: &(SocketAddr, String)) -> Result<Option<TlsStream>> {
+        let mut idle = self.idle.lock().map_err(|_| TlsError::PoisonedLock)?;
+
+        Ok(idle.get_mut(key).and_then(|x| x.pop()))
+    }
+
+    fn put_idle(&self, key: (SocketAddr, String), stream: TlsStream) -> Result<()> {
+        let mut idle = self.idle.lock().map_err(|_| TlsError::PoisonedLock)?;
+
+        let streams = idle.entry(key).or_insert_with(Vec::new);
+        if streams.len() < MAX_IDLE_CONNECTIONS {
+            streams.push(stream);
+        }
+
+        Ok(())
+    }
+}
+
+/// Set how long to wait on a connection for each read and write
+fn set_timeout(stream: &TlsStream, timeout: Duration) -> Result<()> {
+    stream.sock.set_read_timeout(Some(timeout))?;
+    stream.sock.set_write_timeout(Some(timeout))?;
+
+    Ok(())
+}
+
+/// Write a length prefixed query to the stream, and read back the response
+fn exchange<T: Read + Write>(stream: &mut T, request: &[u8]) -> Result<DnsPacket> {
+    let mut message = Vec::with_capacity(request.len() + 2);
+    write_packet_length(&mut message, request.len())?;
+    message.extend_from_slice(request);
+
+    stream.write_all(&message)?;
+    stream.flush()?;
+
+    // The whole response is read before parsing it, so that nothing of it is
+    // left on the connection for the next query to trip over
+    let len = read_packet_length(stream)?;
+    let mut response = vec![0; len as usize];
+    stream.read_exact(&mut response)?;
+
+    let mut reader = &response[..];
+    let mut stream_buffer = StreamPacketBuffer::new(&mut reader);
+
+    Ok(DnsPacket::from_buffer(&mut stream_buffer)?)
+}
+
+fn decode_pin(pin: &str) -> Result<Vec<u8>> {
+    let digest = base64::engine::general_purpose::STANDARD
+        .decode(pin.trim())
+        .map_err(|_| TlsError::InvalidPin)?;
+
+    if digest.len() != 32 {
+        return Err(TlsError::InvalidPin);
+    }
+
+    Ok(digest)
+}
+
+/// The pin of a certificate, being the base64 encoded SHA-256 digest of its
+/// public key, as given to `--tls-pin`
+pub fn certificate_pin(cert: &[u8]) -> Option<String> {
+    let spki = subject_public_key_info(cert)?;
+
+    Some(base64::engine::general_purpose::STANDARD.encode(Sha256::digest(spki)))
+}
+
+/// Accepts the servers presenting a certificate for one of the pinned keys
+struct PinnedKeyVerifier {
+    pins: Vec<Vec<u8>>,
+}
+
+impl ServerCertVerifier for PinnedKeyVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        let spki = subject_public_key_info(&end_entity.0).ok_or_else(|| {
+            rustls::Error::InvalidCertificate(rustls::CertificateError::BadEncoding)
+        })?;
+
+        let digest = Sha256::digest(spki);
+        if self.pins.iter().any(|pin| pin[..] == digest[..]) {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::InvalidCertificate(
+                rustls::CertificateError::ApplicationVerificationFailure,
+            ))
+        }
+    }
+}
+
+/// Split the DER encoded element at the start of `data` into all of it, its
+/// contents, and whatever follows it
+fn der_element(data: &[u8]) -> Option<(&[u8], &[u8], &[u8])> {
+    let first = *data.get(1)?;
+    let (len, header) = if first & 0x80 == 0 {
+        (first as usize, 2)
+    } else {
+        let count = (first & 0x7F) as usize;
+        if count == 0 || count > 4 {
+            return None;
+        }
+
+        let mut len = 0usize;
+        for i in 0..count {
+            len = (len << 8) | *data.get(2 + i)? as usize;
+        }
+
+        (len, 2 + count)
+    };
+
+    let end = header.checked_add(len)?;
+    if end > data.len() {
+        return None;
+    }
+
+    Some((&data[..end], &data[header..end], &data[end..]))
+}
+
+/// Find the encoded public key of a DER encoded X.509 certificate
+fn subject_public_key_info(cert: &[u8]) -> Option<&[u8]> {
+    let (_, certificate, _) = der_element(cert)?;
+    let (_, tbs_certificate, _) = der_element(certificate)?;
+
+    // The version is optional, and tagged as [0] when present
+    let mut rest = tbs_certificate;
+    if rest.first() == Some(&0xA0) {
+        rest = der_element(rest)?.2;
+    }
+
+    // Then come the serial number, signature algorithm, issuer, validity and
+    // subject
+    for _ in 0..5 {
+        rest = der_element(rest)?.2;
+    }
+
+    der_element(rest).map(|(spki, _, _)| spki)
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    use std::env;
+    use std::fs;
+    use std::net::{Ipv4Addr, TcpListener};
+    use std::thread;
+
+    use rcgen::{BasicConstraints, CertificateParams, DnType, IsCa};
+    use rustls::{PrivateKey, ServerConfig, ServerConnection};
+
+    use crate::dns::protocol::{DnsRecord, TransientTtl};
+
+    use super::*;
+
+    /// A certificate for `dns.test`, signed by a CA of its own
+    pub struct TestCertificate {
+        /// Where the certificate of the CA is written to
+        pub ca_file: String,
+        pub pin: String,
+        pub cert_der: Vec<u8>,
+        pub key_der: Vec<u8>,
+    }
+
+    pub fn create_test_certificate(name: &str) -> TestCertificate {
+        let mut ca_params = CertificateParams::new(Vec::new());
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        ca_params
+            .distinguished_name
+            .push(DnType::CommonName, "Hermes Test CA");
+        let ca = rcgen::Certificate::from_params(ca_params).unwrap();
+
+        let cert =
+            rcgen::Certificate::from_params(CertificateParams::new(vec!["dns.test".to_string()]))
+                .unwrap();
+        let cert_der = cert.serialize_der_with_signer(&ca).unwrap();
+
+        // The pin covers the public key, found from within the certificate
+        assert_eq!(
+            Some(cert.get_key_pair().public_key_der().as_slice()),
+            subject_public_key_info(&cert_der)
+        );
+        let pin = certificate_pin(&cert_der).unwrap();
+
+        let ca_file = env::temp_dir()
+            .join(format!("hermes-tls-{}-{}.pem", name, random::<u32>()))
+            .to_string_lossy()
+            .to_string();
+        fs::write(&ca_file, ca.serialize_pem().unwrap()).unwrap();
+
+        TestCertificate {
+            ca_file,
+            pin,
+            cert_der,
+            key_der: cert.serialize_private_key_der(),
+        }
+    }
+
+    struct StandIn {
+        addr: SocketAddr,
+        ca_file: String,
+        pin: String,
+        connections: Arc<AtomicUsize>,
+    }
+
+    /// Run a DNS over TLS server on a local port, answering every query for
+    /// an A record with 127.0.0.1
+    fn start_stand_in(name: &str) -> StandIn {
+        start_stand_in_with_limit(name, usize::MAX)
+    }
+
+    /// Like `start_stand_in`, but leaving every query unanswered once `limit`
+    /// have been answered
+    fn start_stand_in_with_limit(name: &str, limit: usize) -> StandIn {
+        let TestCertificate {
+            ca_file,
+            pin,
+            cert_der,
+            key_der,
+        } = create_test_certificate(name);
+
+        let config = Arc::new(
+            ServerConfig::builder()
+                .with_safe_defaults()
+                .with_no_client_auth()
+                .with_single_cert(vec![Certificate(cert_der)], PrivateKey(key_der))
+                .unwrap(),
+        );
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let counter = connections.clone();
+        let answered = Arc::new(AtomicUsize::new(0));
+
+        thread::spawn(move || {
+            for socket in listener.incoming() {
+                let socket = match socket {
+                    Ok(x) => x,
+                    Err(_) => continue,
+                };
+
+                counter.fetch_add(1, Ordering::SeqCst);
+
+                let connection = ServerConnection::new(config.clone()).unwrap();
+                let mut stream = StreamOwned::new(connection, socket);
+                let answered = answered.clone();
+                thread::spawn(move || loop {
+                    let len = match read_packet_length(&mut stream) {
+                        Ok(x) => x,
+                        Err(_) => return,
+                    };
+                    let mut request = vec![0; len as usize];
+                    if stream.read_exact(&mut request).is_err() {
+                        return;
+                    }
+
+                    let mut reader = &request[..];
+                    let query =
+                        DnsPacket::from_buffer(&mut StreamPacketBuffer::new(&mut reader)).unwrap();
+
+                    if answered.fetch_add(1, Ordering::SeqCst) >= limit {
+                        continue;
+                    }
+
+                    let mut packet = DnsPacket::new();
+                    packet.header.id = query.header.id;
+                    packet.header.response = true;
+                    packet.questions = query.questions.clone();
+                    packet.answers.push(DnsRecord::A {
+                        domain: query.questions[0].name.clone(),
+                        addr: Ipv4Addr::new(127, 0, 0, 1),
+                        ttl: TransientTtl(3600),
+                    });
+
+                    let mut buffer = BytePacketBuffer::new();
+                    packet.write(&mut buffer, 0xFFFF).unwrap();
+
+                    let mut message = Vec::new();
+                    write_packet_length(&mut message, buffer.pos()).unwrap();
+                    message.extend_from_slice(&buffer.buf[0..buffer.pos()]);
+                    if stream.write_all(&message).is_err() {
+                        return;
+                    }
+                });
+            }
+        });
+
+        StandIn {
+            addr,
+            ca_file,
+            pin,
+            connections,
+        }
+    }
+
+    #[test]
+    fn test_tls_client_reuses_connection() {
+        let stand_in = start_stand_in("reuse");
+        let client =
+            DnsTlsClient::with_settings(Some(&stand_in.ca_file), &[], Duration::new(5, 0)).unwrap();
+
+        for qname in &["www.google.com", "www.yahoo.com", "www.bing.com"] {
+            let res = client
+                .send_query(qname, QueryType::A, stand_in.addr, Some("dns.test"))
+                .unwrap();
+
+            assert_eq!(1, res.answers.len());
+            match res.answers[0] {
+                DnsRecord::A { ref domain, .. } => assert_eq!(qname, domain),
+                _ => panic!(),
+            }
+        }
+
+        assert_eq!(3, client.get_sent_count());
+        assert_eq!(0, client.get_failed_count());
+        assert_eq!(1, client.get_connection_count());
+        assert_eq!(1, stand_in.connections.load(Ordering::SeqCst));
+
+        // The certificate isn't valid for any other name
+        assert!(client
+            .send_query(
+                "www.google.com",
+                QueryType::A,
+                stand_in.addr,
+                Some("dns.other")
+            )
+            .is_err());
+
+        let _ = fs::remove_file(&stand_in.ca_file);
+    }
+
+    #[test]
+    fn test_tls_client_retry_timeout() {
+        let stand_in = start_stand_in_with_limit("retry", 1);
+        let client =
+            DnsTlsClient::with_settings(Some(&stand_in.ca_file), &[], Duration::from_millis(500))
+                .unwrap();
+
+        assert!(client
+            .send_query(
+                "www.google.com",
+                QueryType::A,
+                stand_in.addr,
+                Some("dns.test")
+            )
+            .is_ok());
+
+        // The query goes unanswered on the open connection, which leaves no
+        // time for sending it again on a new one
+        let start = Instant::now();
+        match client.send_query(
+            "www.yahoo.com",
+            QueryType::A,
+            stand_in.addr,
+            Some("dns.test"),
+        ) {
+            Err(TlsError::TimeOut) => {}
+            _ => panic!(),
+        }
+        assert!(start.elapsed() < Duration::from_millis(900));
+        assert_eq!(1, client.get_connection_count());
+
+        let _ = fs::remove_file(&stand_in.ca_file);
+    }
+
+    #[test]
+    fn test_tls_client_verification() {
+        let stand_in = start_stand_in("verify");
+
+        // The certificate authority of the stand-in isn't trusted by default
+        let client = DnsTlsClient::with_settings(None, &[], Duration::new(5, 0)).unwrap();
+        assert!(client
+            .send_query(
+                "www.google.com",
+                QueryType::A,
+                stand_in.addr,
+                Some("dns.test")
+            )
+            .is_err());
+        assert_eq!(1, client.get_failed_count());
+
+        // A pinned key is accepted whatever signed it, and whatever the name
+        let client =
+            DnsTlsClient::with_settings(None, &[stand_in.pin.clone()], Duration::new(5, 0))
+                .unwrap();
+        assert!(client
+            .send_query("www.google.com", QueryType::A, stand_in.addr, None)
+            .is_ok());
+
+        // But any other key is turned away
+        let other_pin = base64::engine::general_purpose::STANDARD.encode([0u8; 32]);
+        let client = DnsTlsClient::with_settings(None, &[other_pin], Duration::new(5, 0)).unwrap();
+        assert!(client
+            .send_query(
+                "www.google.com",
+                QueryType::A,
+                stand_in.addr,
+                Some("dns.test")
+            )
+            .is_err());
+
+        assert!(
+            DnsTlsClient::with_settings(None, &["c2hvcnQ=".to_string()], Duration::new(5, 0))
+                .is_err()
+        );
+        assert!(
+            DnsTlsClient::with_settings(None, &["!".to_string()], Duration::new(5, 0)).is_err()
+        );
+
+        let _ = fs::remove_file(&stand_in.ca_file);
+    }
+}
@@ -0,0 +1,524 @@
+//! reading and writing zones in the master file format described in RFC 1035
+
+use std::fmt::Write;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use derive_more::{Display, Error};
+
+use crate::dns::authority::Zone;
+use crate::dns::protocol::{DnsRecord, TransientTtl};
+
+#[derive(Debug, Display, Error)]
+pub enum ZoneFileError {
+    #[display(fmt = "Syntax error on line {}", line)]
+    Syntax { line: usize },
+    #[display(fmt = "Unsupported directive or record type on line {}", line)]
+    Unsupported { line: usize },
+    #[display(fmt = "Missing owner name on line {}", line)]
+    MissingOwner { line: usize },
+}
+
+type Result<T> = std::result::Result<T, ZoneFileError>;
+
+const DEFAULT_TTL: u32 = 3600;
+
+/// A logical line of a master file, with parentheses and comments resolved
+struct Entry {
+    line: usize,
+    inherits_owner: bool,
+    tokens: Vec<String>,
+}
+
+/// Split the contents of a master file into logical entries
+///
+/// Entries can span several physical lines when enclosed in parentheses, which
+/// is commonly done for SOA records. Quoted strings are kept as a single token,
+/// including the quotes, so that TXT data can be told apart from other tokens.
+fn tokenize(data: &str) -> Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+
+    let mut current: Option<Entry> = None;
+    let mut depth = 0;
+
+    for (idx, line) in data.lines().enumerate() {
+        let line_no = idx + 1;
+
+        if current.is_none() {
+            current = Some(Entry {
+                line: line_no,
+                inherits_owner: line.starts_with(' ') || line.starts_with('\t'),
+                tokens: Vec::new(),
+            });
+        }
+
+        let entry = current.as_mut().unwrap();
+
+        let mut token = String::new();
+        let mut quoted = false;
+        let mut escaped = false;
+        for c in line.chars() {
+            if quoted {
+                token.push(c);
+                if escaped {
+                    escaped = false;
+                } else if c == '\\' {
+                    escaped = true;
+                } else if c == '"' {
+                    quoted = false;
+                    entry.tokens.push(std::mem::take(&mut token));
+                }
+                continue;
+            }
+
+            match c {
+                ';' => break,
+                '"' => {
+                    if !token.is_empty() {
+                        entry.tokens.push(std::mem::take(&mut token));
+                    }
+                    token.push(c);
+                    quoted = true;
+                }
+                '(' | ')' | ' ' | '\t' => {
+                    if !token.is_empty() {
+                        entry.tokens.push(std::mem::take(&mut token));
+                    }
+                    if c == '(' {
+                        depth += 1;
+                    } else if c == ')' {
+                        if depth == 0 {
+                            return Err(ZoneFileError::Syntax { line: line_no });
+                        }
+                        depth -= 1;
+                    }
+                }
+                _ => token.push(c),
+            }
+        }
+
+        if quoted {
+            return Err(ZoneFileError::Syntax { line: line_no });
+        }
+
+        if !token.is_empty() {
+            entry.tokens.push(token);
+        }
+
+        if depth == 0 {
+            let entry = current.take().unwrap();
+            if !entry.tokens.is_empty() {
+                entries.push(entry);
+            }
+        }
+    }
+
+    if let Some(entry) = current {
+        if depth > 0 {
+            return Err(ZoneFileError::Syntax { line: entry.line });
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Expand a possibly relative domain name into an absolute one, without the
+/// trailing dot used internally
+fn resolve_name(name: &str, origin: &str) -> String {
+    let name = name.to_lowercase();
+    if name == "@" {
+        origin.to_string()
+    } else if name == "." {
+        String::new()
+    } else if let Some(absolute) = name.strip_suffix('.') {
+        absolute.to_string()
+    } else if origin.is_empty() {
+        name
+    } else {
+        format!("{}.{}", name, origin)
+    }
+}
+
+/// Parse a time value, which is either plain seconds or uses the BIND style
+/// unit suffixes such as `1h30m`
+fn parse_ttl(value: &str) -> Option<u32> {
+    if let Ok(seconds) = value.parse::<u32>() {
+        return Some(seconds);
+    }
+
+    let mut total: u32 = 0;
+    let mut number = String::new();
+    for c in value.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+
+        let multiplier = match c.to_ascii_lowercase() {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            'd' => 86400,
+            'w' => 604_800,
+            _ => return None,
+        };
+
+        let n = number.parse::<u32>().ok()?;
+        total = total.checked_add(n.checked_mul(multiplier)?)?;
+        number.clear();
+    }
+
+    if !number.is_empty() {
+        return None;
+    }
+
+    Some(total)
+}
+
+/// Encode the strings of a TXT record the way they appear on the wire, with
+/// each string prefixed by its length
+fn encode_txt(tokens: &[String]) -> String {
+    let mut data = String::new();
+    for token in tokens {
+        let text = token.trim_matches('"').replace("\\\"", "\"");
+
+        let mut chunk = String::new();
+        for c in text.chars() {
+            if chunk.len() + c.len_utf8() > 127 {
+                data.push(chunk.len() as u8 as char);
+                data.push_str(&chunk);
+                chunk.clear();
+            }
+            chunk.push(c);
+        }
+
+        data.push(chunk.len() as u8 as char);
+        data.push_str(&chunk);
+    }
+
+    data
+}
+
+/// The inverse of `encode_txt`, producing quoted strings for a master file
+fn decode_txt(data: &str) -> String {
+    let bytes = data.as_bytes();
+
+    let mut strings = Vec::new();
+    let mut pos = 0;
+    while pos < bytes.len() {
+        let len = bytes[pos] as usize;
+        let end = std::cmp::min(pos + 1 + len, bytes.len());
+        let text = String::from_utf8_lossy(&bytes[pos + 1..end]);
+        strings.push(format!("\"{}\"", text.replace('"', "\\\"")));
+        pos = end;
+    }
+
+    strings.join(" ")
+}
+
+/// Parse the contents of a master file into a `Zone`
+///
+/// The SOA record of the file, if present, populates the zone parameters. All
+/// other records are added to the zone. If there is no SOA record, `m_name` and
+/// `r_name` are left empty. `origin` is used for relative names until the file
+/// specifies an `$ORIGIN` of its own.
+pub fn parse_zone(data: &str, origin: &str) -> Result<Zone> {
+    let mut origin = origin.trim_end_matches('.').to_lowercase();
+
+    let mut zone = Zone::new(origin.clone(), String::new(), String::new());
+
+    let mut default_ttl = None;
+    let mut last_owner: Option<String> = None;
+    let mut last_ttl = None;
+
+    for entry in tokenize(data)? {
+        let line = entry.line;
+        let tokens = entry.tokens;
+
+        if tokens[0].starts_with('$') {
+            match (tokens[0].to_uppercase().as_str(), tokens.get(1)) {
+                ("$ORIGIN", Some(name)) => origin = resolve_name(name, &origin),
+                ("$TTL", Some(ttl)) => {
+                    default_ttl = Some(parse_ttl(ttl).ok_or(ZoneFileError::Syntax { line })?);
+                }
+                _ => return Err(ZoneFileError::Unsupported { line }),
+            }
+            continue;
+        }
+
+        let mut idx = 0;
+        let owner = if entry.inherits_owner {
+            last_owner
+                .clone()
+                .ok_or(ZoneFileError::MissingOwner { line })?
+        } else {
+            idx += 1;
+            resolve_name(&tokens[0], &origin)
+        };
+        last_owner = Some(owner.clone());
+
+        // The TTL and class are both optional, and may appear in any order
+        let mut ttl = None;
+        while idx < tokens.len() {
+            if tokens[idx].eq_ignore_ascii_case("IN") {
+                idx += 1;
+            } else if ttl.is_none() && tokens[idx].starts_with(|c: char| c.is_ascii_digit()) {
+                ttl = Some(parse_ttl(&tokens[idx]).ok_or(ZoneFileError::Syntax { line })?);
+                idx += 1;
+            } else {
+                break;
+            }
+        }
+
+        let ttl = ttl.or(default_ttl).or(last_ttl).unwrap_or(DEFAULT_TTL);
+        last_ttl = Some(ttl);
+
+        let rtype = tokens
+            .get(idx)
+            .ok_or(ZoneFileError::Syntax { line })?
+            .to_uppercase();
+        let rdata = &tokens[idx + 1..];
+
+        let syntax = || ZoneFileError::Syntax { line };
+        let field = |i: usize| rdata.get(i).ok_or_else(syntax);
+        let number = |i: usize| field(i).and_then(|x| x.parse::<u16>().map_err(|_| syntax()));
+        let time = |i: usize| field(i).and_then(|x| parse_ttl(x).ok_or_else(syntax));
+
+        let domain = owner;
+        let ttl = TransientTtl(ttl);
+        let rec = match rtype.as_str() {
+            "A" => DnsRecord::A {
+                domain,
+                addr: field(0)?.parse::<Ipv4Addr>().map_err(|_| syntax())?,
+                ttl,
+            },
+            "AAAA" => DnsRecord::AAAA {
+                domain,
+                addr: field(0)?.parse::<Ipv6Addr>().map_err(|_| syntax())?,
+                ttl,
+            },
+            "NS" => DnsRecord::NS {
+                domain,
+                host: resolve_name(field(0)?, &origin),
+                ttl,
+            },
+            "CNAME" => DnsRecord::CNAME {
+                domain,
+                host: resolve_name(field(0)?, &origin),
+                ttl,
+            },
+            "MX" => DnsRecord::MX {
+                domain,
+                priority: number(0)?,
+                host: resolve_name(field(1)?, &origin),
+                ttl,
+            },
+            "SRV" => DnsRecord::SRV {
+                domain,
+                priority: number(0)?,
+                weight: number(1)?,
+                port: number(2)?,
+                host: resolve_name(field(3)?, &origin),
+                ttl,
+            },
+            "TXT" => {
+                if rdata.is_empty() {
+                    return Err(syntax());
+                }
+
+                DnsRecord::TXT {
+                    domain,
+                    data: encode_txt(rdata),
+                    ttl,
+                }
+            }
+            "SOA" => {
+                zone.domain = domain;
+                zone.m_name = resolve_name(field(0)?, &origin);
+                zone.r_name = resolve_name(field(1)?, &origin);
+                zone.serial = time(2)?;
+                zone.refresh = time(3)?;
+                zone.retry = time(4)?;
+                zone.expire = time(5)?;
+                zone.minimum = time(6)?;
+                continue;
+            }
+            _ => return Err(ZoneFileError::Unsupported { line }),
+        };
+
+        zone.add_record(&rec);
+    }
+
+    Ok(zone)
+}
+
+fn fqdn(name: &str) -> String {
+    format!("{}.", name)
+}
+
+/// Serialize a `Zone` into master file format
+///
+/// All names are written in absolute form, so the output can be read back
+/// regardless of the origin used.
+pub fn write_zone(zone: &Zone) -> String {
+    let mut out = String::new();
+
+    let _ = writeln!(out, "$ORIGIN {}", fqdn(&zone.domain));
+    let _ = writeln!(
+        out,
+        "{}\t{}\tIN\tSOA\t{} {} {} {} {} {} {}",
+        fqdn(&zone.domain),
+        zone.minimum,
+        fqdn(&zone.m_name),
+        fqdn(&zone.r_name),
+        zone.serial,
+        zone.refresh,
+        zone.retry,
+        zone.expire,
+        zone.minimum
+    );
+
+    for rec in &zone.records {
+        let rdata = match *rec {
+            DnsRecord::A { ref addr, .. } => addr.to_string(),
+            DnsRecord::AAAA { ref addr, .. } => addr.to_string(),
+            DnsRecord::NS { ref host, .. } | DnsRecord::CNAME { ref host, .. } => fqdn(host),
+            DnsRecord::MX {
+                priority, ref host, ..
+            } => format!("{} {}", priority, fqdn(host)),
+            DnsRecord::SRV {
+                priority,
+                weight,
+                port,
+                ref host,
+                ..
+            } => format!("{} {} {} {}", priority, weight, port, fqdn(host)),
+            DnsRecord::TXT { ref data, .. } => decode_txt(data),
+            DnsRecord::SOA { .. } | DnsRecord::UNKNOWN { .. } | DnsRecord::OPT { .. } => continue,
+        };
+
+        let domain = match rec.get_domain() {
+            Some(x) => x,
+            None => continue,
+        };
+
+        let _ = writeln!(
+            out,
+            "{}\t{}\tIN\t{:?}\t{}",
+            fqdn(&domain),
+            rec.get_ttl(),
+            rec.get_querytype(),
+            rdata
+        );
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_parse_zone() {
+        let data = "$TTL 1h
+$ORIGIN example.com.
+@   IN  SOA ns1 hostmaster (
+        2020010101 ; serial
+        3600 600 86400
+        300 )
+    IN  NS  ns1
+ns1 300 IN A 127.0.0.1
+www IN 60 CNAME @
+    AAAA ::1
+mail.other.org. MX 10 mx
+txt TXT \"hello world\" \"second\"
+_sip._tcp SRV 10 20 5060 sip.example.com.
+";
+
+        let zone = parse_zone(data, "ignored.org").unwrap();
+
+        assert_eq!("example.com", zone.domain);
+        assert_eq!("ns1.example.com", zone.m_name);
+        assert_eq!("hostmaster.example.com", zone.r_name);
+        assert_eq!(2020010101, zone.serial);
+        assert_eq!(300, zone.minimum);
+        assert_eq!(7, zone.records.len());
+
+        let records = zone.records.iter().collect::<Vec<_>>();
+        assert!(records.contains(&&DnsRecord::NS {
+            domain: "example.com".to_string(),
+            host: "ns1.example.com".to_string(),
+            ttl: TransientTtl(3600),
+        }));
+        assert!(records.contains(&&DnsRecord::AAAA {
+            domain: "www.example.com".to_string(),
+            addr: "::1".parse().unwrap(),
+            ttl: TransientTtl(60),
+        }));
+        assert!(records.contains(&&DnsRecord::MX {
+            domain: "mail.other.org".to_string(),
+            priority: 10,
+            host: "mx.example.com".to_string(),
+            ttl: TransientTtl(60),
+        }));
+
+        for rec in &zone.records {
+            if let DnsRecord::A { ttl, .. } = rec {
+                assert_eq!(300, ttl.0);
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(matches!(
+            parse_zone("www IN A 300.0.0.1", "example.com"),
+            Err(ZoneFileError::Syntax { line: 1 })
+        ));
+        assert!(matches!(
+            parse_zone("\n  IN A 127.0.0.1", "example.com"),
+            Err(ZoneFileError::MissingOwner { line: 2 })
+        ));
+        assert!(matches!(
+            parse_zone("www IN HINFO a b", "example.com"),
+            Err(ZoneFileError::Unsupported { line: 1 })
+        ));
+        assert!(matches!(
+            parse_zone("@ SOA ns1 host ( 1 2 3 4 5", "example.com"),
+            Err(ZoneFileError::Syntax { line: 1 })
+        ));
+    }
+
+    #[test]
+    fn test_write_zone_roundtrip() {
+        let mut zone = Zone::new(
+            "example.com".to_string(),
+            "ns1.example.com".to_string(),
+            "hostmaster.example.com".to_string(),
+        );
+        zone.serial = 5;
+        zone.minimum = 300;
+        zone.add_record(&DnsRecord::A {
+            domain: "www.example.com".to_string(),
+            addr: "127.0.0.1".parse().unwrap(),
+            ttl: TransientTtl(60),
+        });
+        zone.add_record(&DnsRecord::TXT {
+            domain: "example.com".to_string(),
+            data: encode_txt(&["\"v=spf1 -all\"".to_string()]),
+            ttl: TransientTtl(60),
+        });
+
+        let parsed = parse_zone(&write_zone(&zone), "").unwrap();
+
+        assert_eq!(zone.domain, parsed.domain);
+        assert_eq!(zone.m_name, parsed.m_name);
+        assert_eq!(zone.serial, parsed.serial);
+        assert_eq!(zone.records, parsed.records);
+        assert_eq!(
+            "\"v=spf1 -all\"",
+            decode_txt(&encode_txt(&["\"v=spf1 -all\"".to_string()]))
+        );
+    }
+}
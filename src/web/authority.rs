@@ -4,9 +4,10 @@ use std::net::{Ipv4Addr, Ipv6Addr};
 use serde_derive::{Deserialize, Serialize};
 use serde_json::json;
 
-use crate::dns::authority::Zone;
+use crate::dns::audit::{AuditEntry, AuditOperation};
+use crate::dns::authority::{Zone, ZoneDiff};
 use crate::dns::context::ServerContext;
-use crate::dns::protocol::{DnsRecord, TransientTtl};
+use crate::dns::protocol::{is_subdomain, DnsRecord, TransientTtl};
 
 use crate::web::cache::CacheRecordEntry;
 use crate::web::util::FormDataDecodable;
@@ -142,18 +143,35 @@ pub fn zone_list(context: &ServerContext) -> Result<serde_json::Value> {
     }))
 }
 
-pub fn zone_create(context: &ServerContext, request: ZoneCreateRequest) -> Result<Zone> {
+pub fn zone_create(
+    context: &ServerContext,
+    request: ZoneCreateRequest,
+    client: &str,
+) -> Result<Zone> {
     let mut zones = context.authority.write().map_err(|_| WebError::LockError)?;
 
+    // Creating the zone over again would drop its records without them
+    // being logged, and start its serials over
+    if zones.get_zone(&request.domain).is_some() {
+        return Err(WebError::ZoneExists);
+    }
+
     let mut zone = Zone::new(request.domain, request.m_name, request.r_name);
     zone.serial = 0;
     zone.refresh = request.refresh.unwrap_or(3600);
     zone.retry = request.retry.unwrap_or(3600);
     zone.expire = request.expire.unwrap_or(3600);
     zone.minimum = request.minimum.unwrap_or(3600);
-    zones.add_zone(zone.clone());
-
-    zones.save()?;
+    zones.commit(
+        zone.clone(),
+        vec![AuditEntry::new(
+            client,
+            AuditOperation::CreateZone,
+            zone.serial,
+            None,
+            None,
+        )],
+    )?;
 
     Ok(zone)
 }
@@ -168,6 +186,7 @@ pub fn zone_view(context: &ServerContext, zone: &str) -> Result<serde_json::Valu
         records.push(CacheRecordEntry {
             id: id as u32,
             record: rr.clone(),
+            remaining_ttl: None,
         });
     }
 
@@ -178,35 +197,254 @@ pub fn zone_view(context: &ServerContext, zone: &str) -> Result<serde_json::Valu
     }))
 }
 
-pub fn record_create(context: &ServerContext, zone: &str, request: RecordRequest) -> Result<()> {
+pub fn record_create(
+    context: &ServerContext,
+    zone: &str,
+    request: RecordRequest,
+    client: &str,
+) -> Result<()> {
     let rr = request
         .into_resourcerecord()
         .ok_or_else(|| WebError::InvalidRequest)?;
 
     let mut zones = context.authority.write().map_err(|_| WebError::LockError)?;
-    let zone = zones
-        .get_zone_mut(zone)
+    let mut zone = zones
+        .get_zone(zone)
+        .cloned()
         .ok_or_else(|| WebError::ZoneNotFound)?;
+
+    // Adding a record that's already present replaces it, which matters
+    // when the TTL differs
+    let before = zone.records.get(&rr).cloned();
+    zone.delete_record(&rr);
     zone.add_record(&rr);
+    zone.serial = zone.serial.wrapping_add(1);
+
+    let entry = AuditEntry::new(
+        client,
+        AuditOperation::AddRecord,
+        zone.serial,
+        before,
+        Some(rr),
+    );
 
-    zones.save()?;
+    zones.commit(zone, vec![entry])?;
 
     Ok(())
 }
 
-pub fn record_delete(context: &ServerContext, zone: &str, request: RecordRequest) -> Result<()> {
+pub fn record_delete(
+    context: &ServerContext,
+    zone: &str,
+    request: RecordRequest,
+    client: &str,
+) -> Result<()> {
     let rr = request
         .into_resourcerecord()
         .ok_or_else(|| WebError::InvalidRequest)?;
 
     let mut zones = context.authority.write().map_err(|_| WebError::LockError)?;
-    let zone = zones
-        .get_zone_mut(zone)
+    let mut zone = zones
+        .get_zone(zone)
+        .cloned()
         .ok_or_else(|| WebError::ZoneNotFound)?;
-    zone.delete_record(&rr);
 
-    zones.save()?;
+    let before = match zone.records.take(&rr) {
+        Some(x) => x,
+        None => return Ok(()),
+    };
+    zone.serial = zone.serial.wrapping_add(1);
+
+    let entry = AuditEntry::new(
+        client,
+        AuditOperation::DeleteRecord,
+        zone.serial,
+        Some(before),
+        None,
+    );
+
+    zones.commit(zone, vec![entry])?;
 
     Ok(())
 }
 
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ZoneImportResponse {
+    pub ok: bool,
+    pub zone: String,
+    pub dry_run: bool,
+    pub serial: u32,
+    pub added: Vec<DnsRecord>,
+    pub removed: Vec<DnsRecord>,
+}
+
+/// Replace the contents of a zone with the records of `imported`
+///
+/// All changes are applied while holding the write lock, and the serial is
+/// bumped once for the whole import. If `imported` lacks SOA information, the
+/// parameters of the existing zone are kept. With `dry_run` set, nothing is
+/// changed and only the difference against the current zone is returned.
+pub fn zone_import(
+    context: &ServerContext,
+    zone: &str,
+    mut imported: Zone,
+    dry_run: bool,
+    client: &str,
+) -> Result<ZoneImportResponse> {
+    // Names are stored in lowercase, like those read from zone files
+    let zone = &zone.to_lowercase();
+    imported.domain = zone.to_string();
+    imported.records = imported
+        .records
+        .into_iter()
+        .map(|mut rec| {
+            if let Some(domain) = rec.get_domain() {
+                rec.set_domain(domain.to_lowercase());
+            }
+            rec
+        })
+        .collect();
+
+    if imported
+        .records
+        .iter()
+        .filter_map(|rec| rec.get_domain())
+        .any(|domain| !is_subdomain(&domain, zone))
+    {
+        return Err(WebError::InvalidRequest);
+    }
+
+    let mut zones = context.authority.write().map_err(|_| WebError::LockError)?;
+
+    match zones.get_zone(zone) {
+        Some(current) => {
+            if imported.m_name.is_empty() {
+                imported.m_name = current.m_name.clone();
+                imported.r_name = current.r_name.clone();
+                imported.refresh = current.refresh;
+                imported.retry = current.retry;
+                imported.expire = current.expire;
+                imported.minimum = current.minimum;
+            }
+            imported.serial = current.serial.wrapping_add(1);
+        }
+        None => {
+            if imported.m_name.is_empty() {
+                return Err(WebError::MissingField("SOA"));
+            }
+        }
+    }
+
+    let diff = zones
+        .get_zone(zone)
+        .map(|current| current.diff(&imported))
+        .unwrap_or_else(|| Zone::default().diff(&imported));
+
+    if !dry_run {
+        let entries = diff_entries(client, AuditOperation::Import, imported.serial, &diff);
+
+        zones.commit(imported.clone(), entries)?;
+    }
+
+    Ok(ZoneImportResponse {
+        ok: true,
+        zone: zone.to_string(),
+        dry_run,
+        serial: imported.serial,
+        added: diff.added,
+        removed: diff.removed,
+    })
+}
+
+fn diff_entries(
+    client: &str,
+    operation: AuditOperation,
+    serial: u32,
+    diff: &ZoneDiff,
+) -> Vec<AuditEntry> {
+    let removed = diff
+        .removed
+        .iter()
+        .map(|rec| AuditEntry::new(client, operation, serial, Some(rec.clone()), None));
+    let added = diff
+        .added
+        .iter()
+        .map(|rec| AuditEntry::new(client, operation, serial, None, Some(rec.clone())));
+
+    removed.chain(added).collect()
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RestoreRequest {
+    pub serial: u32,
+}
+
+impl FormDataDecodable<RestoreRequest> for RestoreRequest {
+    fn from_formdata(fields: Vec<(String, String)>) -> Result<RestoreRequest> {
+        let d: HashMap<_, _> = fields.into_iter().collect();
+
+        let serial = d
+            .get("serial")
+            .and_then(|x| x.parse::<u32>().ok())
+            .ok_or_else(|| WebError::MissingField("serial"))?;
+
+        Ok(RestoreRequest { serial })
+    }
+}
+
+pub fn zone_audit(context: &ServerContext, zone: &str) -> Result<serde_json::Value> {
+    let zones = context.authority.read().map_err(|_| WebError::LockError)?;
+
+    let current = zones.get_zone(zone).ok_or_else(|| WebError::ZoneNotFound)?;
+
+    let mut entries = zones.audit().entries(zone).to_vec();
+    entries.reverse();
+
+    Ok(json!({
+        "ok": true,
+        "zone": current.domain,
+        "serial": current.serial,
+        "entries": entries,
+    }))
+}
+
+/// Restore the records of a zone to how they were at a previous serial
+///
+/// The restore is itself a change, so it gets a new serial and is appended to
+/// the audit log like any other modification.
+pub fn zone_restore(
+    context: &ServerContext,
+    zone: &str,
+    request: RestoreRequest,
+    client: &str,
+) -> Result<Zone> {
+    let mut zones = context.authority.write().map_err(|_| WebError::LockError)?;
+
+    let current = zones.get_zone(zone).ok_or_else(|| WebError::ZoneNotFound)?;
+
+    let mut restored = zones.audit().revert(current, request.serial)?;
+    restored.serial = current.serial.wrapping_add(1);
+
+    let diff = current.diff(&restored);
+    let mut entries = diff_entries(client, AuditOperation::Restore, restored.serial, &diff);
+    if entries.is_empty() {
+        entries.push(AuditEntry::new(
+            client,
+            AuditOperation::Restore,
+            restored.serial,
+            None,
+            None,
+        ));
+    }
+
+    zones.commit(restored.clone(), entries)?;
+
+    Ok(restored)
+}
+
+pub fn zone_export(context: &ServerContext, zone: &str) -> Result<Zone> {
+    let zones = context.authority.read().map_err(|_| WebError::LockError)?;
+
+    zones.get_zone(zone).cloned().ok_or(WebError::ZoneNotFound)
+}
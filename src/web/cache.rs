@@ -1,67 +1,293 @@
+use chrono::{DateTime, Local};
 use serde_derive::{Deserialize, Serialize};
 
-use crate::dns::cache::RecordSet;
+use crate::dns::cache::{remaining_ttl, DomainEntry, RecordSet};
 use crate::dns::context::ServerContext;
-use crate::dns::protocol::DnsRecord;
-use crate::web::Result;
+use crate::dns::protocol::{DnsRecord, QueryType};
+use crate::web::{Result, WebError};
+
+/// The number of domains listed per page, unless asked for otherwise
+pub const DEFAULT_PAGE_SIZE: usize = 100;
 
 #[derive(Serialize, Deserialize)]
 pub struct CacheRecordEntry {
     pub id: u32,
     pub record: DnsRecord,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub remaining_ttl: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CacheNegativeEntry {
+    /// The type of record known not to exist, or none if the name itself
+    /// doesn't exist
+    pub qtype: Option<QueryType>,
+    pub ttl: u32,
+    pub remaining_ttl: u32,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct CacheRecord {
     domain: String,
-    hits: u32,
+    hits: u64,
     updates: u32,
     entries: Vec<CacheRecordEntry>,
+    negative: Vec<CacheNegativeEntry>,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct CacheResponse {
     ok: bool,
+    filter: Option<String>,
+    total: usize,
+    offset: usize,
+    limit: usize,
+    previous: Option<usize>,
+    next: Option<usize>,
     records: Vec<CacheRecord>,
 }
 
-pub fn cacheinfo(context: &ServerContext) -> Result<CacheResponse> {
+/// Which parts of the cached domains to list
+pub struct CacheQuery {
+    /// Only list domains containing this string
+    pub filter: Option<String>,
+    pub offset: usize,
+    pub limit: usize,
+}
+
+impl Default for CacheQuery {
+    fn default() -> Self {
+        CacheQuery {
+            filter: None,
+            offset: 0,
+            limit: DEFAULT_PAGE_SIZE,
+        }
+    }
+}
+
+fn cache_record(rs: &DomainEntry, now: DateTime<Local>) -> CacheRecord {
+    let mut cache_record = CacheRecord {
+        domain: rs.domain.clone(),
+        hits: rs.hits.get(),
+        updates: rs.updates,
+        entries: Vec::new(),
+        negative: Vec::new(),
+    };
+
+    if let Some(ref nxdomain) = rs.nxdomain {
+        cache_record.negative.push(CacheNegativeEntry {
+            qtype: None,
+            ttl: nxdomain.ttl,
+            remaining_ttl: remaining_ttl(nxdomain.ttl, nxdomain.timestamp, now),
+        });
+    }
+
+    let mut id = 0;
+    for entry in rs.record_types.values() {
+        match *entry {
+            RecordSet::NoRecords {
+                qtype,
+                ttl,
+                timestamp,
+                ..
+            } => {
+                cache_record.negative.push(CacheNegativeEntry {
+                    qtype: Some(qtype),
+                    ttl,
+                    remaining_ttl: remaining_ttl(ttl, timestamp, now),
+                });
+            }
+            RecordSet::Records { ref records, .. } => {
+                for entry in records {
+                    cache_record.entries.push(CacheRecordEntry {
+                        id,
+                        record: entry.record.clone(),
+                        remaining_ttl: Some(entry.remaining_ttl(now)),
+                    });
+                    id += 1;
+                }
+            }
+        }
+    }
+
+    cache_record
+}
+
+pub fn cacheinfo(context: &ServerContext, query: CacheQuery) -> Result<CacheResponse> {
     let cached_records = match context.cache.list() {
         Ok(x) => x,
         Err(_) => Vec::new(),
     };
 
-    let mut cache_response = CacheResponse {
-        ok: true,
-        records: Vec::new(),
+    let matching = cached_records
+        .iter()
+        .filter(|rs| match query.filter {
+            Some(ref filter) => rs.domain.contains(filter.as_str()),
+            None => true,
+        })
+        .collect::<Vec<_>>();
+
+    let limit = if query.limit == 0 {
+        DEFAULT_PAGE_SIZE
+    } else {
+        query.limit
     };
 
-    let mut id = 0;
-    for rs in cached_records {
-        let mut cache_record = CacheRecord {
-            domain: rs.domain.clone(),
-            hits: rs.hits,
-            updates: rs.updates,
-            entries: Vec::new(),
-        };
-
-        for entry in rs.record_types.values() {
-            match *entry {
-                RecordSet::NoRecords { .. } => {}
-                RecordSet::Records { ref records, .. } => {
-                    for entry in records {
-                        cache_record.entries.push(CacheRecordEntry {
-                            id,
-                            record: entry.record.clone(),
-                        });
-                        id += 1;
-                    }
-                }
+    let now = Local::now();
+
+    let records = matching
+        .iter()
+        .skip(query.offset)
+        .take(limit)
+        .map(|rs| cache_record(rs, now))
+        .collect();
+
+    Ok(CacheResponse {
+        ok: true,
+        filter: query.filter,
+        total: matching.len(),
+        offset: query.offset,
+        limit,
+        previous: if query.offset > 0 {
+            Some(query.offset.saturating_sub(limit))
+        } else {
+            None
+        },
+        next: if query.offset + limit < matching.len() {
+            Some(query.offset + limit)
+        } else {
+            None
+        },
+        records,
+    })
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheEntryState {
+    Positive,
+    Negative,
+    Expired,
+}
+
+/// The state of one type of record for a cached name, or of the name as a
+/// whole when `qtype` is none
+#[derive(Serialize, Deserialize)]
+pub struct CacheTypeEntry {
+    qtype: Option<QueryType>,
+    state: CacheEntryState,
+    ttl: u32,
+    remaining_ttl: u32,
+    records: Vec<CacheRecordEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CacheLookupResponse {
+    ok: bool,
+    domain: String,
+    hits: u64,
+    updates: u32,
+    types: Vec<CacheTypeEntry>,
+}
+
+fn negative_state(remaining: u32) -> CacheEntryState {
+    if remaining > 0 {
+        CacheEntryState::Negative
+    } else {
+        CacheEntryState::Expired
+    }
+}
+
+pub fn cache_lookup(context: &ServerContext, domain: &str) -> Result<CacheLookupResponse> {
+    let entry = context.cache.get(domain)?.ok_or(WebError::NotCached)?;
+
+    let now = Local::now();
+
+    let mut types = Vec::new();
+
+    if let Some(ref nxdomain) = entry.nxdomain {
+        let remaining = remaining_ttl(nxdomain.ttl, nxdomain.timestamp, now);
+        types.push(CacheTypeEntry {
+            qtype: None,
+            state: negative_state(remaining),
+            ttl: nxdomain.ttl,
+            remaining_ttl: remaining,
+            records: Vec::new(),
+        });
+    }
+
+    for rs in entry.record_types.values() {
+        match *rs {
+            RecordSet::NoRecords {
+                qtype,
+                ttl,
+                timestamp,
+                ..
+            } => {
+                let remaining = remaining_ttl(ttl, timestamp, now);
+                types.push(CacheTypeEntry {
+                    qtype: Some(qtype),
+                    state: negative_state(remaining),
+                    ttl,
+                    remaining_ttl: remaining,
+                    records: Vec::new(),
+                });
             }
-        }
+            RecordSet::Records { qtype, ref records } => {
+                let mut records = records
+                    .iter()
+                    .map(|x| (x.record.clone(), x.remaining_ttl(now)))
+                    .collect::<Vec<_>>();
+                records.sort_by_key(|x| std::cmp::Reverse(x.1));
 
-        cache_response.records.push(cache_record);
+                let ttl = records.iter().map(|x| x.0.get_ttl()).max().unwrap_or(0);
+                let remaining = records.first().map(|x| x.1).unwrap_or(0);
+
+                types.push(CacheTypeEntry {
+                    qtype: Some(qtype),
+                    state: if remaining > 0 {
+                        CacheEntryState::Positive
+                    } else {
+                        CacheEntryState::Expired
+                    },
+                    ttl,
+                    remaining_ttl: remaining,
+                    records: records
+                        .into_iter()
+                        .enumerate()
+                        .map(|(id, (record, remaining))| CacheRecordEntry {
+                            id: id as u32,
+                            record,
+                            remaining_ttl: Some(remaining),
+                        })
+                        .collect(),
+                });
+            }
+        }
     }
 
-    Ok(cache_response)
+    types.sort_by_key(|x| x.qtype.map(|qtype| qtype.to_num()));
+
+    Ok(CacheLookupResponse {
+        ok: true,
+        domain: entry.domain.clone(),
+        hits: entry.hits.get(),
+        updates: entry.updates,
+        types,
+    })
+}
+
+/// Remove a name from the cache, or everything at and below it if `subtree`
+/// is set. An empty name flushes the entire cache.
+pub fn cache_flush(context: &ServerContext, domain: &str, subtree: bool) -> Result<usize> {
+    let flushed = context.cache.flush(domain, subtree)?;
+
+    println!(
+        "Flushed {} domains from the cache for {:?}{}",
+        flushed,
+        domain,
+        if subtree { " and below" } else { "" }
+    );
+
+    Ok(flushed)
 }
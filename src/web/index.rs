@@ -1,23 +1,91 @@
+use std::sync::atomic::Ordering;
+
 use serde_derive::{Deserialize, Serialize};
 
-use crate::dns::context::ServerContext;
+use crate::dns::context::{ResolveStrategy, ServerContext};
+use crate::dns::forward::ForwarderStatus;
+use crate::dns::protocol::{QueryType, ResultCode};
 use crate::web::Result;
 
+#[derive(Serialize, Deserialize)]
+pub struct TypeStatisticsResponse {
+    qtype: QueryType,
+    cache_hits: usize,
+    cache_negative_hits: usize,
+    cache_misses: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ResponseStatisticsResponse {
+    rescode: ResultCode,
+    count: usize,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct IndexResponse {
     ok: bool,
     client_sent_queries: usize,
     client_failed_queries: usize,
+    client_coalesced_queries: usize,
     server_tcp_queries: usize,
     server_udp_queries: usize,
+    cache_domains: usize,
+    cache_hits: usize,
+    cache_negative_hits: usize,
+    cache_misses: usize,
+    cache_evictions: u64,
+    cache_expirations: u64,
+    types: Vec<TypeStatisticsResponse>,
+    responses: Vec<ResponseStatisticsResponse>,
+    forwarders: Vec<ForwarderStatus>,
 }
 
 pub fn index(context: &ServerContext) -> Result<IndexResponse> {
+    let cache_statistics = context.cache.statistics()?;
+
+    let mut types = context
+        .statistics
+        .types
+        .iter()
+        .map(|(qtype, stats)| TypeStatisticsResponse {
+            qtype: *qtype,
+            cache_hits: stats.cache_hits.load(Ordering::Acquire),
+            cache_negative_hits: stats.cache_negative_hits.load(Ordering::Acquire),
+            cache_misses: stats.cache_misses.load(Ordering::Acquire),
+        })
+        .collect::<Vec<_>>();
+    types.sort_by_key(|x| x.qtype.to_num());
+
+    let responses = (0..context.statistics.responses.len())
+        .map(|num| {
+            let rescode = ResultCode::from_num(num as u8);
+            ResponseStatisticsResponse {
+                rescode,
+                count: context.statistics.get_response_count(rescode),
+            }
+        })
+        .collect();
+
+    let forwarders = match context.resolve_strategy {
+        ResolveStrategy::Forward { ref servers, .. } => context.forwarders.list(servers)?,
+        ResolveStrategy::Recursive => Vec::new(),
+    };
+
     Ok(IndexResponse {
         ok: true,
         client_sent_queries: context.client.get_sent_count(),
         client_failed_queries: context.client.get_failed_count(),
+        client_coalesced_queries: context.inflight.get_coalesced_count(),
         server_tcp_queries: context.statistics.get_tcp_query_count(),
         server_udp_queries: context.statistics.get_udp_query_count(),
+        cache_domains: cache_statistics.domains,
+        cache_hits: context.statistics.get_cache_hit_count(),
+        cache_negative_hits: context.statistics.get_cache_negative_hit_count(),
+        cache_misses: context.statistics.get_cache_miss_count(),
+        cache_evictions: cache_statistics.evictions,
+        cache_expirations: cache_statistics.expirations,
+        types,
+        responses,
+        forwarders,
     })
 }
@@ -0,0 +1,45 @@
+use chrono::{DateTime, Local};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::dns::context::ServerContext;
+use crate::dns::infra::INFRA_TTL;
+use crate::web::Result;
+
+#[derive(Serialize, Deserialize)]
+pub struct InfraServerEntry {
+    addr: String,
+    srtt: u32,
+    successes: u64,
+    failures: u64,
+    consecutive_failures: u32,
+    updated: DateTime<Local>,
+    expired: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct InfraResponse {
+    ok: bool,
+    servers: Vec<InfraServerEntry>,
+}
+
+pub fn infra(context: &ServerContext) -> Result<InfraResponse> {
+    let now = Local::now();
+
+    let mut servers = context
+        .infra
+        .list()?
+        .into_iter()
+        .map(|(addr, stats)| InfraServerEntry {
+            addr: addr.to_string(),
+            srtt: stats.srtt,
+            successes: stats.successes,
+            failures: stats.failures,
+            consecutive_failures: stats.consecutive_failures,
+            updated: stats.updated,
+            expired: stats.updated + chrono::Duration::seconds(INFRA_TTL) < now,
+        })
+        .collect::<Vec<_>>();
+    servers.sort_by_key(|x| x.srtt);
+
+    Ok(InfraResponse { ok: true, servers })
+}
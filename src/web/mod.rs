@@ -3,17 +3,29 @@ use derive_more::{Display, From};
 pub mod authority;
 pub mod cache;
 pub mod index;
+pub mod infra;
+pub mod routes;
 pub mod server;
 pub mod util;
 
 #[derive(Debug, Display, From)]
 pub enum WebError {
+    Audit(crate::dns::audit::AuditError),
     Authority(crate::dns::authority::AuthorityError),
+    Cache(crate::dns::cache::CacheError),
+    Infra(crate::dns::infra::InfraError),
+    Forward(crate::dns::forward::ForwardError),
+    Route(crate::dns::route::RouteError),
     Io(std::io::Error),
     MissingField(&'static str),
     Serialization(serde_json::Error),
     Template(handlebars::RenderError),
+    ZoneFile(crate::dns::zonefile::ZoneFileError),
     ZoneNotFound,
+    ZoneExists,
+    NotCached,
+    RouteNotFound,
+    RecursionUnavailable,
     LockError,
     InvalidRequest,
 }
@@ -0,0 +1,102 @@
+use std::collections::HashMap;
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::dns::context::ServerContext;
+use crate::dns::route::RouteTarget;
+use crate::web::util::FormDataDecodable;
+use crate::web::{Result, WebError};
+
+#[derive(Serialize, Deserialize)]
+pub struct RouteServerEntry {
+    addr: String,
+    up: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RouteEntry {
+    domain: String,
+    target: String,
+    recursive: bool,
+    servers: Vec<RouteServerEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RoutesResponse {
+    ok: bool,
+    routes: Vec<RouteEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RouteRequest {
+    pub domain: String,
+    /// Either `recursive`, or a comma separated list of servers to forward to
+    pub target: String,
+}
+
+impl FormDataDecodable<RouteRequest> for RouteRequest {
+    fn from_formdata(fields: Vec<(String, String)>) -> Result<RouteRequest> {
+        let mut d: HashMap<_, _> = fields.into_iter().collect();
+
+        let domain = d
+            .remove("domain")
+            .ok_or_else(|| WebError::MissingField("domain"))?;
+        let target = d
+            .remove("target")
+            .ok_or_else(|| WebError::MissingField("target"))?;
+
+        Ok(RouteRequest { domain, target })
+    }
+}
+
+pub fn routes(context: &ServerContext) -> Result<RoutesResponse> {
+    let routes = context
+        .routes
+        .list()?
+        .into_iter()
+        .map(|(domain, target)| {
+            let servers = match target {
+                RouteTarget::Forward(ref forwarders) => forwarders
+                    .iter()
+                    .map(|forwarder| RouteServerEntry {
+                        addr: forwarder.to_string(),
+                        up: context.forwarders.is_up(&forwarder.addr),
+                    })
+                    .collect(),
+                RouteTarget::Recursive => Vec::new(),
+            };
+
+            RouteEntry {
+                domain,
+                target: target.to_string(),
+                recursive: target == RouteTarget::Recursive,
+                servers,
+            }
+        })
+        .collect();
+
+    Ok(RoutesResponse { ok: true, routes })
+}
+
+pub fn route_create(context: &ServerContext, request: RouteRequest) -> Result<()> {
+    let target = RouteTarget::parse(request.target.trim()).ok_or(WebError::InvalidRequest)?;
+    if request.domain.trim_matches('.').is_empty() {
+        return Err(WebError::InvalidRequest);
+    }
+
+    if target == RouteTarget::Recursive && !context.recursion_available() {
+        return Err(WebError::RecursionUnavailable);
+    }
+
+    context.routes.add(&request.domain, target)?;
+
+    Ok(())
+}
+
+pub fn route_delete(context: &ServerContext, domain: &str) -> Result<()> {
+    if !context.routes.remove(domain)? {
+        return Err(WebError::RouteNotFound);
+    }
+
+    Ok(())
+}
@@ -4,9 +4,10 @@ use handlebars::Handlebars;
 use tiny_http::{Method, Request, Response, ResponseBox, Server};
 
 use crate::dns::context::ServerContext;
+use crate::dns::zonefile;
 use crate::web::{
-    authority, cache, index,
-    util::{parse_formdata, FormDataDecodable},
+    authority, cache, index, infra, routes,
+    util::{parse_formdata, query_flag, query_param, split_url, FormDataDecodable},
     Result,
 };
 
@@ -64,8 +65,13 @@ impl<'a> WebServer<'a> {
         register_template("layout", include_str!("templates/layout.html"));
         register_template("authority", include_str!("templates/authority.html"));
         register_template("cache", include_str!("templates/cache.html"));
+        register_template("cache_domain", include_str!("templates/cache_domain.html"));
         register_template("zone", include_str!("templates/zone.html"));
         register_template("index", include_str!("templates/index.html"));
+        register_template("infra", include_str!("templates/infra.html"));
+        register_template("routes", include_str!("templates/routes.html"));
+        register_template("zone_import", include_str!("templates/zone_import.html"));
+        register_template("zone_audit", include_str!("templates/zone_audit.html"));
 
         server
     }
@@ -90,15 +96,39 @@ impl<'a> WebServer<'a> {
             let url = request.url().to_string();
             let method = request.method();
 
-            let url_parts: Vec<&str> = url.split("/").filter(|x| *x != "").collect();
+            let (path, params) = split_url(&url);
+            let url_parts: Vec<&str> = path.split("/").filter(|x| *x != "").collect();
             let response = match (method, url_parts.as_slice()) {
+                (Method::Post, ["authority", zone, "import"]) => {
+                    self.zone_import(&mut request, zone, &params)
+                }
+                (Method::Get, ["authority", zone, "export"]) => {
+                    self.zone_export(&request, zone, &params)
+                }
+                (Method::Get, ["authority", zone, "audit"]) => self.zone_audit(&request, zone),
+                (Method::Post, ["authority", zone, "restore"]) => {
+                    self.zone_restore(&mut request, zone)
+                }
                 (Method::Post, ["authority", zone]) => self.record_create(&mut request, zone),
                 (Method::Delete, ["authority", zone]) => self.record_delete(&mut request, zone),
                 (Method::Post, ["authority", zone, "delete_record"]) => self.record_delete(&mut request, zone),
                 (Method::Get, ["authority", zone]) => self.zone_view(&request, zone),
                 (Method::Post, ["authority"]) => self.zone_create(&mut request),
                 (Method::Get, ["authority"]) => self.zone_list(&request),
-                (Method::Get, ["cache"]) => self.cacheinfo(&request),
+                (Method::Post, ["cache", "flush"]) | (Method::Delete, ["cache"]) => {
+                    self.cache_flush(&request, "", true)
+                }
+                (Method::Post, ["cache", domain, "flush"])
+                | (Method::Delete, ["cache", domain]) => {
+                    self.cache_flush(&request, domain, query_flag(&params, "subtree"))
+                }
+                (Method::Get, ["cache", domain]) => self.cache_lookup(&request, domain),
+                (Method::Get, ["cache"]) => self.cacheinfo(&request, &params),
+                (Method::Get, ["infra"]) => self.infra(&request),
+                (Method::Post, ["routes", domain, "delete"])
+                | (Method::Delete, ["routes", domain]) => self.route_delete(&request, domain),
+                (Method::Post, ["routes"]) => self.route_create(&mut request),
+                (Method::Get, ["routes"]) => self.routes(&request),
                 (Method::Get, []) => self.index(&request),
                 (_, _) => self.not_found(&request),
             };
@@ -168,7 +198,8 @@ impl<'a> WebServer<'a> {
                 .and_then(authority::ZoneCreateRequest::from_formdata)?
         };
 
-        let zone = authority::zone_create(&self.context, zone_create_request)?;
+        let client = request.remote_addr().ip().to_string();
+        let zone = authority::zone_create(&self.context, zone_create_request, &client)?;
 
         let location_header = format!("Location: /authority/{}", zone.domain);
 
@@ -187,7 +218,8 @@ impl<'a> WebServer<'a> {
                 .and_then(authority::RecordRequest::from_formdata)?
         };
 
-        authority::record_create(&self.context, zone, record_request)?;
+        let client = request.remote_addr().ip().to_string();
+        authority::record_create(&self.context, zone, record_request, &client)?;
 
         let location_header = format!("Location: /authority/{}", zone);
         Ok(
@@ -205,7 +237,8 @@ impl<'a> WebServer<'a> {
                 .and_then(authority::RecordRequest::from_formdata)?
         };
 
-        authority::record_delete(&self.context, zone, record_request)?;
+        let client = request.remote_addr().ip().to_string();
+        authority::record_delete(&self.context, zone, record_request, &client)?;
 
         let location_header = format!("Location: /authority/{}", zone);
         Ok(
@@ -215,11 +248,140 @@ impl<'a> WebServer<'a> {
         )
     }
 
-    fn cacheinfo(&self, request: &Request) -> Result<ResponseBox> {
-        let cacheinfo_result = cache::cacheinfo(&self.context)?;
+    fn zone_import(
+        &self,
+        request: &mut Request,
+        zone: &str,
+        params: &[(String, String)],
+    ) -> Result<ResponseBox> {
+        let mut data = String::new();
+        request.as_reader().read_to_string(&mut data)?;
+
+        let imported = if request.json_input() {
+            serde_json::from_str(&data)?
+        } else {
+            zonefile::parse_zone(&data, zone)?
+        };
+
+        let dry_run = query_flag(params, "dry_run");
+        let client = request.remote_addr().ip().to_string();
+        let import_result =
+            authority::zone_import(&self.context, zone, imported, dry_run, &client)?;
+        self.response_from_media_type(request, "zone_import", import_result)
+    }
+
+    fn zone_export(
+        &self,
+        request: &Request,
+        zone: &str,
+        params: &[(String, String)],
+    ) -> Result<ResponseBox> {
+        let zone = authority::zone_export(&self.context, zone)?;
+
+        if request.json_output() || query_param(params, "format") == Some("json") {
+            return Ok(Response::from_string(serde_json::to_string(&zone)?)
+                .with_header::<tiny_http::Header>("Content-Type: application/json".parse().unwrap())
+                .boxed());
+        }
+
+        Ok(Response::from_string(zonefile::write_zone(&zone))
+            .with_header::<tiny_http::Header>("Content-Type: text/plain".parse().unwrap())
+            .boxed())
+    }
+
+    fn zone_audit(&self, request: &Request, zone: &str) -> Result<ResponseBox> {
+        let zone_audit_result = authority::zone_audit(&self.context, zone)?;
+        self.response_from_media_type(request, "zone_audit", zone_audit_result)
+    }
+
+    fn zone_restore(&self, request: &mut Request, zone: &str) -> Result<ResponseBox> {
+        let restore_request = if request.json_input() {
+            serde_json::from_reader(request.as_reader())?
+        } else {
+            parse_formdata(&mut request.as_reader())
+                .and_then(authority::RestoreRequest::from_formdata)?
+        };
+
+        let client = request.remote_addr().ip().to_string();
+        authority::zone_restore(&self.context, zone, restore_request, &client)?;
+
+        let location_header = format!("Location: /authority/{}/audit", zone);
+        Ok(
+            Response::empty(if request.json_output() { 201 } else { 302 })
+                .with_header::<tiny_http::Header>(location_header.parse().unwrap())
+                .boxed(),
+        )
+    }
+
+    fn cacheinfo(&self, request: &Request, params: &[(String, String)]) -> Result<ResponseBox> {
+        let query = cache::CacheQuery {
+            filter: query_param(params, "filter")
+                .filter(|x| !x.is_empty())
+                .map(|x| x.to_string()),
+            offset: query_param(params, "offset")
+                .and_then(|x| x.parse().ok())
+                .unwrap_or(0),
+            limit: query_param(params, "limit")
+                .and_then(|x| x.parse().ok())
+                .unwrap_or(cache::DEFAULT_PAGE_SIZE),
+        };
+
+        let cacheinfo_result = cache::cacheinfo(&self.context, query)?;
         self.response_from_media_type(request, "cache", cacheinfo_result)
     }
 
+    fn cache_lookup(&self, request: &Request, domain: &str) -> Result<ResponseBox> {
+        let cache_lookup_result = cache::cache_lookup(&self.context, domain)?;
+        self.response_from_media_type(request, "cache_domain", cache_lookup_result)
+    }
+
+    fn cache_flush(&self, request: &Request, domain: &str, subtree: bool) -> Result<ResponseBox> {
+        cache::cache_flush(&self.context, domain, subtree)?;
+
+        Ok(
+            Response::empty(if request.json_output() { 204 } else { 302 })
+                .with_header::<tiny_http::Header>("Location: /cache".parse().unwrap())
+                .boxed(),
+        )
+    }
+
+    fn infra(&self, request: &Request) -> Result<ResponseBox> {
+        let infra_result = infra::infra(&self.context)?;
+        self.response_from_media_type(request, "infra", infra_result)
+    }
+
+    fn routes(&self, request: &Request) -> Result<ResponseBox> {
+        let routes_result = routes::routes(&self.context)?;
+        self.response_from_media_type(request, "routes", routes_result)
+    }
+
+    fn route_create(&self, request: &mut Request) -> Result<ResponseBox> {
+        let route_request = if request.json_input() {
+            serde_json::from_reader(request.as_reader())?
+        } else {
+            parse_formdata(&mut request.as_reader())
+                .and_then(routes::RouteRequest::from_formdata)?
+        };
+
+        routes::route_create(&self.context, route_request)?;
+
+        Ok(
+            Response::empty(if request.json_output() { 201 } else { 302 })
+                .with_header::<tiny_http::Header>("Location: /routes".parse().unwrap())
+                .boxed(),
+        )
+    }
+
+    fn route_delete(&self, request: &Request, domain: &str) -> Result<ResponseBox> {
+        routes::route_delete(&self.context, domain)?;
+
+        Ok(
+            Response::empty(if request.json_output() { 204 } else { 302 })
+                .with_header::<tiny_http::Header>("Location: /routes".parse().unwrap())
+                .boxed(),
+        )
+    }
+
     fn not_found(&self, _request: &Request) -> Result<ResponseBox> {
         Ok(Response::from_string("Not found")
             .with_status_code(404)
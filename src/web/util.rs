@@ -39,12 +39,8 @@ pub fn url_decode(instr: &str) -> String {
     buffer
 }
 
-pub fn parse_formdata<R: Read>(reader: &mut R) -> Result<Vec<(String, String)>> {
-    let mut data = String::new();
-    reader.read_to_string(&mut data)?;
-
-    let res = data
-        .split('&')
+pub fn parse_query(data: &str) -> Vec<(String, String)> {
+    data.split('&')
         .filter_map(|x| {
             let s = x.split('=').collect::<Vec<&str>>();
             match s.len() {
@@ -52,9 +48,37 @@ pub fn parse_formdata<R: Read>(reader: &mut R) -> Result<Vec<(String, String)>>
                 _ => None,
             }
         })
-        .collect::<Vec<(String, String)>>();
+        .collect::<Vec<(String, String)>>()
+}
 
-    Ok(res)
+pub fn parse_formdata<R: Read>(reader: &mut R) -> Result<Vec<(String, String)>> {
+    let mut data = String::new();
+    reader.read_to_string(&mut data)?;
+
+    Ok(parse_query(&data))
+}
+
+/// Split a request url into the path and the decoded query string parameters
+pub fn split_url(url: &str) -> (&str, Vec<(String, String)>) {
+    match url.find('?') {
+        Some(idx) => (&url[..idx], parse_query(&url[idx + 1..])),
+        None => (url, Vec::new()),
+    }
+}
+
+/// Check whether a query string parameter is present and set to a true value
+pub fn query_flag(params: &[(String, String)], name: &str) -> bool {
+    params
+        .iter()
+        .any(|(k, v)| k == name && (v == "1" || v == "true" || v == "on"))
+}
+
+/// Look up the first value of a query string parameter
+pub fn query_param<'a>(params: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    params
+        .iter()
+        .find(|(k, _)| k == name)
+        .map(|(_, v)| v.as_str())
 }
 
 #[cfg(test)]
@@ -95,4 +119,17 @@ mod tests {
         assert_eq!(1, result4.len());
         assert_eq!(("foo".to_string(), "bar".to_string()), result4[0]);
     }
+
+    #[test]
+    fn test_split_url() {
+        let (path, params) = split_url("/authority/example.com/import?dry_run=1&x=%40");
+        assert_eq!("/authority/example.com/import", path);
+        assert!(query_flag(&params, "dry_run"));
+        assert_eq!(Some("@"), query_param(&params, "x"));
+        assert_eq!(None, query_param(&params, "y"));
+
+        let (path, params) = split_url("/cache");
+        assert_eq!("/cache", path);
+        assert!(params.is_empty());
+    }
 }